@@ -1,19 +1,201 @@
 use std::{
+    fmt::Write,
     fs::{self, metadata},
-    io::Read,
+    io::{self, Read},
     path::PathBuf,
 };
 
 use bytes::BufMut;
 
+const RANGE_FLAG_REVERSE: u8 = 1;
+const RANGE_FLAG_CONTINUATION: u8 = 1 << 1;
+const RANGE_FLAG_VALUE_FILTER: u8 = 1 << 2;
+const RANGE_FLAG_BY_VALUE: u8 = 1 << 3;
+
+const CAS_FLAG_EXPECTED: u8 = 1;
+
+/// Protocol version greeted with, and the capabilities asked for: pipelining and streaming
+pub const PROTOCOL_VERSION: u16 = 1;
+pub const CAPABILITIES: u32 = 0b11;
+
+// response frames start with one of these, see `Command::read_response`
+const STATUS_OK: u8 = 0;
+const STATUS_NOT_FOUND: u8 = 1;
+const STATUS_ERROR: u8 = 2;
+const STATUS_FAILED: u8 = 3;
+const STATUS_MORE: u8 = 4;
+
+const ITEM_NIL: u8 = b'n';
+const ITEM_VALUE: u8 = b'v';
+const ITEM_PAIR: u8 = b'p';
+const ITEM_NEXT: u8 = b'c';
+const ITEM_INT: u8 = b'i';
+const ITEM_TEXT: u8 = b't';
+
 #[derive(Clone, Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Command {
-    PUT { key: i32, val: i32 },
-    GET { key: i32 },
-    DELETE { key: i32 },
-    LOAD { file: PathBuf },
-    RANGE { min_key: i32, max_key: i32 },
-    STATS
+    PUT {
+        key: i32,
+        val: i32,
+    },
+    /// A put that expires after `ttl` seconds
+    PUTEX {
+        key: i32,
+        val: i32,
+        ttl: u32,
+    },
+    GET {
+        key: i32,
+    },
+    MGET {
+        keys: Vec<i32>,
+    },
+    DELETE {
+        key: i32,
+    },
+    CAS {
+        key: i32,
+        expected: Option<i32>,
+        val: i32,
+    },
+    GETSET {
+        key: i32,
+        val: i32,
+    },
+    GETDEL {
+        key: i32,
+    },
+    MERGE {
+        key: i32,
+        operand: i32,
+    },
+    LOAD {
+        file: PathBuf,
+    },
+    RANGE {
+        min_key: i32,
+        max_key: i32,
+        limit: Option<u32>,
+        reverse: bool,
+        continuation: Option<i32>,
+        /// Inclusive bounds on the values
+        value_filter: Option<(i32, i32)>,
+        by_value: bool,
+    },
+    /// `count`, `sum`, `min` or `max` of the values in the range, sent as its code
+    AGGREGATE {
+        min_key: i32,
+        max_key: i32,
+        aggregate: u8,
+    },
+    /// Keys holding a value, needs a value index
+    LOOKUP {
+        val: i32,
+    },
+    /// Pairs ordered by value within inclusive bounds, needs a value index
+    VRANGE {
+        min_val: i32,
+        max_val: i32,
+        limit: Option<u32>,
+    },
+    STATS,
+    /// Puts (with a value) and deletes applied atomically
+    BATCH {
+        commands: Vec<(i32, Option<i32>)>,
+    },
+    // the same commands on byte string keys and values
+    BPUT {
+        key: Vec<u8>,
+        val: Vec<u8>,
+    },
+    BPUTEX {
+        key: Vec<u8>,
+        val: Vec<u8>,
+        ttl: u32,
+    },
+    BGET {
+        key: Vec<u8>,
+    },
+    BMGET {
+        keys: Vec<Vec<u8>>,
+    },
+    BDELETE {
+        key: Vec<u8>,
+    },
+    BCAS {
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        val: Vec<u8>,
+    },
+    BGETSET {
+        key: Vec<u8>,
+        val: Vec<u8>,
+    },
+    BGETDEL {
+        key: Vec<u8>,
+    },
+    BMERGE {
+        key: Vec<u8>,
+        operand: Vec<u8>,
+    },
+    BRANGE {
+        min_key: Vec<u8>,
+        max_key: Option<Vec<u8>>,
+        limit: Option<u32>,
+        reverse: bool,
+        continuation: Option<Vec<u8>>,
+        value_filter: Option<(Vec<u8>, Option<Vec<u8>>)>,
+        by_value: bool,
+    },
+    BBATCH {
+        commands: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    },
+    BAGGREGATE {
+        min_key: Vec<u8>,
+        max_key: Option<Vec<u8>>,
+        aggregate: u8,
+    },
+    BLOOKUP {
+        val: Vec<u8>,
+    },
+    BVRANGE {
+        min_val: Vec<u8>,
+        max_val: Option<Vec<u8>>,
+        limit: Option<u32>,
+    },
+    // column families
+    FCREATE {
+        name: String,
+        flags: String,
+    },
+    FDROP {
+        name: String,
+    },
+    FLIST,
+    /// Greets the server with a protocol version and capability flags
+    HELLO {
+        version: u16,
+        capabilities: u32,
+    },
+    /// Any of the key value commands, run on a named family
+    NAMESPACED {
+        family: String,
+        command: Box<Command>,
+    },
+    // transactions
+    BEGIN,
+    COMMIT {
+        id: u32,
+    },
+    ABORT {
+        id: u32,
+    },
+    /// A get, put or delete within a transaction
+    TRANSACTED {
+        id: u32,
+        command: Box<Command>,
+    },
 }
 
 impl Command {
@@ -24,14 +206,53 @@ impl Command {
                 buf.put_i32(*key);
                 buf.put_i32(*val);
             }
+            Self::PUTEX { key, val, ttl } => {
+                buf.put_u8(b'P');
+                buf.put_i32(*key);
+                buf.put_i32(*val);
+                buf.put_u32(*ttl);
+            }
             Self::GET { key } => {
                 buf.put_u8(b'g');
                 buf.put_i32(*key);
             }
+            Self::MGET { keys } => {
+                buf.put_u8(b'm');
+                buf.put_u32(keys.len() as u32);
+                for key in keys {
+                    buf.put_i32(*key);
+                }
+            }
             Self::DELETE { key } => {
                 buf.put_u8(b'd');
                 buf.put_i32(*key);
             }
+            Self::CAS { key, expected, val } => {
+                buf.put_u8(b'c');
+                buf.put_i32(*key);
+                match expected {
+                    Some(expected) => {
+                        buf.put_u8(CAS_FLAG_EXPECTED);
+                        buf.put_i32(*expected);
+                    }
+                    None => buf.put_u8(0),
+                }
+                buf.put_i32(*val);
+            }
+            Self::GETSET { key, val } => {
+                buf.put_u8(b'G');
+                buf.put_i32(*key);
+                buf.put_i32(*val);
+            }
+            Self::GETDEL { key } => {
+                buf.put_u8(b'D');
+                buf.put_i32(*key);
+            }
+            Self::MERGE { key, operand } => {
+                buf.put_u8(b'M');
+                buf.put_i32(*key);
+                buf.put_i32(*operand);
+            }
             Self::LOAD { file } => {
                 buf.put_u8(b'l');
 
@@ -41,14 +262,352 @@ impl Command {
                 buf.put_u64(kv_pairs);
                 fs::File::open(file).unwrap().read_to_end(buf).unwrap();
             }
-            Self::RANGE { min_key, max_key } => {
+            Self::RANGE {
+                min_key,
+                max_key,
+                limit: None,
+                reverse: false,
+                continuation: None,
+                value_filter: None,
+                by_value: false,
+            } => {
                 buf.put_u8(b'r');
                 buf.put_i32(*min_key);
                 buf.put_i32(*max_key);
             }
+            Self::RANGE {
+                min_key,
+                max_key,
+                limit,
+                reverse,
+                continuation,
+                value_filter,
+                by_value,
+            } => {
+                buf.put_u8(b'R');
+                buf.put_i32(*min_key);
+                buf.put_i32(*max_key);
+                buf.put_u32(limit.unwrap_or(0));
+                buf.put_u8(range_flags(
+                    *reverse,
+                    continuation.is_some(),
+                    value_filter.is_some(),
+                    *by_value,
+                ));
+
+                if let Some(continuation) = continuation {
+                    buf.put_i32(*continuation);
+                }
+                if let Some((min_val, max_val)) = value_filter {
+                    buf.put_i32(*min_val);
+                    buf.put_i32(*max_val);
+                }
+            }
+            Self::AGGREGATE {
+                min_key,
+                max_key,
+                aggregate,
+            } => {
+                buf.put_u8(b'a');
+                buf.put_i32(*min_key);
+                buf.put_i32(*max_key);
+                buf.put_u8(*aggregate);
+            }
+            Self::LOOKUP { val } => {
+                buf.put_u8(b'V');
+                buf.put_i32(*val);
+            }
+            Self::VRANGE {
+                min_val,
+                max_val,
+                limit,
+            } => {
+                buf.put_u8(b'v');
+                buf.put_i32(*min_val);
+                buf.put_i32(*max_val);
+                buf.put_u32(limit.unwrap_or(0));
+            }
             Self::STATS => {
                 buf.put_u8(b's');
             }
+            Self::BATCH { commands } => {
+                buf.put_u8(b'B');
+                buf.put_u32(commands.len() as u32);
+                for (key, val) in commands {
+                    match val {
+                        Some(val) => {
+                            buf.put_u8(b'p');
+                            buf.put_i32(*key);
+                            buf.put_i32(*val);
+                        }
+                        None => {
+                            buf.put_u8(b'd');
+                            buf.put_i32(*key);
+                        }
+                    }
+                }
+            }
+            Self::BPUT { key, val } => {
+                buf.put_slice(b"bp");
+                put_sized(buf, key);
+                put_sized(buf, val);
+            }
+            Self::BPUTEX { key, val, ttl } => {
+                buf.put_slice(b"bP");
+                put_sized(buf, key);
+                put_sized(buf, val);
+                buf.put_u32(*ttl);
+            }
+            Self::BGET { key } => {
+                buf.put_slice(b"bg");
+                put_sized(buf, key);
+            }
+            Self::BMGET { keys } => {
+                buf.put_slice(b"bm");
+                buf.put_u32(keys.len() as u32);
+                for key in keys {
+                    put_sized(buf, key);
+                }
+            }
+            Self::BDELETE { key } => {
+                buf.put_slice(b"bd");
+                put_sized(buf, key);
+            }
+            Self::BCAS { key, expected, val } => {
+                buf.put_slice(b"bc");
+                put_sized(buf, key);
+                match expected {
+                    Some(expected) => {
+                        buf.put_u8(CAS_FLAG_EXPECTED);
+                        put_sized(buf, expected);
+                    }
+                    None => buf.put_u8(0),
+                }
+                put_sized(buf, val);
+            }
+            Self::BGETSET { key, val } => {
+                buf.put_slice(b"bG");
+                put_sized(buf, key);
+                put_sized(buf, val);
+            }
+            Self::BGETDEL { key } => {
+                buf.put_slice(b"bD");
+                put_sized(buf, key);
+            }
+            Self::BMERGE { key, operand } => {
+                buf.put_slice(b"bM");
+                put_sized(buf, key);
+                put_sized(buf, operand);
+            }
+            Self::BRANGE {
+                min_key,
+                max_key,
+                limit,
+                reverse,
+                continuation,
+                value_filter,
+                by_value,
+            } => {
+                buf.put_slice(b"bR");
+                put_sized(buf, min_key);
+                // an empty max key means no upper bound
+                put_sized(buf, max_key.as_deref().unwrap_or_default());
+                buf.put_u32(limit.unwrap_or(0));
+                buf.put_u8(range_flags(
+                    *reverse,
+                    continuation.is_some(),
+                    value_filter.is_some(),
+                    *by_value,
+                ));
+
+                if let Some(continuation) = continuation {
+                    put_sized(buf, continuation);
+                }
+                if let Some((min_val, max_val)) = value_filter {
+                    put_sized(buf, min_val);
+                    put_sized(buf, max_val.as_deref().unwrap_or_default());
+                }
+            }
+            Self::BAGGREGATE {
+                min_key,
+                max_key,
+                aggregate,
+            } => {
+                buf.put_slice(b"ba");
+                put_sized(buf, min_key);
+                put_sized(buf, max_key.as_deref().unwrap_or_default());
+                buf.put_u8(*aggregate);
+            }
+            Self::BLOOKUP { val } => {
+                buf.put_slice(b"bV");
+                put_sized(buf, val);
+            }
+            Self::BVRANGE {
+                min_val,
+                max_val,
+                limit,
+            } => {
+                buf.put_slice(b"bv");
+                put_sized(buf, min_val);
+                // an empty max value means no upper bound
+                put_sized(buf, max_val.as_deref().unwrap_or_default());
+                buf.put_u32(limit.unwrap_or(0));
+            }
+            Self::BBATCH { commands } => {
+                buf.put_slice(b"bB");
+                buf.put_u32(commands.len() as u32);
+                for (key, val) in commands {
+                    match val {
+                        Some(val) => {
+                            buf.put_u8(b'p');
+                            put_sized(buf, key);
+                            put_sized(buf, val);
+                        }
+                        None => {
+                            buf.put_u8(b'd');
+                            put_sized(buf, key);
+                        }
+                    }
+                }
+            }
+            Self::FCREATE { name, flags } => {
+                buf.put_slice(b"fc");
+                put_name(buf, name);
+                put_sized(buf, flags.as_bytes());
+            }
+            Self::FDROP { name } => {
+                buf.put_slice(b"fd");
+                put_name(buf, name);
+            }
+            Self::FLIST => {
+                buf.put_slice(b"fl");
+            }
+            Self::HELLO {
+                version,
+                capabilities,
+            } => {
+                buf.put_u8(b'h');
+                buf.put_u16(*version);
+                buf.put_u32(*capabilities);
+            }
+            Self::NAMESPACED { family, command } => {
+                buf.put_u8(b'n');
+                put_name(buf, family);
+                command.serialize(buf);
+            }
+            Self::BEGIN => {
+                buf.put_slice(b"tb");
+            }
+            Self::COMMIT { id } => {
+                buf.put_slice(b"tc");
+                buf.put_u32(*id);
+            }
+            Self::ABORT { id } => {
+                buf.put_slice(b"ta");
+                buf.put_u32(*id);
+            }
+            Self::TRANSACTED { id, command } => {
+                buf.put_u8(b'x');
+                buf.put_u32(*id);
+                command.serialize(buf);
+            }
+        }
+    }
+
+    /// Reads the frames answering this command and renders them as text: values separated by
+    /// spaces, pairs as `key:value`, misses as nothing, empty answers as `OK`, compare and set
+    /// failures as `FAIL <current>` and errors as `ERR <reason>`
+    pub fn read_response(&self, reader: &mut impl Read) -> io::Result<String> {
+        let mut out = String::new();
+        loop {
+            let mut header = [0; 5];
+            reader.read_exact(&mut header)?;
+            let len = u32::from_be_bytes(header[1..].try_into().unwrap()) as usize;
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body)?;
+
+            match header[0] {
+                STATUS_MORE => self.render_items(&body, &mut out)?,
+                STATUS_OK => {
+                    self.render_items(&body, &mut out)?;
+                    if body.is_empty() && out.is_empty() {
+                        out.push_str("OK");
+                    }
+                    return Ok(out);
+                }
+                STATUS_NOT_FOUND => return Ok(out),
+                STATUS_ERROR => return Ok(format!("ERR {}", String::from_utf8_lossy(&body))),
+                STATUS_FAILED => {
+                    out.push_str("FAIL");
+                    if !body.is_empty() {
+                        out.push(' ');
+                        self.render_items(&body, &mut out)?;
+                    }
+                    return Ok(out);
+                }
+                _ => return Err(invalid_response()),
+            }
+        }
+    }
+
+    fn render_items(&self, mut body: &[u8], out: &mut String) -> io::Result<()> {
+        let int = self.is_int();
+        // values, misses and integers are separated by spaces, pairs end in one
+        let mut after_value = false;
+        while let Some((&tag, rest)) = body.split_first() {
+            body = rest;
+            if after_value && matches!(tag, ITEM_NIL | ITEM_VALUE | ITEM_INT) {
+                out.push(' ');
+            }
+            after_value = matches!(tag, ITEM_NIL | ITEM_VALUE | ITEM_INT);
+
+            match tag {
+                ITEM_NIL => {}
+                ITEM_VALUE => write_value(out, take_sized(&mut body)?, int),
+                ITEM_PAIR => {
+                    write_value(out, take_sized(&mut body)?, int);
+                    out.push(':');
+                    write_value(out, take_sized(&mut body)?, int);
+                    out.push(' ');
+                }
+                ITEM_NEXT => {
+                    out.push_str("next=");
+                    write_value(out, take_sized(&mut body)?, int);
+                }
+                ITEM_INT => {
+                    let (val, rest) = body.split_first_chunk().ok_or_else(invalid_response)?;
+                    body = rest;
+                    write!(out, "{}", i128::from_be_bytes(*val)).unwrap();
+                }
+                ITEM_TEXT => out.push_str(&String::from_utf8_lossy(take_sized(&mut body)?)),
+                _ => return Err(invalid_response()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether keys and values are i32s, printed as numbers
+    fn is_int(&self) -> bool {
+        match self {
+            Self::NAMESPACED { command, .. } | Self::TRANSACTED { command, .. } => command.is_int(),
+            Self::BPUT { .. }
+            | Self::BPUTEX { .. }
+            | Self::BGET { .. }
+            | Self::BMGET { .. }
+            | Self::BDELETE { .. }
+            | Self::BCAS { .. }
+            | Self::BGETSET { .. }
+            | Self::BGETDEL { .. }
+            | Self::BMERGE { .. }
+            | Self::BRANGE { .. }
+            | Self::BBATCH { .. }
+            | Self::BAGGREGATE { .. }
+            | Self::BLOOKUP { .. }
+            | Self::BVRANGE { .. }
+            | Self::FCREATE { .. }
+            | Self::FDROP { .. }
+            | Self::FLIST => false,
+            _ => true,
         }
     }
 
@@ -62,14 +621,52 @@ impl Command {
                 let val: i32 = split_iter.next()?.parse().ok()?;
                 Some(Command::PUT { key, val })
             }
+            "P" => {
+                // P <key> <val> <ttl seconds>
+                let key: i32 = split_iter.next()?.parse().ok()?;
+                let val: i32 = split_iter.next()?.parse().ok()?;
+                let ttl: u32 = split_iter.next()?.parse().ok()?;
+                Some(Command::PUTEX { key, val, ttl })
+            }
             "g" => {
                 let key: i32 = split_iter.next()?.parse().ok()?;
                 Some(Command::GET { key })
             }
+            "m" => {
+                let keys: Vec<i32> = split_iter.map(|k| k.parse().ok()).collect::<Option<_>>()?;
+                if keys.is_empty() {
+                    return None;
+                }
+                Some(Command::MGET { keys })
+            }
             "d" => {
                 let key: i32 = split_iter.next()?.parse().ok()?;
                 Some(Command::DELETE { key })
             }
+            "c" => {
+                // c <key> <expected|*> <val>, `*` expects the key to be absent
+                let key: i32 = split_iter.next()?.parse().ok()?;
+                let expected = match split_iter.next()? {
+                    "*" => None,
+                    expected => Some(expected.parse().ok()?),
+                };
+                let val: i32 = split_iter.next()?.parse().ok()?;
+                Some(Command::CAS { key, expected, val })
+            }
+            "G" => {
+                let key: i32 = split_iter.next()?.parse().ok()?;
+                let val: i32 = split_iter.next()?.parse().ok()?;
+                Some(Command::GETSET { key, val })
+            }
+            "D" => {
+                let key: i32 = split_iter.next()?.parse().ok()?;
+                Some(Command::GETDEL { key })
+            }
+            "M" => {
+                let key: i32 = split_iter.next()?.parse().ok()?;
+                let operand: i32 = split_iter.next()?.parse().ok()?;
+                Some(Command::MERGE { key, operand })
+            }
             "l" => {
                 let file: PathBuf = split_iter.next()?.parse().ok()?;
 
@@ -80,12 +677,409 @@ impl Command {
                 Some(Command::LOAD { file })
             }
             "r" => {
+                // r <min> <max> [limit] [rev] [next=<key>] [val=<min>..<max>] [byval]
                 let min_key: i32 = split_iter.next()?.parse().ok()?;
                 let max_key: i32 = split_iter.next()?.parse().ok()?;
-                Some(Command::RANGE { min_key, max_key })
+
+                let mut limit = None;
+                let mut reverse = false;
+                let mut continuation = None;
+                let mut value_filter = None;
+                let mut by_value = false;
+                for option in split_iter {
+                    if option == "rev" {
+                        reverse = true;
+                    } else if option == "byval" {
+                        by_value = true;
+                    } else if let Some(key) = option.strip_prefix("next=") {
+                        continuation = Some(key.parse().ok()?);
+                    } else if let Some(bounds) = option.strip_prefix("val=") {
+                        // either bound can be left out
+                        let (min_val, max_val) = bounds.split_once("..")?;
+                        value_filter = Some((
+                            if min_val.is_empty() {
+                                i32::MIN
+                            } else {
+                                min_val.parse().ok()?
+                            },
+                            if max_val.is_empty() {
+                                i32::MAX
+                            } else {
+                                max_val.parse().ok()?
+                            },
+                        ));
+                    } else {
+                        limit = Some(option.parse().ok()?);
+                    }
+                }
+
+                Some(Command::RANGE {
+                    min_key,
+                    max_key,
+                    limit,
+                    reverse,
+                    continuation,
+                    value_filter,
+                    by_value,
+                })
+            }
+            "a" => {
+                // a <min> <max> count|sum|min|max
+                let min_key: i32 = split_iter.next()?.parse().ok()?;
+                let max_key: i32 = split_iter.next()?.parse().ok()?;
+                let aggregate = aggregate_code(split_iter.next()?)?;
+                Some(Command::AGGREGATE {
+                    min_key,
+                    max_key,
+                    aggregate,
+                })
+            }
+            "V" => Some(Command::LOOKUP {
+                val: split_iter.next()?.parse().ok()?,
+            }),
+            "v" => {
+                // v <min> <max> [limit]
+                let min_val: i32 = split_iter.next()?.parse().ok()?;
+                let max_val: i32 = split_iter.next()?.parse().ok()?;
+                let limit = match split_iter.next() {
+                    Some(limit) => Some(limit.parse().ok()?),
+                    None => None,
+                };
+                Some(Command::VRANGE {
+                    min_val,
+                    max_val,
+                    limit,
+                })
             }
             "s" => Some(Command::STATS),
+            "B" => {
+                // B p <key> <val> d <key> ...
+                let mut commands = vec![];
+                while let Some(op) = split_iter.next() {
+                    let key: i32 = split_iter.next()?.parse().ok()?;
+                    let val = match op {
+                        "p" => Some(split_iter.next()?.parse().ok()?),
+                        "d" => None,
+                        _ => return None,
+                    };
+                    commands.push((key, val));
+                }
+                Some(Command::BATCH { commands })
+            }
+            "bp" => {
+                let key = unescape(split_iter.next()?)?;
+                let val = unescape(split_iter.next()?)?;
+                Some(Command::BPUT { key, val })
+            }
+            "bP" => {
+                let key = unescape(split_iter.next()?)?;
+                let val = unescape(split_iter.next()?)?;
+                let ttl: u32 = split_iter.next()?.parse().ok()?;
+                Some(Command::BPUTEX { key, val, ttl })
+            }
+            "bg" => {
+                let key = unescape(split_iter.next()?)?;
+                Some(Command::BGET { key })
+            }
+            "bm" => {
+                let keys: Vec<Vec<u8>> = split_iter.map(unescape).collect::<Option<_>>()?;
+                if keys.is_empty() {
+                    return None;
+                }
+                Some(Command::BMGET { keys })
+            }
+            "bd" => {
+                let key = unescape(split_iter.next()?)?;
+                Some(Command::BDELETE { key })
+            }
+            "bc" => {
+                let key = unescape(split_iter.next()?)?;
+                let expected = match split_iter.next()? {
+                    "*" => None,
+                    expected => Some(unescape(expected)?),
+                };
+                let val = unescape(split_iter.next()?)?;
+                Some(Command::BCAS { key, expected, val })
+            }
+            "bG" => {
+                let key = unescape(split_iter.next()?)?;
+                let val = unescape(split_iter.next()?)?;
+                Some(Command::BGETSET { key, val })
+            }
+            "bD" => {
+                let key = unescape(split_iter.next()?)?;
+                Some(Command::BGETDEL { key })
+            }
+            "bM" => {
+                let key = unescape(split_iter.next()?)?;
+                let operand = unescape(split_iter.next()?)?;
+                Some(Command::BMERGE { key, operand })
+            }
+            "br" => {
+                // br <min> <max|*> [limit] [rev] [next=<key>] [val=<min>..<max>] [byval]
+                let min_key = unescape(split_iter.next()?)?;
+                let max_key = match split_iter.next()? {
+                    "*" => None,
+                    max_key => Some(unescape(max_key)?),
+                };
+
+                let mut limit = None;
+                let mut reverse = false;
+                let mut continuation = None;
+                let mut value_filter = None;
+                let mut by_value = false;
+                for option in split_iter {
+                    if option == "rev" {
+                        reverse = true;
+                    } else if option == "byval" {
+                        by_value = true;
+                    } else if let Some(key) = option.strip_prefix("next=") {
+                        continuation = Some(unescape(key)?);
+                    } else if let Some(bounds) = option.strip_prefix("val=") {
+                        // an empty max means no upper bound
+                        let (min_val, max_val) = bounds.split_once("..")?;
+                        let max_val = match max_val {
+                            "" => None,
+                            max_val => Some(unescape(max_val)?),
+                        };
+                        value_filter = Some((unescape(min_val)?, max_val));
+                    } else {
+                        limit = Some(option.parse().ok()?);
+                    }
+                }
+
+                Some(Command::BRANGE {
+                    min_key,
+                    max_key,
+                    limit,
+                    reverse,
+                    continuation,
+                    value_filter,
+                    by_value,
+                })
+            }
+            "ba" => {
+                let min_key = unescape(split_iter.next()?)?;
+                let max_key = match split_iter.next()? {
+                    "*" => None,
+                    max_key => Some(unescape(max_key)?),
+                };
+                let aggregate = aggregate_code(split_iter.next()?)?;
+                Some(Command::BAGGREGATE {
+                    min_key,
+                    max_key,
+                    aggregate,
+                })
+            }
+            "bV" => Some(Command::BLOOKUP {
+                val: unescape(split_iter.next()?)?,
+            }),
+            "bv" => {
+                let min_val = unescape(split_iter.next()?)?;
+                let max_val = match split_iter.next()? {
+                    "*" => None,
+                    max_val => Some(unescape(max_val)?),
+                };
+                let limit = match split_iter.next() {
+                    Some(limit) => Some(limit.parse().ok()?),
+                    None => None,
+                };
+                Some(Command::BVRANGE {
+                    min_val,
+                    max_val,
+                    limit,
+                })
+            }
+            "bB" => {
+                let mut commands = vec![];
+                while let Some(op) = split_iter.next() {
+                    let key = unescape(split_iter.next()?)?;
+                    let val = match op {
+                        "p" => Some(unescape(split_iter.next()?)?),
+                        "d" => None,
+                        _ => return None,
+                    };
+                    commands.push((key, val));
+                }
+                Some(Command::BBATCH { commands })
+            }
+            "fc" => {
+                // fc <name> [--filters f,...] [--learned-index] [--range-filter] [--mmap]
+                let name = split_iter.next()?.to_owned();
+                let flags = split_iter.collect::<Vec<_>>().join(" ");
+                Some(Command::FCREATE { name, flags })
+            }
+            "fd" => {
+                let name = split_iter.next()?.to_owned();
+                Some(Command::FDROP { name })
+            }
+            "fl" => Some(Command::FLIST),
+            "h" => {
+                // h <version> [capabilities]
+                let version = split_iter.next()?.parse().ok()?;
+                let capabilities = match split_iter.next() {
+                    Some(capabilities) => capabilities.parse().ok()?,
+                    None => CAPABILITIES,
+                };
+                Some(Command::HELLO {
+                    version,
+                    capabilities,
+                })
+            }
+            "n" => {
+                // n <family> <command>
+                let family = split_iter.next()?.to_owned();
+                let command = Self::from_input(&split_iter.collect::<Vec<_>>().join(" "))?;
+                if matches!(
+                    command,
+                    Self::FCREATE { .. }
+                        | Self::FDROP { .. }
+                        | Self::FLIST
+                        | Self::HELLO { .. }
+                        | Self::NAMESPACED { .. }
+                        | Self::COMMIT { .. }
+                        | Self::ABORT { .. }
+                        | Self::TRANSACTED { .. }
+                ) {
+                    return None;
+                }
+                Some(Command::NAMESPACED {
+                    family,
+                    command: Box::new(command),
+                })
+            }
+            "tb" => Some(Command::BEGIN),
+            "tc" => {
+                let id = split_iter.next()?.parse().ok()?;
+                Some(Command::COMMIT { id })
+            }
+            "ta" => {
+                let id = split_iter.next()?.parse().ok()?;
+                Some(Command::ABORT { id })
+            }
+            "x" => {
+                // x <id> <get, put or delete>
+                let id = split_iter.next()?.parse().ok()?;
+                let command = Self::from_input(&split_iter.collect::<Vec<_>>().join(" "))?;
+                if !matches!(
+                    command,
+                    Self::GET { .. }
+                        | Self::PUT { .. }
+                        | Self::DELETE { .. }
+                        | Self::BGET { .. }
+                        | Self::BPUT { .. }
+                        | Self::BDELETE { .. }
+                ) {
+                    return None;
+                }
+                Some(Command::TRANSACTED {
+                    id,
+                    command: Box::new(command),
+                })
+            }
             _ => None,
         }
     }
-}
\ No newline at end of file
+}
+
+fn range_flags(reverse: bool, continuation: bool, value_filter: bool, by_value: bool) -> u8 {
+    let mut flags = 0;
+    if reverse {
+        flags |= RANGE_FLAG_REVERSE;
+    }
+    if continuation {
+        flags |= RANGE_FLAG_CONTINUATION;
+    }
+    if value_filter {
+        flags |= RANGE_FLAG_VALUE_FILTER;
+    }
+    if by_value {
+        flags |= RANGE_FLAG_BY_VALUE;
+    }
+    flags
+}
+
+fn aggregate_code(name: &str) -> Option<u8> {
+    match name {
+        "count" => Some(b'c'),
+        "sum" => Some(b's'),
+        "min" => Some(b'<'),
+        "max" => Some(b'>'),
+        _ => None,
+    }
+}
+
+/// i32s as numbers and anything else quoted for integer commands, see `escape`
+fn write_value(out: &mut String, bytes: &[u8], int: bool) {
+    match bytes.try_into() {
+        Ok(val) if int => write!(out, "{}", i32::from_be_bytes(val)).unwrap(),
+        _ if int => {
+            out.push('"');
+            escape(out, bytes);
+            out.push('"');
+        }
+        _ => escape(out, bytes),
+    }
+}
+
+fn take_sized<'a>(body: &mut &'a [u8]) -> io::Result<&'a [u8]> {
+    let (len, rest) = body.split_first_chunk().ok_or_else(invalid_response)?;
+    let len = u32::from_be_bytes(*len) as usize;
+    if rest.len() < len {
+        return Err(invalid_response());
+    }
+    let (bytes, rest) = rest.split_at(len);
+    *body = rest;
+    Ok(bytes)
+}
+
+fn invalid_response() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid response")
+}
+
+fn put_name(buf: &mut Vec<u8>, name: &str) {
+    buf.put_u8(name.len() as u8);
+    buf.put_slice(name.as_bytes());
+}
+
+fn put_sized(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.put_u16(bytes.len() as u16);
+    buf.put_slice(bytes);
+}
+
+/// Escapes non printable bytes like `escape_ascii`, and spaces and colons as well so they can't
+/// be confused with the separators of a rendered response
+fn escape(out: &mut String, bytes: &[u8]) {
+    for &b in bytes {
+        match b {
+            b' ' | b':' => write!(out, "\\x{b:02x}").unwrap(),
+            _ => write!(out, "{}", b.escape_ascii()).unwrap(),
+        }
+    }
+}
+
+/// Reverses the escaping of keys and values in responses (`\xNN`, `\n`, `\\`, ...) so they can
+/// be pasted back into commands
+fn unescape(input: &str) -> Option<Vec<u8>> {
+    let mut res = Vec::with_capacity(input.len());
+    let mut bytes = input.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            res.push(b);
+            continue;
+        }
+
+        res.push(match bytes.next()? {
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b't' => b'\t',
+            b'0' => b'\0',
+            b'x' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            other => other, // \\, \' and \"
+        });
+    }
+    Some(res)
+}
@@ -1,10 +1,10 @@
-use core::str;
 use std::{
-    io::{self, BufRead, BufReader, Write},
+    collections::VecDeque,
+    io::{self, BufReader, Write},
     net::TcpStream,
 };
 
-use clap::{command, Parser};
+use clap::Parser;
 use command::Command;
 mod command;
 
@@ -13,6 +13,9 @@ mod command;
 struct Args {
     #[arg(short, long, default_value_t = 1234)]
     port: u16,
+    /// Commands sent ahead of their responses, for piping in scripts like `generator`
+    #[arg(long, default_value_t = 1)]
+    pipeline: usize,
 }
 
 fn main() -> io::Result<()> {
@@ -24,43 +27,67 @@ fn main() -> io::Result<()> {
         let mut write_half = stream;
         let mut read_half = BufReader::new(write_half.try_clone()?);
 
+        // greet first, a server speaking another version of the protocol says so
+        let hello = Command::HELLO {
+            version: command::PROTOCOL_VERSION,
+            capabilities: command::CAPABILITIES,
+        };
+        hello.serialize(&mut output_buf);
+        write_half.write_all(&output_buf)?;
+        output_buf.clear();
+        let greeting = hello.read_response(&mut read_half)?;
+        if let Some(err) = greeting.strip_prefix("ERR ") {
+            println!("Could not greet server at 127.0.0.1:{}: {err}", args.port);
+            return Ok(());
+        }
+
+        let depth = args.pipeline.max(1);
+        let mut in_flight = VecDeque::with_capacity(depth);
         loop {
             // prompt
-            print!("127.0.0.1:{}> ", args.port);
-            std::io::stdout().flush()?;
+            if depth == 1 {
+                print!("127.0.0.1:{}> ", args.port);
+                std::io::stdout().flush()?;
+            }
 
             // read
-            if std::io::stdin().read_line(&mut input_buf)? == 0 {
-                break;
+            let eof = std::io::stdin().read_line(&mut input_buf)? == 0;
+            if !eof {
+                input_buf.pop(); // \n
+                if let Some(command) = Command::from_input(&input_buf) {
+                    command.serialize(&mut output_buf);
+                    in_flight.push_back(command);
+                } else {
+                    println!("Invalid command...");
+                }
+                input_buf.clear();
             }
 
-            input_buf.pop(); // \n
-            if let Some(command) = Command::from_input(&input_buf) {
-                // send
-                command.serialize(&mut output_buf);
-                write_half.write_all(&output_buf)?;
-                output_buf.clear();
+            // send once the pipeline is full or the input is over
+            if in_flight.len() < depth && !eof {
+                continue;
+            }
+            write_half.write_all(&output_buf)?;
+            output_buf.clear();
 
-                // recv
-                read_half.read_until(0x00, &mut output_buf)?;
-                if output_buf.is_empty() || !output_buf.ends_with(b"\0") {
+            // recv, oldest first, until there is room for the next command
+            while in_flight.len() >= depth || (eof && !in_flight.is_empty()) {
+                let command = in_flight.pop_front().unwrap();
+                let Ok(response) = command.read_response(&mut read_half) else {
                     // connection was cut off
                     println!(
                         "Could not read response from server at 127.0.0.1:{}: Connection dropped",
                         args.port
                     );
-                    break;
-                }
-                output_buf.pop(); // \0
+                    return Ok(());
+                };
 
                 // print
-                println!("{}", unsafe { str::from_utf8_unchecked(&output_buf) });
-                output_buf.clear();
-            } else {
-                println!("Invalid command...");
+                println!("{response}");
+            }
+            if eof {
+                break;
             }
-
-            input_buf.clear();
         }
     } else {
         println!(
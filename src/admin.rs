@@ -0,0 +1,364 @@
+use std::{fmt::Write, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::timeout,
+};
+
+use crate::database::{counters::COUNTERS, families::Families, Database};
+use crate::metrics::Metrics;
+
+// Requests with a longer head than this are refused, no endpoint takes parameters
+const MAX_REQUEST_HEAD_BYTES: u64 = 1 << 13;
+// Connections that haven't sent their request by then are dropped, so they can't hold up
+// shutting down
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves one HTTP/1.1 request of the admin listener and closes the connection:
+///
+/// - `GET /metrics`: request counts and latencies, level sizes, flush, compaction and filter
+///   counters in the Prometheus text format
+/// - `GET /health`: `ok` while the server is up
+/// - `GET /levels`: JSON view of the memtable and the tables of every disk level, per family
+pub async fn handle_connection(stream: TcpStream, families: Arc<Families>, metrics: Arc<Metrics>) {
+    let (read, mut write) = stream.into_split();
+    let mut read = BufReader::new(read).take(MAX_REQUEST_HEAD_BYTES);
+
+    let Ok(read_res) = timeout(REQUEST_TIMEOUT, read_request_line(&mut read)).await else {
+        return;
+    };
+    let response = match read_res {
+        Ok(Some((method, path))) => match (method.as_str(), path.as_str()) {
+            ("GET", "/metrics") => {
+                let text = prometheus_text(&families, &metrics).await;
+                ok("text/plain; version=0.0.4", text)
+            }
+            ("GET", "/health") => ok("text/plain", "ok\n".to_owned()),
+            ("GET", "/levels") => ok("application/json", levels_json(&families).await),
+            ("GET", _) => status("404 Not Found"),
+            _ => status("405 Method Not Allowed"),
+        },
+        Ok(None) => status("400 Bad Request"),
+        Err(_) => return,
+    };
+    let _ = write.write_all(response.as_bytes()).await;
+    let _ = write.shutdown().await;
+}
+
+/// Method and path of the request, without the query. Reads past the headers, which are
+/// ignored. `None` if the request isn't HTTP.
+async fn read_request_line<R: AsyncBufReadExt + Unpin>(
+    reader: &mut R,
+) -> io::Result<Option<(String, String)>> {
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target), Some(version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Ok(None);
+    };
+    if !version.starts_with("HTTP/") {
+        return Ok(None);
+    }
+    let path = target.split('?').next().unwrap_or_default();
+    let request = (method.to_owned(), path.to_owned());
+
+    let mut header = String::new();
+    loop {
+        header.clear();
+        if reader.read_line(&mut header).await? == 0 || header.trim_end().is_empty() {
+            break;
+        }
+    }
+    Ok(Some(request))
+}
+
+fn ok(content_type: &str, body: String) -> String {
+    format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+fn status(status: &str) -> String {
+    let body = format!("{status}\n");
+    format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+/// The default family first, as `None`, then the named ones
+async fn all_families(families: &Families) -> Vec<(Option<String>, Arc<Database>)> {
+    let mut all = vec![(None, families.get(None).await.unwrap())];
+    for name in families.names().await {
+        // dropped since the names were listed
+        if let Some(db) = families.get(Some(&name)).await {
+            all.push((Some(name), db));
+        }
+    }
+    all
+}
+
+async fn prometheus_text(families: &Families, metrics: &Metrics) -> String {
+    let mut out = String::new();
+    metrics.write_prometheus(&mut out);
+
+    let counter = |out: &mut String, name: &str, help: &str, val: u64| {
+        writeln!(
+            out,
+            "# HELP {name} {help}\n# TYPE {name} counter\n{name} {val}"
+        )
+        .unwrap();
+    };
+    counter(
+        &mut out,
+        "lsm_tree_flushes_total",
+        "Memtables flushed to disk.",
+        COUNTERS.get(&COUNTERS.flushes),
+    );
+    out.push_str("# HELP lsm_tree_compactions_total Compactions into each disk level.\n");
+    out.push_str("# TYPE lsm_tree_compactions_total counter\n");
+    for (idx, compactions) in COUNTERS.compactions.iter().enumerate() {
+        writeln!(
+            out,
+            "lsm_tree_compactions_total{{level=\"{}\"}} {}",
+            idx + 1,
+            COUNTERS.get(compactions)
+        )
+        .unwrap();
+    }
+    counter(
+        &mut out,
+        "lsm_tree_table_bytes_written_total",
+        "File bytes of the tables written by flushes and compactions.",
+        COUNTERS.get(&COUNTERS.table_bytes_written),
+    );
+    counter(
+        &mut out,
+        "lsm_tree_filter_probes_total",
+        "Point filter lookups.",
+        COUNTERS.get(&COUNTERS.filter_probes),
+    );
+    counter(
+        &mut out,
+        "lsm_tree_filter_negatives_total",
+        "Point filter lookups that ruled the key out.",
+        COUNTERS.get(&COUNTERS.filter_negatives),
+    );
+
+    let mut sizes = vec![];
+    for (name, db) in all_families(families).await {
+        let name = name.unwrap_or_else(|| "default".to_owned());
+        sizes.push((escape_label(&name), db.level_sizes().await));
+    }
+    // the memtable is level 0, like in `STATS`
+    let gauge = |out: &mut String, name: &str, help: &str| {
+        writeln!(out, "# HELP {name} {help}\n# TYPE {name} gauge").unwrap();
+    };
+    gauge(
+        &mut out,
+        "lsm_tree_memtable_records",
+        "Records in the memtable.",
+    );
+    for (family, sizes) in &sizes {
+        let records = sizes.mem_records;
+        writeln!(
+            out,
+            "lsm_tree_memtable_records{{family=\"{family}\"}} {records}"
+        )
+        .unwrap();
+    }
+    gauge(
+        &mut out,
+        "lsm_tree_level_tables",
+        "Tables in each disk level.",
+    );
+    for (family, sizes) in &sizes {
+        for level in &sizes.levels {
+            writeln!(
+                out,
+                "lsm_tree_level_tables{{family=\"{family}\",level=\"{}\"}} {}",
+                level.level,
+                level.tables.len()
+            )
+            .unwrap();
+        }
+    }
+    gauge(
+        &mut out,
+        "lsm_tree_level_bytes",
+        "Encoded bytes of the memtable and file bytes of each disk level.",
+    );
+    for (family, sizes) in &sizes {
+        let mem_bytes = sizes.mem_bytes;
+        writeln!(
+            out,
+            "lsm_tree_level_bytes{{family=\"{family}\",level=\"0\"}} {mem_bytes}"
+        )
+        .unwrap();
+        for level in &sizes.levels {
+            writeln!(
+                out,
+                "lsm_tree_level_bytes{{family=\"{family}\",level=\"{}\"}} {}",
+                level.level,
+                level.file_bytes()
+            )
+            .unwrap();
+        }
+    }
+    gauge(
+        &mut out,
+        "lsm_tree_filter_bytes",
+        "Memory taken by the point filters of each disk level.",
+    );
+    for (family, sizes) in &sizes {
+        for level in &sizes.levels {
+            writeln!(
+                out,
+                "lsm_tree_filter_bytes{{family=\"{family}\",level=\"{}\",filter=\"{}\"}} {}",
+                level.level,
+                level.filter.name(),
+                level.filter_bytes
+            )
+            .unwrap();
+        }
+    }
+    out
+}
+
+async fn levels_json(families: &Families) -> String {
+    let mut out = String::from("{\"families\":[");
+    for (idx, (name, db)) in all_families(families).await.into_iter().enumerate() {
+        if idx > 0 {
+            out.push(',');
+        }
+        let sizes = db.level_sizes().await;
+        match name {
+            Some(name) => write!(out, "{{\"name\":\"{}\",", escape_json(&name)).unwrap(),
+            None => out.push_str("{\"name\":null,"),
+        }
+        write!(
+            out,
+            "\"memtable\":{{\"records\":{},\"bytes\":{}}},\"levels\":[",
+            sizes.mem_records, sizes.mem_bytes
+        )
+        .unwrap();
+
+        for (idx, level) in sizes.levels.iter().enumerate() {
+            if idx > 0 {
+                out.push(',');
+            }
+            write!(
+                out,
+                "{{\"level\":{},\"bytes\":{},\"filter\":\"{}\",\"filter_bytes\":{},\"tables\":[",
+                level.level,
+                level.file_bytes(),
+                level.filter.name(),
+                level.filter_bytes
+            )
+            .unwrap();
+            for (idx, table) in level.tables.iter().enumerate() {
+                if idx > 0 {
+                    out.push(',');
+                }
+                // keys are arbitrary bytes, sent as hex
+                write!(
+                    out,
+                    "{{\"id\":{},\"bytes\":{},\"min_key\":\"{}\",\"max_key\":\"{}\"}}",
+                    table.id,
+                    table.file_size,
+                    hex(&table.min_key),
+                    hex(&table.max_key)
+                )
+                .unwrap();
+            }
+            out.push_str("]}");
+        }
+        out.push_str("]}");
+    }
+    out.push_str("]}\n");
+    out
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn escape_label(val: &str) -> String {
+    val.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn escape_json(val: &str) -> String {
+    let mut out = String::with_capacity(val.len());
+    for c in val.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    async fn request_line(input: &[u8]) -> (io::Result<Option<(String, String)>>, Vec<u8>) {
+        let mut reader = input;
+        let res = read_request_line(&mut reader).await;
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest).await.unwrap();
+        (res, rest)
+    }
+
+    #[tokio::test]
+    async fn reads_the_method_and_path_past_the_headers() {
+        let (res, rest) = request_line(
+            b"GET /metrics?name[]=x HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\n\r\nbody",
+        )
+        .await;
+        assert_eq!(
+            res.unwrap(),
+            Some(("GET".to_owned(), "/metrics".to_owned()))
+        );
+        assert_eq!(rest, b"body");
+
+        let (res, _) = request_line(b"POST /health HTTP/1.0\n\n").await;
+        assert_eq!(
+            res.unwrap(),
+            Some(("POST".to_owned(), "/health".to_owned()))
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_requests_that_arent_http() {
+        for input in [
+            &b""[..],
+            b"\r\n",
+            b"GET /health\r\n\r\n",
+            b"GET /health SPDY/3\r\n\r\n",
+        ] {
+            assert_eq!(request_line(input).await.0.unwrap(), None, "{input:?}");
+        }
+        let (res, _) = request_line(b"GET /\xFF HTTP/1.1\r\n\r\n").await;
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn escapes_labels_and_json_strings() {
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+        assert_eq!(
+            escape_json("a\"b\\c\nd\u{1}é"),
+            "a\\\"b\\\\c\\u000ad\\u0001é"
+        );
+        assert_eq!(hex(&[0x00, 0xAB, 0x7F]), "00ab7f");
+    }
+}
@@ -1,70 +1,881 @@
-use std::fmt::Write;
-use std::i32;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fmt;
+use std::iter::Peekable;
+use std::time::Duration;
+
+use bytes::Bytes;
 use tokio::io;
 use tokio::io::AsyncBufReadExt;
 use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::config::{MAX_KEY_BYTES, MAX_VALUE_BYTES};
+use crate::database::codec::Codec;
+use crate::database::families::{Families, FamilyError};
+use crate::database::transaction::{Transaction, TransactionConflict};
+use crate::database::write_batch::WriteBatch;
+use crate::database::{run_blocking, Database};
+use crate::metrics::Op;
+
+const RESPONSE_CHUNK_BYTES: usize = 1 << 16;
+const RANGE_CHUNK_PAIRS: usize = 1 << 12;
+
+const RANGE_FLAG_REVERSE: u8 = 1;
+const RANGE_FLAG_CONTINUATION: u8 = 1 << 1;
+const RANGE_FLAG_VALUE_FILTER: u8 = 1 << 2;
+const RANGE_FLAG_BY_VALUE: u8 = 1 << 3;
+
+const CAS_FLAG_EXPECTED: u8 = 1;
 
-use crate::database::Database;
+/// Version of the protocol spoken by the server, the framed responses are version 1.
+/// Connections that never send `HELLO` speak it too.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Requests may be sent ahead of the replies to the previous ones, always granted
+pub const CAPABILITY_PIPELINING: u32 = 1;
+/// Long ranges may be answered in several frames, all but the last with a `MORE` status.
+/// Without it they are answered in a single frame. The range is reopened after every frame
+/// sent, so writes made meanwhile can show up in the frames that follow.
+pub const CAPABILITY_STREAMING: u32 = 1 << 1;
+const CAPABILITIES: u32 = CAPABILITY_PIPELINING | CAPABILITY_STREAMING;
+
+// tags of the items making up a response body
+const ITEM_NIL: u8 = b'n';
+const ITEM_VALUE: u8 = b'v';
+const ITEM_PAIR: u8 = b'p';
+const ITEM_NEXT: u8 = b'c';
+const ITEM_INT: u8 = b'i';
+const ITEM_TEXT: u8 = b't';
+
+/// How the keys and values of a command were sent, and how they are written back
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Big endian i32s, answered the same way. Anything stored that isn't an i32 is answered
+    /// as is.
+    Int,
+    /// Byte strings behind a u16 length, answered as they are stored
+    Bytes,
+}
+
+impl Format {
+    /// Appends `bytes` behind a u32 length
+    fn write(self, out: &mut Vec<u8>, bytes: &[u8]) {
+        match (self, i32::decode_slice(bytes)) {
+            (Self::Int, Some(val)) => {
+                out.extend_from_slice(&4u32.to_be_bytes());
+                out.extend_from_slice(&val.to_be_bytes());
+            }
+            _ => {
+                out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+                out.extend_from_slice(bytes);
+            }
+        }
+    }
+}
+
+/// Status byte leading every response frame
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Status {
+    #[default]
+    Ok = 0,
+    /// The key asked for has no value (or the range no pairs to aggregate)
+    NotFound = 1,
+    /// The body is a UTF-8 message instead of items
+    Error = 2,
+    /// A compare and set found another value, the body holds it unless the key is absent
+    Failed = 3,
+    /// Part of a long response, the frames that follow continue it
+    More = 4,
+}
+
+/// A response being built. Responses are sent as frames of a status byte, the u32 length of
+/// the body and the body, a sequence of items that each start with a tag byte:
+///
+/// - `n`: a miss among the values of `MGET`
+/// - `v`: a value (or key), behind a u32 length
+/// - `p`: a pair, the key and the value each behind a u32 length
+/// - `c`: the continuation key of a range page, behind a u32 length
+/// - `i`: a big endian i128, counts, sums and transaction ids
+/// - `t`: UTF-8 text behind a u32 length, `STATS`
+///
+/// Writes answer with an empty body.
+#[derive(Debug)]
+pub struct Response {
+    status: Status,
+    body: Vec<u8>,
+    /// Whether the connection granted `CAPABILITY_STREAMING`, off until a `HELLO` asks for it
+    streaming: bool,
+}
+
+impl Default for Response {
+    fn default() -> Self {
+        Self {
+            status: Status::Ok,
+            body: Vec::new(),
+            streaming: false,
+        }
+    }
+}
+
+impl Response {
+    fn set_status(&mut self, status: Status) {
+        self.status = status;
+    }
+
+    pub fn error(&mut self, message: impl fmt::Display) {
+        self.status = Status::Error;
+        self.body.clear();
+        self.body.extend_from_slice(message.to_string().as_bytes());
+    }
+
+    fn nil(&mut self) {
+        self.body.push(ITEM_NIL);
+    }
+
+    fn value(&mut self, format: Format, val: &[u8]) {
+        self.body.push(ITEM_VALUE);
+        format.write(&mut self.body, val);
+    }
+
+    fn pair(&mut self, format: Format, key: &[u8], val: &[u8]) {
+        self.body.push(ITEM_PAIR);
+        format.write(&mut self.body, key);
+        format.write(&mut self.body, val);
+    }
+
+    fn next(&mut self, format: Format, key: &[u8]) {
+        self.body.push(ITEM_NEXT);
+        format.write(&mut self.body, key);
+    }
+
+    fn int(&mut self, val: i128) {
+        self.body.push(ITEM_INT);
+        self.body.extend_from_slice(&val.to_be_bytes());
+    }
+
+    fn text(&mut self, text: &str) {
+        self.body.push(ITEM_TEXT);
+        Format::Bytes.write(&mut self.body, text.as_bytes());
+    }
+
+    /// Appends the frame of the response to `to` and resets it for the next one
+    pub fn write_frame(&mut self, to: &mut Vec<u8>) {
+        to.push(self.status as u8);
+        to.extend_from_slice(&(self.body.len() as u32).to_be_bytes());
+        to.append(&mut self.body);
+        self.status = Status::Ok;
+    }
+
+    /// Sends the items so far ahead of the rest of the response, unless the connection doesn't
+    /// stream
+    async fn send_partial<W: AsyncWrite + Unpin>(&mut self, writer: &mut W) -> io::Result<()> {
+        if !self.streaming {
+            return Ok(());
+        }
+        let mut frame = Vec::with_capacity(self.body.len() + 5);
+        let status = std::mem::replace(&mut self.status, Status::More);
+        self.write_frame(&mut frame);
+        self.status = status;
+        writer.write_all(&frame).await
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RangeOptions {
+    pub limit: Option<u32>,
+    pub reverse: bool,
+    /// Last key of the previous page, the page starts right after it in iteration order
+    pub continuation: Option<Bytes>,
+    /// Only pairs whose value is within `[min, max]`, no max means no upper bound
+    pub value_filter: Option<(Bytes, Option<Bytes>)>,
+    /// Orders pairs by value (then key) instead of by key, `reverse` puts the largest first
+    /// and `limit` keeps the top pairs only. Answered in one page without a continuation.
+    pub by_value: bool,
+}
+
+/// Folds the pairs of a range into one reply on the server. Sums add values that are i32 or
+/// i64 encodings (4 or 8 bytes long) and fail on any other value, min and max compare values
+/// as bytes, which orders integers like numbers.
+#[derive(Clone, Copy, Debug)]
+pub enum Aggregate {
+    Count,
+    Sum,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    fn from_byte(byte: u8) -> io::Result<Self> {
+        Ok(match byte {
+            b'c' => Self::Count,
+            b's' => Self::Sum,
+            b'<' => Self::Min,
+            b'>' => Self::Max,
+            _ => return Err(invalid_data("Invalid aggregate!")),
+        })
+    }
+
+    /// Counts and sums are answered as integers, min and max as the value (`NOT_FOUND` for an
+    /// empty range). Sums over a value that isn't an integer are answered with an error.
+    fn evaluate(
+        self,
+        pairs: impl Iterator<Item = (Bytes, Bytes)>,
+        format: Format,
+        out: &mut Response,
+    ) {
+        let values = pairs.map(|(_, val)| val);
+        match self {
+            Self::Count => out.int(values.count() as i128),
+            Self::Sum => {
+                let sum = values
+                    .map(integer)
+                    .try_fold(0i128, |sum, val| Some(sum + val?));
+                match sum {
+                    Some(sum) => out.int(sum),
+                    None => out.error("sum of a value that isn't an i32 or i64"),
+                }
+            }
+            Self::Min => match values.min() {
+                Some(min) => out.value(format, &min),
+                None => out.set_status(Status::NotFound),
+            },
+            Self::Max => match values.max() {
+                Some(max) => out.value(format, &max),
+                None => out.set_status(Status::NotFound),
+            },
+        }
+    }
+}
+
+/// The i32 or i64 a value is the encoding of, going by its length like merges do
+fn integer(val: Bytes) -> Option<i128> {
+    match val.len() {
+        4 => i32::decode(val).map(i128::from),
+        8 => i64::decode(val).map(i128::from),
+        _ => None,
+    }
+}
 
 #[derive(Clone, Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Command {
-    PUT { key: i32, val: i32 },
-    GET { key: i32 },
-    DELETE { key: i32 },
-    LOAD { data: Vec<u8> },
-    RANGE { min_key: i32, max_key: i32 },
+    PUT {
+        key: Bytes,
+        val: Bytes,
+    },
+    PUTEX {
+        key: Bytes,
+        val: Bytes,
+        ttl: Duration,
+    },
+    GET {
+        key: Bytes,
+    },
+    MGET {
+        keys: Vec<Bytes>,
+    },
+    DELETE {
+        key: Bytes,
+    },
+    CAS {
+        key: Bytes,
+        expected: Option<Bytes>, // `None` if the key has to be absent
+        val: Bytes,
+    },
+    GETSET {
+        key: Bytes,
+        val: Bytes,
+    },
+    GETDEL {
+        key: Bytes,
+    },
+    MERGE {
+        key: Bytes,
+        operand: Bytes,
+    },
+    LOAD {
+        data: Vec<u8>,
+    },
+    BATCH {
+        batch: WriteBatch,
+    },
+    RANGE {
+        min_key: Bytes,
+        max_key: Option<Bytes>, // exclusive, `None` for no upper bound
+        options: RangeOptions,
+    },
+    AGGREGATE {
+        min_key: Bytes,
+        max_key: Option<Bytes>, // exclusive, `None` for no upper bound
+        aggregate: Aggregate,
+    },
+    LOOKUP {
+        val: Bytes,
+    },
+    VRANGE {
+        min_val: Bytes,
+        max_val: Option<Bytes>, // inclusive, `None` for no upper bound
+        limit: Option<u32>,
+    },
     STATS,
+    FCREATE {
+        name: String,
+        flags: String, // table flags like on the command line
+    },
+    FDROP {
+        name: String,
+    },
+    FLIST,
+    HELLO {
+        version: u16,
+        capabilities: u32, // `CAPABILITY_*` flags the client asks for
+    },
+    BEGIN,
+    COMMIT {
+        id: u32,
+    },
+    ABORT {
+        id: u32,
+    },
 }
 
-impl Command {
-    pub async fn execute(self, db: &Database, out: &mut String) {
+/// A command and the column family it runs on, `None` for the default family. Commands sent
+/// within a transaction run on the transaction's family instead.
+#[derive(Debug)]
+pub struct Request {
+    pub command: Command,
+    pub format: Format,
+    pub family: Option<String>,
+    pub transaction: Option<u32>,
+}
+
+/// State kept across the requests of a connection. Transactions still open when the
+/// connection closes are aborted.
+#[derive(Default)]
+pub struct Session {
+    transactions: HashMap<u32, OpenTransaction>,
+    next_transaction: u32,
+}
+
+struct OpenTransaction {
+    family: Option<String>,
+    tx: Transaction,
+}
+
+/// Failures answered with an `ERROR` status instead of closing the connection
+enum RequestError {
+    Family(FamilyError),
+    Conflict(TransactionConflict),
+    UnknownTransaction,
+    UnsupportedVersion(u16),
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::GET { key } => {
-                if let Some(val) = db.get(key).await {
-                    out.push_str(&val.to_string());
+            Self::Family(err) => err.fmt(f),
+            Self::Conflict(err) => err.fmt(f),
+            Self::UnknownTransaction => f.write_str("no such transaction"),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported protocol version {version}, the server speaks version {PROTOCOL_VERSION}"
+            ),
+        }
+    }
+}
+
+impl Request {
+    /// Builds the response in `out`, long responses may send parts of it to `writer` early.
+    /// The caller is responsible for writing the last frame of `out`. Family and transaction
+    /// errors are answered with an `ERROR` status.
+    pub async fn execute<W: AsyncWrite + Unpin>(
+        self,
+        families: &Families,
+        session: &mut Session,
+        out: &mut Response,
+        writer: &mut W,
+    ) -> io::Result<()> {
+        let result = match (self.transaction, self.command) {
+            (
+                None,
+                Command::HELLO {
+                    version,
+                    capabilities,
+                },
+            ) => {
+                if version != PROTOCOL_VERSION {
+                    Err(RequestError::UnsupportedVersion(version))
+                } else {
+                    // answered with the version and the capabilities granted out of the ones
+                    // asked for
+                    let granted = capabilities & CAPABILITIES;
+                    out.streaming = granted & CAPABILITY_STREAMING != 0;
+                    out.int(version.into());
+                    out.int(granted.into());
+                    return Ok(());
+                }
+            }
+            (Some(id), command) => {
+                run_in_transaction(command, id, self.format, families, session, out).await
+            }
+            (None, Command::FCREATE { name, flags }) => families
+                .create(&name, &flags)
+                .await
+                .map_err(RequestError::Family),
+            (None, Command::FDROP { name }) => {
+                families.remove(&name).await.map_err(RequestError::Family)
+            }
+            (None, Command::FLIST) => {
+                for name in families.names().await {
+                    out.value(Format::Bytes, name.as_bytes());
                 }
+                return Ok(());
             }
-            Self::DELETE { key } => {
-                db.delete(key).await;
-                out.push_str("OK");
+            (None, Command::BEGIN) => match families.get(self.family.as_deref()).await {
+                Some(db) => {
+                    let id = session.next_transaction;
+                    session.next_transaction = id.wrapping_add(1);
+                    let open = OpenTransaction {
+                        family: self.family,
+                        tx: db.begin().await,
+                    };
+                    session.transactions.insert(id, open);
+                    out.int(id.into());
+                    return Ok(());
+                }
+                None => Err(RequestError::Family(FamilyError::NotFound)),
+            },
+            (None, Command::COMMIT { id }) => match session.transactions.remove(&id) {
+                Some(open) => match families.get(open.family.as_deref()).await {
+                    Some(db) => db.commit(open.tx).await.map_err(RequestError::Conflict),
+                    None => Err(RequestError::Family(FamilyError::NotFound)),
+                },
+                None => Err(RequestError::UnknownTransaction),
+            },
+            (None, Command::ABORT { id }) => match session.transactions.remove(&id) {
+                Some(_) => Ok(()),
+                None => Err(RequestError::UnknownTransaction),
+            },
+            (None, command) => match families.get(self.family.as_deref()).await {
+                Some(db) => return command.execute(&db, self.format, out, writer).await,
+                None => Err(RequestError::Family(FamilyError::NotFound)),
+            },
+        };
+
+        if let Err(err) = result {
+            out.error(err);
+        }
+        Ok(())
+    }
+}
+
+/// `GET`, `PUT` and `DELETE` within the open transaction `id`, writes are answered once
+/// buffered
+async fn run_in_transaction(
+    command: Command,
+    id: u32,
+    format: Format,
+    families: &Families,
+    session: &mut Session,
+    out: &mut Response,
+) -> Result<(), RequestError> {
+    let open = session
+        .transactions
+        .get_mut(&id)
+        .ok_or(RequestError::UnknownTransaction)?;
+    let db = families
+        .get(open.family.as_deref())
+        .await
+        .ok_or(RequestError::Family(FamilyError::NotFound))?;
+
+    match command {
+        Command::GET { key } => match open.tx.get(&db, &key).await {
+            Some(val) => out.value(format, &val),
+            None => out.set_status(Status::NotFound),
+        },
+        Command::PUT { key, val } => open.tx.put(key, val),
+        Command::DELETE { key } => open.tx.delete(key),
+        _ => unreachable!("only GET, PUT and DELETE are read within transactions"),
+    }
+    Ok(())
+}
+
+impl Command {
+    /// What the command is counted as in the metrics
+    pub fn op(&self) -> Op {
+        match self {
+            Self::PUT { .. } | Self::PUTEX { .. } => Op::Put,
+            Self::GET { .. } => Op::Get,
+            Self::MGET { .. } => Op::MultiGet,
+            Self::DELETE { .. } => Op::Delete,
+            Self::CAS { .. } => Op::CompareAndSet,
+            Self::GETSET { .. } => Op::GetSet,
+            Self::GETDEL { .. } => Op::GetDelete,
+            Self::MERGE { .. } => Op::Merge,
+            Self::LOAD { .. } => Op::Load,
+            Self::BATCH { .. } => Op::Batch,
+            Self::RANGE { .. } => Op::Range,
+            Self::AGGREGATE { .. } => Op::Aggregate,
+            Self::LOOKUP { .. } => Op::Lookup,
+            Self::VRANGE { .. } => Op::ValueRange,
+            Self::STATS => Op::Stats,
+            Self::FCREATE { .. } | Self::FDROP { .. } | Self::FLIST => Op::Family,
+            Self::HELLO { .. } => Op::Hello,
+            Self::BEGIN | Self::COMMIT { .. } | Self::ABORT { .. } => Op::Transaction,
+        }
+    }
+
+    async fn execute<W: AsyncWrite + Unpin>(
+        self,
+        db: &Database,
+        format: Format,
+        out: &mut Response,
+        writer: &mut W,
+    ) -> io::Result<()> {
+        match self {
+            Self::GET { key } => match db.get(&key).await {
+                Some(val) => out.value(format, &val),
+                None => out.set_status(Status::NotFound),
+            },
+            Self::MGET { keys } => {
+                // one item per key in request order, misses included
+                for val in db.multi_get(&keys).await {
+                    match val {
+                        Some(val) => out.value(format, &val),
+                        None => out.nil(),
+                    }
+                }
             }
-            Self::PUT { key, val } => {
-                db.insert(key, val).await;
-                out.push_str("OK");
+            Self::DELETE { key } => db.delete(key).await,
+            Self::CAS { key, expected, val } => {
+                if let Err(current) = db.compare_and_set(key, expected.as_ref(), val).await {
+                    out.set_status(Status::Failed);
+                    if let Some(current) = current {
+                        out.value(format, &current);
+                    }
+                }
             }
+            // the old value, the write happens either way
+            Self::GETSET { key, val } => match db.get_set(key, val).await {
+                Some(old) => out.value(format, &old),
+                None => out.set_status(Status::NotFound),
+            },
+            Self::GETDEL { key } => match db.get_delete(key).await {
+                Some(old) => out.value(format, &old),
+                None => out.set_status(Status::NotFound),
+            },
+            Self::PUT { key, val } => db.insert(key, val).await,
+            Self::PUTEX { key, val, ttl } => db.insert_with_ttl(key, val, ttl).await,
+            Self::MERGE { key, operand } => db.merge(key, operand).await,
             Self::LOAD { data } => {
-                db.load(&data).await;
-                out.push_str("OK");
+                let pairs = data
+                    .chunks_exact(8)
+                    .map(|pair| {
+                        let key = i32::from_be_bytes(pair[..4].try_into().unwrap());
+                        let val = i32::from_be_bytes(pair[4..].try_into().unwrap());
+                        (key.encode(), val.encode())
+                    })
+                    .collect();
+                db.load(pairs).await;
+            }
+            Self::BATCH { batch } => db.write(batch).await,
+            Self::RANGE {
+                mut min_key,
+                mut max_key,
+                options,
+            } => {
+                if let Some(continuation) = &options.continuation {
+                    skip_past(
+                        continuation,
+                        format,
+                        options.reverse,
+                        &mut min_key,
+                        &mut max_key,
+                    );
+                }
+
+                if options.by_value {
+                    let pairs = open_range(db, &min_key, max_key.as_ref(), &options).await;
+                    let (limit, reverse) = (options.limit, options.reverse);
+                    let top = run_blocking(move || top_by_value(pairs, limit, reverse)).await;
+                    for (key, val) in top {
+                        out.pair(format, &key, &val);
+                    }
+                    return Ok(());
+                }
+
+                let mut pairs = None;
+                let mut last_key = None;
+                let mut remaining = options.limit.map_or(usize::MAX, |l| l as usize);
+                loop {
+                    let mut open = match pairs.take() {
+                        Some(open) => open,
+                        None => {
+                            // picks up right after the last pair sent
+                            if let Some(last_key) = &last_key {
+                                skip_past(
+                                    last_key,
+                                    format,
+                                    options.reverse,
+                                    &mut min_key,
+                                    &mut max_key,
+                                );
+                            }
+                            open_range(db, &min_key, max_key.as_ref(), &options).await
+                        }
+                    };
+
+                    // pull pairs off disk in chunks on the blocking pool
+                    let take = remaining.min(RANGE_CHUNK_PAIRS);
+                    let (returned, chunk, more) = run_blocking(move || {
+                        let chunk: Vec<(Bytes, Bytes)> = open.by_ref().take(take).collect();
+                        let more = open.peek().is_some();
+                        (open, chunk, more)
+                    })
+                    .await;
+                    pairs = Some(returned);
+                    remaining -= chunk.len();
+
+                    for (key, val) in chunk {
+                        out.pair(format, &key, &val);
+                        last_key = Some(key);
+                    }
+
+                    if !more || remaining == 0 {
+                        // continuation key for the next page, only sent when the page was cut short
+                        if let (Some(last_key), true) = (last_key, more) {
+                            out.next(format, &last_key);
+                        }
+                        break;
+                    }
+
+                    // stream large ranges instead of materializing the whole response. The
+                    // range is let go first, its level locks would hold up flushes and
+                    // compactions for as long as the client takes to read.
+                    if out.streaming && out.body.len() >= RESPONSE_CHUNK_BYTES {
+                        pairs = None;
+                        out.send_partial(writer).await?;
+                    }
+                }
+            }
+            Self::AGGREGATE {
+                min_key,
+                max_key,
+                aggregate,
+            } => {
+                // the pairs never leave the server, only the result is serialized
+                let pairs = db.range(&min_key, max_key.as_ref()).await;
+                let res = run_blocking(move || {
+                    let mut res = Response::default();
+                    aggregate.evaluate(pairs, format, &mut res);
+                    res
+                })
+                .await;
+                // only the reply is taken over, the connection keeps the capabilities it was
+                // granted
+                out.status = res.status;
+                out.body = res.body;
             }
-            Self::RANGE { min_key, max_key } => {
-                if let Some(iter) = db.range(min_key, max_key - 1).await {
-                    for (key, val) in iter {
-                        write!(out, "{key}:{} ", val).unwrap();
+            Self::LOOKUP { val } => match db.keys_with_value(&val).await {
+                Some(keys) => {
+                    for key in keys {
+                        out.value(format, &key);
                     }
                 }
+                None => out.error("no value index"),
+            },
+            Self::VRANGE {
+                min_val,
+                max_val,
+                limit,
+            } => {
+                let limit = limit.map(|l| l as usize);
+                match db.range_by_value(&min_val, max_val.as_ref(), limit).await {
+                    Some(pairs) => {
+                        for (key, val) in pairs {
+                            out.pair(format, &key, &val);
+                        }
+                    }
+                    None => out.error("no value index"),
+                }
             }
             Self::STATS => {
-                db.write_stats(out).await;
+                let mut stats = String::new();
+                db.write_stats(&mut stats).await;
+                out.text(&stats);
             }
+            Self::FCREATE { .. }
+            | Self::FDROP { .. }
+            | Self::FLIST
+            | Self::HELLO { .. }
+            | Self::BEGIN
+            | Self::COMMIT { .. }
+            | Self::ABORT { .. } => {
+                unreachable!("connection, family and transaction commands are run by the request")
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Commands with i32 keys and values use the original one byte opcodes, the same commands on
+/// byte strings are prefixed with `b`. Either can be prefixed with `n` and a family name to
+/// run on that family instead of the default one, `f` starts the family commands.
+///
+/// `t` starts the transaction commands. Gets, puts and deletes prefixed with `x` and a u32
+/// transaction id run within that transaction.
+///
+/// `h` (HELLO) is followed by the u16 protocol version and u32 capability flags of the client.
+pub async fn read_command<T: AsyncBufReadExt + Unpin>(reader: &mut T) -> io::Result<Request> {
+    let mut family = None;
+    let mut transaction = None;
+    let mut opcode = reader.read_u8().await?;
+    if opcode == b'n' {
+        family = Some(read_name(reader).await?);
+        opcode = reader.read_u8().await?;
+    } else if opcode == b'x' {
+        transaction = Some(reader.read_u32().await?);
+        opcode = reader.read_u8().await?;
+    }
+
+    let (command, format) = match opcode {
+        b'b' => (read_bytes_command(reader).await?, Format::Bytes),
+        b'f' if family.is_none() && transaction.is_none() => {
+            (read_family_command(reader).await?, Format::Bytes)
         }
+        b't' if transaction.is_none() => (read_transaction_command(reader).await?, Format::Int),
+        b'h' if family.is_none() && transaction.is_none() => (
+            Command::HELLO {
+                version: reader.read_u16().await?,
+                capabilities: reader.read_u32().await?,
+            },
+            Format::Int,
+        ),
+        opcode => (read_int_command(opcode, reader).await?, Format::Int),
+    };
+    if transaction.is_some()
+        && !matches!(
+            command,
+            Command::GET { .. } | Command::PUT { .. } | Command::DELETE { .. }
+        )
+    {
+        return Err(invalid_data(
+            "Only gets, puts and deletes run in transactions!",
+        ));
     }
+
+    Ok(Request {
+        command,
+        format,
+        family,
+        transaction,
+    })
+}
+
+/// `tb` begins a transaction on the family of the request and answers with its id, `tc` and
+/// `ta` commit and abort the transaction with the u32 id that follows
+async fn read_transaction_command<T: AsyncBufReadExt + Unpin>(
+    reader: &mut T,
+) -> io::Result<Command> {
+    Ok(match reader.read_u8().await? {
+        b'b' => Command::BEGIN,
+        b'c' => Command::COMMIT {
+            id: reader.read_u32().await?,
+        },
+        b'a' => Command::ABORT {
+            id: reader.read_u32().await?,
+        },
+        _ => return Err(invalid_data("Invalid incoming command!")),
+    })
 }
 
-pub async fn read_command<T: AsyncBufReadExt + Unpin>(reader: &mut T) -> io::Result<Command> {
+/// `fc` creates the family named by a u8 length and the name, tuned with table flags sent
+/// behind a u16 length. `fd` drops a family, `fl` lists them.
+async fn read_family_command<T: AsyncBufReadExt + Unpin>(reader: &mut T) -> io::Result<Command> {
     Ok(match reader.read_u8().await? {
+        b'c' => {
+            let name = read_name(reader).await?;
+            let flags = String::from_utf8(read_sized(reader, u16::MAX as usize).await?.into())
+                .map_err(|_| invalid_data("Family flags aren't UTF-8!"))?;
+            Command::FCREATE { name, flags }
+        }
+        b'd' => Command::FDROP {
+            name: read_name(reader).await?,
+        },
+        b'l' => Command::FLIST,
+        _ => return Err(invalid_data("Invalid incoming command!")),
+    })
+}
+
+async fn read_name<T: AsyncBufReadExt + Unpin>(reader: &mut T) -> io::Result<String> {
+    let len = reader.read_u8().await? as usize;
+    let mut buf = vec![0_u8; len];
+    reader.read_exact(&mut buf).await?;
+    String::from_utf8(buf).map_err(|_| invalid_data("Family name isn't UTF-8!"))
+}
+
+async fn read_int_command<T: AsyncBufReadExt + Unpin>(
+    opcode: u8,
+    reader: &mut T,
+) -> io::Result<Command> {
+    Ok(match opcode {
         b'p' => {
-            let key = reader.read_i32().await?;
-            let val = reader.read_i32().await?;
+            let key = reader.read_i32().await?.encode();
+            let val = reader.read_i32().await?.encode();
             Command::PUT { key, val }
         }
+        b'P' => {
+            // a put followed by its time to live in seconds
+            let key = reader.read_i32().await?.encode();
+            let val = reader.read_i32().await?.encode();
+            let ttl = Duration::from_secs(reader.read_u32().await?.into());
+            Command::PUTEX { key, val, ttl }
+        }
         b'g' => {
-            let key = reader.read_i32().await?;
+            let key = reader.read_i32().await?.encode();
             Command::GET { key }
         }
+        b'm' => {
+            let count = reader.read_u32().await?;
+            let mut keys = Vec::with_capacity(count.min(1 << 16) as usize);
+            for _ in 0..count {
+                keys.push(reader.read_i32().await?.encode());
+            }
+            Command::MGET { keys }
+        }
         b'd' => {
-            let key = reader.read_i32().await?;
+            let key = reader.read_i32().await?.encode();
             Command::DELETE { key }
         }
+        b'c' => {
+            // the expected value is only sent with `CAS_FLAG_EXPECTED`
+            let key = reader.read_i32().await?.encode();
+            let flags = reader.read_u8().await?;
+            let expected = if flags & CAS_FLAG_EXPECTED != 0 {
+                Some(reader.read_i32().await?.encode())
+            } else {
+                None
+            };
+            let val = reader.read_i32().await?.encode();
+            Command::CAS { key, expected, val }
+        }
+        b'G' => {
+            let key = reader.read_i32().await?.encode();
+            let val = reader.read_i32().await?.encode();
+            Command::GETSET { key, val }
+        }
+        b'D' => {
+            let key = reader.read_i32().await?.encode();
+            Command::GETDEL { key }
+        }
+        b'M' => {
+            let key = reader.read_i32().await?.encode();
+            let operand = reader.read_i32().await?.encode();
+            Command::MERGE { key, operand }
+        }
         b'l' => {
             let kv_pairs = reader.read_u64().await?;
             let mut buf = vec![0_u8; kv_pairs as usize * 8];
@@ -73,16 +884,599 @@ pub async fn read_command<T: AsyncBufReadExt + Unpin>(reader: &mut T) -> io::Res
             Command::LOAD { data: buf }
         }
         b'r' => {
-            let min_key = reader.read_i32().await?;
-            let max_key = reader.read_i32().await?;
-            Command::RANGE { min_key, max_key }
+            let min_key = reader.read_i32().await?.encode();
+            let max_key = reader.read_i32().await?.encode();
+            Command::RANGE {
+                min_key,
+                max_key: Some(max_key),
+                options: RangeOptions::default(),
+            }
+        }
+        b'R' => {
+            let min_key = reader.read_i32().await?.encode();
+            let max_key = reader.read_i32().await?.encode();
+            let limit = reader.read_u32().await?;
+            let flags = reader.read_u8().await?;
+            let continuation = if flags & RANGE_FLAG_CONTINUATION != 0 {
+                Some(reader.read_i32().await?.encode())
+            } else {
+                None
+            };
+            // inclusive bounds, so every value can be matched
+            let value_filter = if flags & RANGE_FLAG_VALUE_FILTER != 0 {
+                let min_val = reader.read_i32().await?.encode();
+                let max_val = reader.read_i32().await?.encode();
+                Some((min_val, Some(max_val)))
+            } else {
+                None
+            };
+
+            Command::RANGE {
+                min_key,
+                max_key: Some(max_key),
+                options: RangeOptions {
+                    limit: (limit != 0).then_some(limit),
+                    reverse: flags & RANGE_FLAG_REVERSE != 0,
+                    continuation,
+                    value_filter,
+                    by_value: flags & RANGE_FLAG_BY_VALUE != 0,
+                },
+            }
+        }
+        b'B' => {
+            // a count, then `p` with a key and value, `P` with a key, value and ttl, `d` with
+            // a key or `M` with a key and operand for every command
+            let count = reader.read_u32().await?;
+            let mut batch = WriteBatch::new();
+            for _ in 0..count {
+                match reader.read_u8().await? {
+                    b'p' => {
+                        let key = reader.read_i32().await?.encode();
+                        let val = reader.read_i32().await?.encode();
+                        batch.put(key, val);
+                    }
+                    b'P' => {
+                        let key = reader.read_i32().await?.encode();
+                        let val = reader.read_i32().await?.encode();
+                        let ttl = Duration::from_secs(reader.read_u32().await?.into());
+                        batch.put_with_ttl(key, val, ttl);
+                    }
+                    b'd' => batch.delete(reader.read_i32().await?.encode()),
+                    b'M' => {
+                        let key = reader.read_i32().await?.encode();
+                        let operand = reader.read_i32().await?.encode();
+                        batch.merge(key, operand);
+                    }
+                    _ => return Err(invalid_data("Invalid batch command!")),
+                }
+            }
+            Command::BATCH { batch }
+        }
+        b'a' => {
+            let min_key = reader.read_i32().await?.encode();
+            let max_key = reader.read_i32().await?.encode();
+            let aggregate = Aggregate::from_byte(reader.read_u8().await?)?;
+            Command::AGGREGATE {
+                min_key,
+                max_key: Some(max_key),
+                aggregate,
+            }
+        }
+        b'V' => Command::LOOKUP {
+            val: reader.read_i32().await?.encode(),
+        },
+        b'v' => {
+            let min_val = reader.read_i32().await?.encode();
+            let max_val = reader.read_i32().await?.encode();
+            let limit = reader.read_u32().await?;
+            Command::VRANGE {
+                min_val,
+                max_val: Some(max_val),
+                limit: (limit != 0).then_some(limit),
+            }
         }
         b's' => Command::STATS,
-        _ => {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Invalid incoming command!",
-            ))
+        _ => return Err(invalid_data("Invalid incoming command!")),
+    })
+}
+
+/// Same layout as the i32 commands with every key and value sent as a u16 length followed by
+/// the bytes. An empty max key in a range means no upper bound.
+async fn read_bytes_command<T: AsyncBufReadExt + Unpin>(reader: &mut T) -> io::Result<Command> {
+    Ok(match reader.read_u8().await? {
+        b'p' => {
+            let key = read_sized(reader, MAX_KEY_BYTES).await?;
+            let val = read_sized(reader, MAX_VALUE_BYTES).await?;
+            Command::PUT { key, val }
+        }
+        b'P' => {
+            let key = read_sized(reader, MAX_KEY_BYTES).await?;
+            let val = read_sized(reader, MAX_VALUE_BYTES).await?;
+            let ttl = Duration::from_secs(reader.read_u32().await?.into());
+            Command::PUTEX { key, val, ttl }
+        }
+        b'g' => {
+            let key = read_sized(reader, MAX_KEY_BYTES).await?;
+            Command::GET { key }
+        }
+        b'm' => {
+            let count = reader.read_u32().await?;
+            let mut keys = Vec::with_capacity(count.min(1 << 16) as usize);
+            for _ in 0..count {
+                keys.push(read_sized(reader, MAX_KEY_BYTES).await?);
+            }
+            Command::MGET { keys }
+        }
+        b'd' => {
+            let key = read_sized(reader, MAX_KEY_BYTES).await?;
+            Command::DELETE { key }
+        }
+        b'c' => {
+            let key = read_sized(reader, MAX_KEY_BYTES).await?;
+            let flags = reader.read_u8().await?;
+            let expected = if flags & CAS_FLAG_EXPECTED != 0 {
+                Some(read_sized(reader, MAX_VALUE_BYTES).await?)
+            } else {
+                None
+            };
+            let val = read_sized(reader, MAX_VALUE_BYTES).await?;
+            Command::CAS { key, expected, val }
+        }
+        b'G' => {
+            let key = read_sized(reader, MAX_KEY_BYTES).await?;
+            let val = read_sized(reader, MAX_VALUE_BYTES).await?;
+            Command::GETSET { key, val }
+        }
+        b'D' => {
+            let key = read_sized(reader, MAX_KEY_BYTES).await?;
+            Command::GETDEL { key }
+        }
+        b'M' => {
+            let key = read_sized(reader, MAX_KEY_BYTES).await?;
+            let operand = read_sized(reader, MAX_VALUE_BYTES).await?;
+            Command::MERGE { key, operand }
+        }
+        b'B' => {
+            let count = reader.read_u32().await?;
+            let mut batch = WriteBatch::new();
+            for _ in 0..count {
+                match reader.read_u8().await? {
+                    b'p' => {
+                        let key = read_sized(reader, MAX_KEY_BYTES).await?;
+                        let val = read_sized(reader, MAX_VALUE_BYTES).await?;
+                        batch.put(key, val);
+                    }
+                    b'P' => {
+                        let key = read_sized(reader, MAX_KEY_BYTES).await?;
+                        let val = read_sized(reader, MAX_VALUE_BYTES).await?;
+                        let ttl = Duration::from_secs(reader.read_u32().await?.into());
+                        batch.put_with_ttl(key, val, ttl);
+                    }
+                    b'd' => batch.delete(read_sized(reader, MAX_KEY_BYTES).await?),
+                    b'M' => {
+                        let key = read_sized(reader, MAX_KEY_BYTES).await?;
+                        let operand = read_sized(reader, MAX_VALUE_BYTES).await?;
+                        batch.merge(key, operand);
+                    }
+                    _ => return Err(invalid_data("Invalid batch command!")),
+                }
+            }
+            Command::BATCH { batch }
+        }
+        b'R' => {
+            let min_key = read_sized(reader, MAX_KEY_BYTES).await?;
+            let max_key = read_sized(reader, MAX_KEY_BYTES).await?;
+            let limit = reader.read_u32().await?;
+            let flags = reader.read_u8().await?;
+            let continuation = if flags & RANGE_FLAG_CONTINUATION != 0 {
+                Some(read_sized(reader, MAX_KEY_BYTES).await?)
+            } else {
+                None
+            };
+            // an empty max value means no upper bound
+            let value_filter = if flags & RANGE_FLAG_VALUE_FILTER != 0 {
+                let min_val = read_sized(reader, MAX_VALUE_BYTES).await?;
+                let max_val = read_sized(reader, MAX_VALUE_BYTES).await?;
+                Some((min_val, (!max_val.is_empty()).then_some(max_val)))
+            } else {
+                None
+            };
+
+            Command::RANGE {
+                min_key,
+                max_key: (!max_key.is_empty()).then_some(max_key),
+                options: RangeOptions {
+                    limit: (limit != 0).then_some(limit),
+                    reverse: flags & RANGE_FLAG_REVERSE != 0,
+                    continuation,
+                    value_filter,
+                    by_value: flags & RANGE_FLAG_BY_VALUE != 0,
+                },
+            }
+        }
+        b'a' => {
+            let min_key = read_sized(reader, MAX_KEY_BYTES).await?;
+            let max_key = read_sized(reader, MAX_KEY_BYTES).await?;
+            let aggregate = Aggregate::from_byte(reader.read_u8().await?)?;
+            Command::AGGREGATE {
+                min_key,
+                max_key: (!max_key.is_empty()).then_some(max_key),
+                aggregate,
+            }
+        }
+        b'V' => Command::LOOKUP {
+            val: read_sized(reader, MAX_VALUE_BYTES).await?,
+        },
+        b'v' => {
+            let min_val = read_sized(reader, MAX_VALUE_BYTES).await?;
+            let max_val = read_sized(reader, MAX_VALUE_BYTES).await?;
+            let limit = reader.read_u32().await?;
+            Command::VRANGE {
+                min_val,
+                max_val: (!max_val.is_empty()).then_some(max_val),
+                limit: (limit != 0).then_some(limit),
+            }
         }
+        _ => return Err(invalid_data("Invalid incoming command!")),
     })
 }
+
+type Pairs = Peekable<Box<dyn Iterator<Item = (Bytes, Bytes)> + Send>>;
+
+/// Pairs of `[min_key, max_key)` in the order and with the value filter of `options`. Holds
+/// read locks on the levels until dropped.
+async fn open_range(
+    db: &Database,
+    min_key: &Bytes,
+    max_key: Option<&Bytes>,
+    options: &RangeOptions,
+) -> Pairs {
+    let mut pairs: Box<dyn Iterator<Item = (Bytes, Bytes)> + Send> = if options.reverse {
+        Box::new(db.range_rev(min_key, max_key).await)
+    } else {
+        Box::new(db.range(min_key, max_key).await)
+    };
+    // filtered while merging, so only matching pairs are serialized
+    if let Some((min_val, max_val)) = options.value_filter.clone() {
+        pairs = Box::new(pairs.filter(move |(_, val)| {
+            *val >= min_val && max_val.as_ref().is_none_or(|max| val <= max)
+        }));
+    }
+    pairs.peekable()
+}
+
+/// Narrows `[min_key, max_key)` to the keys after `key` in iteration order
+fn skip_past(
+    key: &Bytes,
+    format: Format,
+    reverse: bool,
+    min_key: &mut Bytes,
+    max_key: &mut Option<Bytes>,
+) {
+    if reverse {
+        // the bound is exclusive already
+        if max_key.as_ref().is_none_or(|max| key < max) {
+            *max_key = Some(key.clone());
+        }
+        return;
+    }
+    // i32 keys step to the next integer so the bounds keep their length
+    let bound = match (format, i32::decode_slice(key)) {
+        (Format::Int, Some(int)) if int < i32::MAX => (int + 1).encode(),
+        _ => [&key[..], &[0]].concat().into(),
+    };
+    *min_key = min_key.clone().max(bound);
+}
+
+/// Pairs ordered by value and then key, largest first if `reverse`. With a `limit` only the
+/// top pairs are kept in a heap while scanning, instead of sorting the whole range.
+fn top_by_value(
+    pairs: impl Iterator<Item = (Bytes, Bytes)>,
+    limit: Option<u32>,
+    reverse: bool,
+) -> Vec<(Bytes, Bytes)> {
+    let Some(limit) = limit.map(|l| l as usize) else {
+        let mut all: Vec<(Bytes, Bytes)> = pairs.map(|(key, val)| (val, key)).collect();
+        all.sort_unstable();
+        if reverse {
+            all.reverse();
+        }
+        return all.into_iter().map(|(val, key)| (key, val)).collect();
+    };
+
+    // the worst pair kept sits on top of the heap, ready to be pushed out. Limits come from
+    // clients, so the heap grows past a chunk only if the range has that many pairs.
+    let capacity = limit.min(RANGE_CHUNK_PAIRS) + 1;
+    if reverse {
+        let mut top = BinaryHeap::with_capacity(capacity);
+        for (key, val) in pairs {
+            top.push(Reverse((val, key)));
+            if top.len() > limit {
+                top.pop();
+            }
+        }
+        top.into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((val, key))| (key, val))
+            .collect()
+    } else {
+        let mut top = BinaryHeap::with_capacity(capacity);
+        for (key, val) in pairs {
+            top.push((val, key));
+            if top.len() > limit {
+                top.pop();
+            }
+        }
+        top.into_sorted_vec()
+            .into_iter()
+            .map(|(val, key)| (key, val))
+            .collect()
+    }
+}
+
+async fn read_sized<T: AsyncBufReadExt + Unpin>(
+    reader: &mut T,
+    max_len: usize,
+) -> io::Result<Bytes> {
+    let len = reader.read_u16().await? as usize;
+    if len > max_len {
+        return Err(invalid_data("Key or value too long!"));
+    }
+
+    let mut buf = vec![0_u8; len];
+    reader.read_exact(&mut buf).await?;
+    Ok(buf.into())
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::config::{MEM_CAPACITY_BYTES, NUM_LEVELS};
+    use crate::database::table::TableOptions;
+
+    fn pairs(values: &[Bytes]) -> impl Iterator<Item = (Bytes, Bytes)> + '_ {
+        values
+            .iter()
+            .enumerate()
+            .map(|(idx, val)| ((idx as i32).encode(), val.clone()))
+    }
+
+    #[test]
+    fn sums_add_i32s_and_i64s() {
+        let values = [5i32.encode(), (-7i32).encode(), (1i64 << 40).encode()];
+        let mut out = Response::default();
+        Aggregate::Sum.evaluate(pairs(&values), Format::Int, &mut out);
+        assert_eq!(out.status, Status::Ok);
+        let mut expected = vec![ITEM_INT];
+        expected.extend_from_slice(&((1i128 << 40) - 2).to_be_bytes());
+        assert_eq!(out.body, expected);
+    }
+
+    #[test]
+    fn sums_fail_on_other_values() {
+        let values = [5i32.encode(), Bytes::from_static(b"a word")];
+        let mut out = Response::default();
+        Aggregate::Sum.evaluate(pairs(&values), Format::Bytes, &mut out);
+        assert_eq!(out.status, Status::Error);
+    }
+
+    #[test]
+    fn top_by_value_keeps_the_top_pairs() {
+        let values: Vec<Bytes> = [3, 1, 4, 1, 5, 9, 2, 6].iter().map(i32::encode).collect();
+        let sorted = |reverse: bool, limit: usize| {
+            let mut all: Vec<(Bytes, Bytes)> = pairs(&values).collect();
+            all.sort_by(|a, b| (&a.1, &a.0).cmp(&(&b.1, &b.0)));
+            if reverse {
+                all.reverse();
+            }
+            all.truncate(limit);
+            all
+        };
+        for reverse in [false, true] {
+            for limit in [0, 3, 8, u32::MAX] {
+                assert_eq!(
+                    top_by_value(pairs(&values), Some(limit), reverse),
+                    sorted(reverse, limit as usize)
+                );
+            }
+            assert_eq!(
+                top_by_value(pairs(&values), None, reverse),
+                sorted(reverse, 8)
+            );
+        }
+    }
+
+    /// Frames written while executing `command` on the default family, and its reply
+    async fn execute(families: &Families, command: Command, out: &mut Response) -> Vec<u8> {
+        let request = Request {
+            command,
+            format: Format::Int,
+            family: None,
+            transaction: None,
+        };
+        let mut writer = vec![];
+        request
+            .execute(families, &mut Session::default(), out, &mut writer)
+            .await
+            .unwrap();
+        out.write_frame(&mut writer);
+        writer
+    }
+
+    async fn hello(families: &Families, capabilities: u32, out: &mut Response) {
+        let command = Command::HELLO {
+            version: PROTOCOL_VERSION,
+            capabilities,
+        };
+        execute(families, command, out).await;
+    }
+
+    /// Bytes of the partial frame `out` sends for one value
+    async fn partial(out: &mut Response) -> Vec<u8> {
+        out.value(Format::Bytes, b"val");
+        let mut writer = vec![];
+        out.send_partial(&mut writer).await.unwrap();
+        out.body.clear();
+        writer
+    }
+
+    #[tokio::test]
+    async fn ranges_stream_only_once_granted() {
+        let dir = tempfile::tempdir().unwrap();
+        let families = Families::new(dir.path().to_owned(), [TableOptions::default(); NUM_LEVELS]);
+
+        let mut out = Response::default();
+        assert!(partial(&mut out).await.is_empty());
+        hello(&families, CAPABILITY_PIPELINING, &mut out).await;
+        assert!(partial(&mut out).await.is_empty());
+        hello(&families, CAPABILITIES, &mut out).await;
+        assert_eq!(partial(&mut out).await[0], Status::More as u8);
+    }
+
+    /// Loads enough pairs for the reply to a range over them to outgrow a chunk
+    async fn load_pairs(families: &Families, out: &mut Response) {
+        let pairs = (RESPONSE_CHUNK_BYTES / 8) as i32;
+        let data = (0..pairs)
+            .flat_map(|key| [key, key])
+            .flat_map(i32::to_be_bytes);
+        let load = Command::LOAD {
+            data: data.collect(),
+        };
+        execute(families, load, out).await;
+    }
+
+    #[tokio::test]
+    async fn aggregates_keep_ranges_streaming() {
+        let dir = tempfile::tempdir().unwrap();
+        let families = Families::new(dir.path().to_owned(), [TableOptions::default(); NUM_LEVELS]);
+        let mut out = Response::default();
+        hello(&families, CAPABILITIES, &mut out).await;
+
+        load_pairs(&families, &mut out).await;
+        let aggregate = Command::AGGREGATE {
+            min_key: 0.encode(),
+            max_key: None,
+            aggregate: Aggregate::Count,
+        };
+        execute(&families, aggregate, &mut out).await;
+
+        let range = Command::RANGE {
+            min_key: 0.encode(),
+            max_key: None,
+            options: RangeOptions::default(),
+        };
+        let frames = execute(&families, range, &mut out).await;
+        assert_eq!(frames[0], Status::More as u8);
+    }
+
+    /// Keys of the pairs in `frames`, which hold nothing but pairs of i32s
+    fn frame_keys(mut frames: &[u8]) -> Vec<i32> {
+        let mut keys = vec![];
+        while !frames.is_empty() {
+            let len = u32::from_be_bytes(frames[1..5].try_into().unwrap()) as usize;
+            for pair in frames[5..5 + len].chunks(17) {
+                assert_eq!((pair[0], &pair[1..5]), (ITEM_PAIR, &4u32.to_be_bytes()[..]));
+                keys.push(i32::from_be_bytes(pair[5..9].try_into().unwrap()));
+            }
+            frames = &frames[5 + len..];
+        }
+        keys
+    }
+
+    #[tokio::test]
+    async fn streamed_ranges_resume_after_the_last_pair_sent() {
+        let dir = tempfile::tempdir().unwrap();
+        let families = Families::new(dir.path().to_owned(), [TableOptions::default(); NUM_LEVELS]);
+        let mut out = Response::default();
+        hello(&families, CAPABILITIES, &mut out).await;
+        load_pairs(&families, &mut out).await;
+        let pairs = (RESPONSE_CHUNK_BYTES / 8) as i32;
+
+        for reverse in [false, true] {
+            let range = Command::RANGE {
+                min_key: 1.encode(),
+                max_key: Some((pairs - 1).encode()),
+                options: RangeOptions {
+                    reverse,
+                    value_filter: Some((0.encode(), None)),
+                    ..RangeOptions::default()
+                },
+            };
+            let frames = execute(&families, range, &mut out).await;
+            assert_eq!(frames[0], Status::More as u8);
+            let mut expected: Vec<i32> = (1..pairs - 1).collect();
+            if reverse {
+                expected.reverse();
+            }
+            assert_eq!(frame_keys(&frames), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn stalled_range_readers_dont_hold_up_flushes() {
+        let dir = tempfile::tempdir().unwrap();
+        let families = Arc::new(Families::new(
+            dir.path().to_owned(),
+            [TableOptions::default(); NUM_LEVELS],
+        ));
+        let mut out = Response::default();
+        hello(&families, CAPABILITIES, &mut out).await;
+        load_pairs(&families, &mut out).await;
+
+        // the client never reads, so the first partial frame fills the pipe and stays there
+        let (mut client, mut server) = io::duplex(1 << 10);
+        let range = tokio::spawn({
+            let families = families.clone();
+            async move {
+                let request = Request {
+                    command: Command::RANGE {
+                        min_key: 0.encode(),
+                        max_key: None,
+                        options: RangeOptions::default(),
+                    },
+                    format: Format::Int,
+                    family: None,
+                    transaction: None,
+                };
+                let mut session = Session::default();
+                request
+                    .execute(&families, &mut session, &mut out, &mut server)
+                    .await
+                    .unwrap();
+                let mut frame = vec![];
+                out.write_frame(&mut frame);
+                server.write_all(&frame).await.unwrap();
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!range.is_finished());
+
+        // fills the memtable until it's flushed, then reads past it
+        let db = families.get(None).await.unwrap();
+        let val = Bytes::from(vec![0; MAX_VALUE_BYTES]);
+        let writes = async {
+            for idx in 0..MEM_CAPACITY_BYTES / MAX_VALUE_BYTES + 1 {
+                db.insert(format!("big{idx}").into(), val.clone()).await;
+            }
+            db.get(&0.encode()).await
+        };
+        let got = tokio::time::timeout(Duration::from_secs(5), writes)
+            .await
+            .expect("flush stalled behind a range waiting on its client");
+        assert_eq!(got, Some(0.encode()));
+        assert!(db.level_sizes().await.mem_bytes < MEM_CAPACITY_BYTES);
+
+        let mut frames = vec![];
+        client.read_to_end(&mut frames).await.unwrap();
+        range.await.unwrap();
+        assert_eq!(frames[0], Status::More as u8);
+    }
+}
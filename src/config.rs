@@ -1,5 +1,7 @@
 use std::{env::args, path::PathBuf};
 
+use crate::database::{filter::FilterKind, table::TableOptions};
+
 pub const BLOCK_SIZE_BYTES: usize = 4096;
 
 // 466033 * 4(5^5) > 2^32 ==> the final level can fit all possible key-value pairs
@@ -10,41 +12,147 @@ pub const NUM_LEVELS: usize = 6;
 pub const MAX_FILE_SIZE_BYTES: usize = 1 << 22; // 4 MB
 pub const MAX_FILE_SIZE_BLOCKS: usize = MAX_FILE_SIZE_BYTES >> 12;
 
-// Worst case (all puts, each taking 9 bytes) upper bound on number of entries in the memory level that can serialize into a single file
-pub const MEM_CAPACITY: u32 = (MAX_FILE_SIZE_BLOCKS * BLOCK_SIZE_BYTES / 9) as u32;
-// pub const MEM_CAPACITY: u32 = 10;
+// Encoded size of the records in the memory level before it gets flushed, about one file
+pub const MEM_CAPACITY_BYTES: usize = MAX_FILE_SIZE_BYTES;
+// pub const MEM_CAPACITY_BYTES: usize = 1024;
+
+// Every record has to fit in a single block: tag, two u16 lengths, the u64 expiry of expiring
+// puts, key and value
+pub const MAX_KEY_BYTES: usize = 1024;
+pub const MAX_VALUE_BYTES: usize = BLOCK_SIZE_BYTES - MAX_KEY_BYTES - 13;
 
 pub const BLOOM_CAPACITY: usize = 1 << 16;
 
-const DEFAULT_DATABASE_DIRECTORY: &'static str = "/Users/noahr/dev/rust/lsm-tree/database";
+// Max distance in records between a learned index prediction and the actual position
+pub const LEARNED_INDEX_EPSILON: u32 = 16;
+
+// Range filters bucket 2^RANGE_FILTER_BUCKET_BITS consecutive keys together and give up on
+// ranges spanning more than RANGE_FILTER_MAX_PROBES buckets
+pub const RANGE_FILTER_BUCKET_BITS: u32 = 4;
+pub const RANGE_FILTER_MAX_PROBES: u32 = 64;
+
+const DEFAULT_DATABASE_DIRECTORY: &str = "/Users/noahr/dev/rust/lsm-tree/database";
 
 #[derive(Debug)]
 pub struct Config {
     pub data_dir: PathBuf,
     pub port: u16,
+    /// Port of the optional RESP listener, see `resp`
+    pub resp_port: Option<u16>,
+    /// Port of the optional HTTP admin listener, see `admin`
+    pub http_port: Option<u16>,
+    pub table_options: [TableOptions; NUM_LEVELS],
+    pub io_threads: usize,
 }
 
 impl Config {
     pub fn parse_from_args() -> Self {
         let mut data_dir = DEFAULT_DATABASE_DIRECTORY.parse().unwrap();
         let mut port = 1234;
+        let mut resp_port = None;
+        let mut http_port = None;
+        let mut table_options = [TableOptions::default(); NUM_LEVELS];
+        let mut io_threads = 64;
 
         let mut args = args();
 
         while let Some(arg) = args.next() {
-            if arg.starts_with("--") {
-                match &arg[2..] {
+            if let Some(flag) = arg.strip_prefix("--") {
+                match flag {
                     "data-dir" => {
                         data_dir = args.next().map(|d| d.parse().unwrap()).unwrap();
                     }
                     "port" => {
                         port = args.next().map(|d| d.parse().unwrap()).unwrap();
                     }
-                    _ => unimplemented!(),
+                    "resp-port" => {
+                        resp_port = Some(args.next().map(|d| d.parse().unwrap()).unwrap());
+                    }
+                    "http-port" => {
+                        http_port = Some(args.next().map(|d| d.parse().unwrap()).unwrap());
+                    }
+                    "io-threads" => {
+                        io_threads = args.next().map(|d| d.parse().unwrap()).unwrap();
+                    }
+                    _ => apply_table_flag(&mut table_options, flag, &mut args).unwrap(),
                 }
             }
         }
 
-        Config { data_dir, port }
+        Config {
+            data_dir,
+            port,
+            resp_port,
+            http_port,
+            table_options,
+            io_threads,
+        }
+    }
+}
+
+/// Applies one of the table tuning flags (`filters`, `learned-index`, `range-filter`, `mmap`,
+/// `value-index`, `sync-wal` or `merge-operator`, without the leading `--`), taking its argument from
+/// `args`. `None` for anything else.
+pub fn apply_table_flag(
+    table_options: &mut [TableOptions; NUM_LEVELS],
+    flag: &str,
+    args: &mut impl Iterator<Item = String>,
+) -> Option<()> {
+    match flag {
+        "filters" => {
+            // either one filter for every level or a comma separated list per level
+            let kinds = args
+                .next()?
+                .split(',')
+                .map(|f| f.parse().ok())
+                .collect::<Option<Vec<FilterKind>>>()?;
+            for (idx, options) in table_options.iter_mut().enumerate() {
+                options.filter = *kinds.get(idx).unwrap_or(kinds.last()?);
+            }
+        }
+        "learned-index" => {
+            for options in table_options.iter_mut() {
+                options.learned_index = true;
+            }
+        }
+        "range-filter" => {
+            for options in table_options.iter_mut() {
+                options.range_filter = true;
+            }
+        }
+        "mmap" => {
+            for options in table_options.iter_mut() {
+                options.mmap = true;
+            }
+        }
+        "value-index" => {
+            for options in table_options.iter_mut() {
+                options.value_index = true;
+            }
+        }
+        "sync-wal" => {
+            for options in table_options.iter_mut() {
+                options.sync_wal = true;
+            }
+        }
+        "merge-operator" => {
+            let operator = args.next()?.parse().ok()?;
+            for options in table_options.iter_mut() {
+                options.merge_operator = operator;
+            }
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+/// Table options of a column family from its flags, written like on the command line
+/// (`--filters xor --mmap`). Unset options keep their defaults.
+pub fn parse_table_flags(flags: &str) -> Option<[TableOptions; NUM_LEVELS]> {
+    let mut table_options = [TableOptions::default(); NUM_LEVELS];
+    let mut args = flags.split_whitespace().map(String::from);
+    while let Some(arg) = args.next() {
+        apply_table_flag(&mut table_options, arg.strip_prefix("--")?, &mut args)?;
     }
+    Some(table_options)
 }
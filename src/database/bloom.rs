@@ -1,5 +1,7 @@
 use fixedbitset::FixedBitSet;
-use std::hash::{BuildHasher, Hash, Hasher, RandomState};
+use std::hash::{BuildHasher, RandomState};
+
+use super::filter::{FilterKind, PointFilter};
 
 #[derive(Debug, Default)]
 pub struct Bloom {
@@ -9,23 +11,31 @@ pub struct Bloom {
 
 impl Bloom {
     pub fn new(capacity: usize) -> Self {
-        return Self {
+        Self {
             inner: FixedBitSet::with_capacity(capacity),
             random_state: RandomState::new(),
-        };
+        }
     }
 
-    pub fn put(&mut self, key: i32) {
+    pub fn put(&mut self, key: &[u8]) {
         self.inner.put(self.get_index(key));
     }
 
-    pub fn maybe_contains(&self, key: i32) -> bool {
+    fn get_index(&self, key: &[u8]) -> usize {
+        (self.random_state.hash_one(key) as usize) % self.inner.len()
+    }
+}
+
+impl PointFilter for Bloom {
+    fn kind(&self) -> FilterKind {
+        FilterKind::Bloom
+    }
+
+    fn maybe_contains(&self, key: &[u8]) -> bool {
         self.inner[self.get_index(key)]
     }
 
-    fn get_index(&self, key: i32) -> usize {
-        let mut hasher = self.random_state.build_hasher();
-        key.hash(&mut hasher);
-        (hasher.finish() as usize) % self.inner.len()
+    fn size_bytes(&self) -> usize {
+        self.inner.len() / 8
     }
 }
@@ -0,0 +1,270 @@
+use std::fmt::Write;
+
+use bytes::Bytes;
+
+/// Order preserving encoding of the keys and values of a typed [`Database`](super::Database).
+/// Tables, fences and merges only ever compare encoded bytes, so for keys `a < b` has to hold
+/// exactly when `a.encode() < b.encode()`.
+pub trait Codec: Sized + Send + 'static {
+    /// Appends the encoding of `self`. Encodings that can be followed by more bytes (anything
+    /// but the last element of a tuple) must be self delimiting.
+    fn encode_to(&self, out: &mut Vec<u8>);
+
+    /// Reads one value off the front of `bytes`
+    fn decode_from(bytes: &mut &[u8]) -> Option<Self>;
+
+    fn encode(&self) -> Bytes {
+        let mut out = vec![];
+        self.encode_to(&mut out);
+        out.into()
+    }
+
+    /// `None` unless all of `bytes` is exactly one value
+    fn decode_slice(mut bytes: &[u8]) -> Option<Self> {
+        let res = Self::decode_from(&mut bytes)?;
+        bytes.is_empty().then_some(res)
+    }
+
+    fn decode(bytes: Bytes) -> Option<Self> {
+        Self::decode_slice(&bytes)
+    }
+}
+
+/// Raw bytes, stored as they are. Not self delimiting, so only usable on their own or as the
+/// last element of a tuple.
+impl Codec for Bytes {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self);
+    }
+
+    fn decode_from(bytes: &mut &[u8]) -> Option<Self> {
+        Some(Bytes::copy_from_slice(std::mem::take(bytes)))
+    }
+
+    fn encode(&self) -> Bytes {
+        self.clone()
+    }
+
+    fn decode(bytes: Bytes) -> Option<Self> {
+        Some(bytes)
+    }
+}
+
+/// Big endian with the sign bit flipped, so byte order matches integer order. The i32 keys
+/// and values of the original protocol are stored this way.
+impl Codec for i32 {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&((*self as u32) ^ (1 << 31)).to_be_bytes());
+    }
+
+    fn decode_from(bytes: &mut &[u8]) -> Option<Self> {
+        Some((u32::decode_from(bytes)? ^ (1 << 31)) as i32)
+    }
+}
+
+impl Codec for i64 {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&((*self as u64) ^ (1 << 63)).to_be_bytes());
+    }
+
+    fn decode_from(bytes: &mut &[u8]) -> Option<Self> {
+        Some((u64::decode_from(bytes)? ^ (1 << 63)) as i64)
+    }
+}
+
+impl Codec for u32 {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_be_bytes());
+    }
+
+    fn decode_from(bytes: &mut &[u8]) -> Option<Self> {
+        let (head, rest) = bytes.split_first_chunk()?;
+        *bytes = rest;
+        Some(Self::from_be_bytes(*head))
+    }
+}
+
+impl Codec for u64 {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_be_bytes());
+    }
+
+    fn decode_from(bytes: &mut &[u8]) -> Option<Self> {
+        let (head, rest) = bytes.split_first_chunk()?;
+        *bytes = rest;
+        Some(Self::from_be_bytes(*head))
+    }
+}
+
+/// Escaped and terminated like in FoundationDB's tuple layer: 0x00 becomes 0x00 0xFF and a
+/// single 0x00 ends the string, so shorter strings still sort before their extensions
+impl Codec for Vec<u8> {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        for &b in self {
+            out.push(b);
+            if b == 0 {
+                out.push(0xFF);
+            }
+        }
+        out.push(0);
+    }
+
+    fn decode_from(bytes: &mut &[u8]) -> Option<Self> {
+        let mut res = vec![];
+        loop {
+            let (&b, rest) = bytes.split_first()?;
+            *bytes = rest;
+            if b != 0 {
+                res.push(b);
+                continue;
+            }
+
+            match bytes.split_first() {
+                Some((0xFF, rest)) => {
+                    *bytes = rest;
+                    res.push(0);
+                }
+                _ => return Some(res),
+            }
+        }
+    }
+}
+
+/// Same encoding as `Vec<u8>`
+impl Codec for String {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.as_bytes().to_vec().encode_to(out);
+    }
+
+    fn decode_from(bytes: &mut &[u8]) -> Option<Self> {
+        String::from_utf8(Vec::decode_from(bytes)?).ok()
+    }
+}
+
+/// Elements one after the other, ordered by the first element, then the second
+impl<A: Codec, B: Codec> Codec for (A, B) {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.0.encode_to(out);
+        self.1.encode_to(out);
+    }
+
+    fn decode_from(bytes: &mut &[u8]) -> Option<Self> {
+        Some((A::decode_from(bytes)?, B::decode_from(bytes)?))
+    }
+}
+
+impl<A: Codec, B: Codec, C: Codec> Codec for (A, B, C) {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.0.encode_to(out);
+        self.1.encode_to(out);
+        self.2.encode_to(out);
+    }
+
+    fn decode_from(bytes: &mut &[u8]) -> Option<Self> {
+        Some((
+            A::decode_from(bytes)?,
+            B::decode_from(bytes)?,
+            C::decode_from(bytes)?,
+        ))
+    }
+}
+
+/// Appends `bytes` as an i32 if it is 4 bytes long, otherwise escaped and quoted
+pub fn write_display(out: &mut String, bytes: &[u8]) {
+    match i32::decode_slice(bytes) {
+        Some(val) => write!(out, "{val}").unwrap(),
+        None => {
+            out.push('"');
+            write_escaped(out, bytes);
+            out.push('"');
+        }
+    }
+}
+
+/// Appends `bytes` with non printable characters escaped like `escape_ascii`, and spaces and
+/// colons as well so they can't be confused with the separators of printed pairs
+pub fn write_escaped(out: &mut String, bytes: &[u8]) {
+    for &b in bytes {
+        match b {
+            b' ' | b':' => write!(out, "\\x{b:02x}").unwrap(),
+            _ => write!(out, "{}", b.escape_ascii()).unwrap(),
+        }
+    }
+}
+
+/// Decodes bytes read back from the database, which were encoded with the same type
+pub(super) fn decode_stored<T: Codec>(bytes: Bytes) -> T {
+    T::decode(bytes).expect("stored bytes don't decode, the database was written with other types")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+
+    use super::*;
+
+    /// `values` must be sorted, checks their encodings sort the same way and decode back
+    fn assert_order_preserved<T: Codec + Debug + PartialEq>(values: &[T]) {
+        for pair in values.windows(2) {
+            assert!(
+                pair[0].encode() < pair[1].encode(),
+                "{:?} doesn't encode below {:?}",
+                pair[0],
+                pair[1]
+            );
+        }
+        for val in values {
+            assert_eq!(T::decode(val.encode()).as_ref(), Some(val));
+        }
+    }
+
+    #[test]
+    fn integers_keep_their_order() {
+        assert_order_preserved(&[i32::MIN, -70_000, -1, 0, 1, 255, 256, i32::MAX]);
+        assert_order_preserved(&[i64::MIN, -1 << 40, -1, 0, 1, 1 << 40, i64::MAX]);
+        assert_order_preserved(&[0_u32, 1, 255, 256, u32::MAX]);
+        assert_order_preserved(&[0_u64, 1, 1 << 40, u64::MAX]);
+    }
+
+    #[test]
+    fn strings_sort_before_their_extensions() {
+        assert_order_preserved(&[
+            vec![],
+            vec![0],
+            vec![0, 0],
+            vec![0, 1],
+            vec![0xFF],
+            vec![0xFF, 0],
+        ]);
+        assert_order_preserved(&[
+            "".to_owned(),
+            "a".to_owned(),
+            "a\0".to_owned(),
+            "ab".to_owned(),
+        ]);
+    }
+
+    #[test]
+    fn tuples_sort_by_their_first_element() {
+        let strings = ["", "a", "a\0b", "ab"];
+        let mut tuples = vec![];
+        for a in strings {
+            for b in [-1, 0, 7] {
+                tuples.push((a.to_owned(), b));
+            }
+        }
+        assert_order_preserved(&tuples);
+        assert_order_preserved(&[
+            (1_u32, "b".to_owned(), -1_i64),
+            (1, "b".to_owned(), 0),
+            (2, "".to_owned(), 0),
+        ]);
+    }
+
+    #[test]
+    fn decoding_takes_exactly_one_value() {
+        assert_eq!(i32::decode_slice(&[0, 0, 0, 0, 0]), None);
+        assert_eq!(<(String, u32)>::decode_slice(b"ab\0\0\0\0"), None);
+        assert_eq!(Vec::<u8>::decode_slice(&[1, 2]), None);
+    }
+}
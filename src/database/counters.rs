@@ -0,0 +1,44 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::config::NUM_LEVELS;
+
+/// Work done by every database of the process, exported by the admin listener. Counted
+/// globally since flushes, compactions and table reads run on the blocking pool, in free
+/// functions that don't know their database.
+pub static COUNTERS: Counters = Counters::new();
+
+pub struct Counters {
+    pub flushes: AtomicU64,
+    /// Compactions into every disk level, merges from the level above and rewrites in place
+    pub compactions: [AtomicU64; NUM_LEVELS],
+    /// File bytes of the tables written by flushes and compactions
+    pub table_bytes_written: AtomicU64,
+    /// Point filter lookups, and the ones that ruled the key out without reading the table
+    pub filter_probes: AtomicU64,
+    pub filter_negatives: AtomicU64,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Self {
+            flushes: AtomicU64::new(0),
+            compactions: [const { AtomicU64::new(0) }; NUM_LEVELS],
+            table_bytes_written: AtomicU64::new(0),
+            filter_probes: AtomicU64::new(0),
+            filter_negatives: AtomicU64::new(0),
+        }
+    }
+
+    pub(super) fn add(&self, counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// `level` counts from 1 like the level directories
+    pub(super) fn add_compaction(&self, level: u32) {
+        self.add(&self.compactions[level as usize - 1], 1);
+    }
+
+    pub fn get(&self, counter: &AtomicU64) -> u64 {
+        counter.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,119 @@
+use bytes::Bytes;
+
+use super::filter::{hash_key, FilterKind, PointFilter};
+
+const BUCKET_SIZE: usize = 4;
+const MAX_KICKS: usize = 500;
+const LOAD_FACTOR: f64 = 0.95;
+
+/// Cuckoo filter with 16 bit fingerprints and 4 slots per bucket (Fan et al., 2014).
+/// Each key lives in one of two buckets, the second derived from the first and the
+/// fingerprint alone so entries can be relocated without knowing the original key.
+#[derive(Debug)]
+pub struct CuckooFilter {
+    buckets: Vec<[u16; BUCKET_SIZE]>,
+    mask: usize,
+}
+
+impl CuckooFilter {
+    pub fn new(keys: &[Bytes]) -> Self {
+        let min_buckets = (keys.len() as f64 / (BUCKET_SIZE as f64 * LOAD_FACTOR)).ceil() as usize;
+        let mut num_buckets = min_buckets.max(1).next_power_of_two();
+
+        loop {
+            let mut filter = Self {
+                buckets: vec![[0; BUCKET_SIZE]; num_buckets],
+                mask: num_buckets - 1,
+            };
+
+            if keys.iter().all(|key| filter.insert(key)) {
+                return filter;
+            }
+            // too many evictions, give every key more room
+            num_buckets <<= 1;
+        }
+    }
+
+    fn insert(&mut self, key: &[u8]) -> bool {
+        let (mut fp, i1, i2) = self.locations(key);
+
+        for idx in [i1, i2] {
+            if let Some(slot) = self.buckets[idx].iter_mut().find(|s| **s == 0) {
+                *slot = fp;
+                return true;
+            }
+        }
+
+        let mut idx = if fp & 1 == 0 { i1 } else { i2 };
+        for kick in 0..MAX_KICKS {
+            let slot = kick % BUCKET_SIZE;
+            std::mem::swap(&mut fp, &mut self.buckets[idx][slot]);
+            idx = self.alt_index(idx, fp);
+
+            if let Some(slot) = self.buckets[idx].iter_mut().find(|s| **s == 0) {
+                *slot = fp;
+                return true;
+            }
+        }
+
+        false
+    }
+
+    fn locations(&self, key: &[u8]) -> (u16, usize, usize) {
+        let hash = hash_key(key, 0);
+        // 0 marks an empty slot
+        let fp = ((hash >> 48) as u16).max(1);
+        let i1 = hash as usize & self.mask;
+        (fp, i1, self.alt_index(i1, fp))
+    }
+
+    fn alt_index(&self, idx: usize, fp: u16) -> usize {
+        (idx ^ hash_key(&fp.to_le_bytes(), 1) as usize) & self.mask
+    }
+}
+
+impl PointFilter for CuckooFilter {
+    fn kind(&self) -> FilterKind {
+        FilterKind::Cuckoo
+    }
+
+    fn maybe_contains(&self, key: &[u8]) -> bool {
+        let (fp, i1, i2) = self.locations(key);
+        self.buckets[i1].contains(&fp) || self.buckets[i2].contains(&fp)
+    }
+
+    fn size_bytes(&self) -> usize {
+        self.buckets.len() * BUCKET_SIZE * 2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(count: u32) -> Vec<Bytes> {
+        (0..count)
+            .map(|i| Bytes::copy_from_slice(&(i * 7).to_be_bytes()))
+            .collect()
+    }
+
+    #[test]
+    fn no_false_negatives() {
+        for count in [0, 1, 2, 100, 10_000] {
+            let keys = keys(count);
+            let filter = CuckooFilter::new(&keys);
+            assert!(keys.iter().all(|key| filter.maybe_contains(key)));
+        }
+    }
+
+    #[test]
+    fn rules_out_most_missing_keys() {
+        let filter = CuckooFilter::new(&keys(10_000));
+        let false_positives = (0..10_000_u32)
+            .map(|i| (i * 7 + 3).to_be_bytes())
+            .filter(|key| filter.maybe_contains(key))
+            .count();
+        // 8 probed slots of 16 bit fingerprints, ~0.01% expected
+        assert!(false_positives < 20, "{false_positives} false positives");
+    }
+}
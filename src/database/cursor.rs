@@ -0,0 +1,562 @@
+use std::marker::PhantomData;
+
+use bytes::Bytes;
+use tokio::sync::OwnedRwLockReadGuard;
+
+use super::{
+    codec::{decode_stored, Codec},
+    disk_level::DiskLevel,
+    merge_operator::MergeOperator,
+    table::{unix_millis, Command},
+};
+
+/// Positioned iteration over a sorted run of commands, shadowed keys included
+trait CommandCursor: Send {
+    fn current(&self) -> Option<&Command>;
+
+    /// Positions at the first command with a key >= `key`
+    fn seek(&mut self, key: &[u8]);
+
+    fn seek_to_first(&mut self);
+
+    fn seek_to_last(&mut self);
+
+    fn next(&mut self);
+
+    fn prev(&mut self);
+
+    fn key(&self) -> Option<&Bytes> {
+        self.current().map(|c| c.key())
+    }
+}
+
+/// Cursor over a snapshot of the memtable
+struct MemCursor {
+    commands: Vec<Command>,
+    pos: usize, // == commands.len() when not positioned
+}
+
+impl CommandCursor for MemCursor {
+    fn current(&self) -> Option<&Command> {
+        self.commands.get(self.pos)
+    }
+
+    fn seek(&mut self, key: &[u8]) {
+        self.pos = self.commands.partition_point(|c| &c.key()[..] < key);
+    }
+
+    fn seek_to_first(&mut self) {
+        self.pos = 0;
+    }
+
+    fn seek_to_last(&mut self) {
+        self.pos = self.commands.len().saturating_sub(1);
+    }
+
+    fn next(&mut self) {
+        self.pos = (self.pos + 1).min(self.commands.len());
+    }
+
+    fn prev(&mut self) {
+        if self.pos < self.commands.len() {
+            self.pos = self.pos.checked_sub(1).unwrap_or(self.commands.len());
+        }
+    }
+}
+
+/// Cursor over one disk level, decoding a single block at a time
+struct LevelCursor {
+    level: OwnedRwLockReadGuard<DiskLevel>,
+    table_index: usize,
+    block_index: usize,
+    block: Vec<Command>,
+    pos: usize, // == block.len() when not positioned
+}
+
+impl LevelCursor {
+    fn load_block(&mut self, table_index: usize, block_index: usize) {
+        self.table_index = table_index;
+        self.block_index = block_index;
+        self.block.clear();
+        if let Some(block) = self.level.tables[table_index]
+            .view()
+            .get_block_at(block_index)
+        {
+            self.block.extend(block.iter());
+        }
+    }
+
+    fn invalidate(&mut self) {
+        self.block.clear();
+        self.pos = 0;
+    }
+}
+
+impl CommandCursor for LevelCursor {
+    fn current(&self) -> Option<&Command> {
+        self.block.get(self.pos)
+    }
+
+    fn seek(&mut self, key: &[u8]) {
+        let Some(locate) = self.level.locate_nearest(key) else {
+            return self.invalidate();
+        };
+
+        self.load_block(locate.table_index, locate.block_index);
+        self.pos = self.block.partition_point(|c| &c.key()[..] < key);
+        if self.pos == self.block.len() {
+            // key falls between the end of this block and the start of the next
+            self.pos = self.block.len() - 1;
+            self.next();
+        }
+    }
+
+    fn seek_to_first(&mut self) {
+        if self.level.tables.is_empty() {
+            return self.invalidate();
+        }
+        self.load_block(0, 0);
+        self.pos = 0;
+    }
+
+    fn seek_to_last(&mut self) {
+        let Some(table) = self.level.tables.last() else {
+            return self.invalidate();
+        };
+        let last_block = table.index.len() - 1;
+        self.load_block(self.level.tables.len() - 1, last_block);
+        self.pos = self.block.len() - 1;
+    }
+
+    fn next(&mut self) {
+        if self.pos + 1 < self.block.len() {
+            self.pos += 1;
+            return;
+        }
+
+        if self.block.is_empty() {
+            return;
+        }
+
+        if self.block_index + 1 < self.level.tables[self.table_index].index.len() {
+            self.load_block(self.table_index, self.block_index + 1);
+        } else if self.table_index + 1 < self.level.tables.len() {
+            self.load_block(self.table_index + 1, 0);
+        } else {
+            return self.invalidate();
+        }
+        self.pos = 0;
+    }
+
+    fn prev(&mut self) {
+        if self.block.is_empty() {
+            return;
+        }
+
+        if self.pos > 0 {
+            self.pos -= 1;
+            return;
+        }
+
+        if self.block_index > 0 {
+            self.load_block(self.table_index, self.block_index - 1);
+        } else if self.table_index > 0 {
+            let last_block = self.level.tables[self.table_index - 1].index.len() - 1;
+            self.load_block(self.table_index - 1, last_block);
+        } else {
+            return self.invalidate();
+        }
+        self.pos = self.block.len() - 1;
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Forward,
+    Reverse,
+}
+
+/// Bidirectional cursor over the live pairs of the memtable and every disk level.
+///
+/// The memtable is copied when the cursor is created while the disk levels stay read
+/// locked until it is dropped, so the cursor sees a consistent snapshot but long lived
+/// cursors hold off compactions (and with them writers once the memtable fills up).
+/// A new cursor is not positioned, call one of the seek methods first.
+///
+/// Moving the cursor reads blocks synchronously, from async code move it onto the
+/// blocking pool with [`run_blocking`](super::run_blocking).
+pub struct Cursor<K = Bytes, V = Bytes> {
+    children: Vec<Box<dyn CommandCursor>>, // newest first
+    current: Option<usize>,
+    direction: Direction,
+    merge_operator: MergeOperator,
+    /// Expiries are checked against the time the cursor was created
+    now: u64,
+    _codec: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K: Codec, V: Codec> Cursor<K, V> {
+    pub(super) fn new(
+        mem_commands: Vec<Command>,
+        levels: Vec<OwnedRwLockReadGuard<DiskLevel>>,
+        merge_operator: MergeOperator,
+    ) -> Self {
+        let mut children: Vec<Box<dyn CommandCursor>> = Vec::with_capacity(levels.len() + 1);
+        let pos = mem_commands.len();
+        children.push(Box::new(MemCursor {
+            commands: mem_commands,
+            pos,
+        }));
+        for level in levels {
+            children.push(Box::new(LevelCursor {
+                level,
+                table_index: 0,
+                block_index: 0,
+                block: vec![],
+                pos: 0,
+            }));
+        }
+
+        Self {
+            children,
+            current: None,
+            direction: Direction::Forward,
+            merge_operator,
+            now: unix_millis(),
+            _codec: PhantomData,
+        }
+    }
+
+    pub fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// The pair under the cursor
+    pub fn current(&self) -> Option<(K, V)> {
+        match self.resolved_command()? {
+            Command::Put(key, val) | Command::Merge(key, val) | Command::Expiring(key, val, _) => {
+                Some((decode_stored(key), decode_stored(val)))
+            }
+            Command::Delete(..) => unreachable!("cursor stopped on a tombstone"),
+        }
+    }
+
+    pub fn key(&self) -> Option<K> {
+        self.current_command()
+            .map(|c| decode_stored(c.key().clone()))
+    }
+
+    pub fn value(&self) -> Option<V> {
+        self.resolved_command()
+            .and_then(|c| c.value().cloned())
+            .map(decode_stored)
+    }
+
+    /// Positions at the first pair with a key >= `key`
+    pub fn seek(&mut self, key: &K) {
+        let key = key.encode();
+        for child in self.children.iter_mut() {
+            child.seek(&key);
+        }
+        self.find_smallest();
+        self.skip_deleted_forward();
+    }
+
+    /// Positions at the last pair with a key <= `key`
+    pub fn seek_for_prev(&mut self, key: &K) {
+        let key = key.encode();
+        for child in self.children.iter_mut() {
+            seek_child_for_prev(child.as_mut(), &key);
+        }
+        self.find_largest();
+        self.skip_deleted_backward();
+    }
+
+    pub fn seek_to_first(&mut self) {
+        for child in self.children.iter_mut() {
+            child.seek_to_first();
+        }
+        self.find_smallest();
+        self.skip_deleted_forward();
+    }
+
+    pub fn seek_to_last(&mut self) {
+        for child in self.children.iter_mut() {
+            child.seek_to_last();
+        }
+        self.find_largest();
+        self.skip_deleted_backward();
+    }
+
+    /// Moves to the next larger key, invalidating the cursor past the last one
+    pub fn next(&mut self) {
+        self.step_forward();
+        self.skip_deleted_forward();
+    }
+
+    /// Moves to the next smaller key, invalidating the cursor before the first one
+    pub fn prev(&mut self) {
+        self.step_backward();
+        self.skip_deleted_backward();
+    }
+
+    fn current_command(&self) -> Option<&Command> {
+        self.children[self.current?].current()
+    }
+
+    /// The current command with the older commands on its key folded in while it's a merge.
+    /// Every child holding the key sits on it, whichever way the cursor moves.
+    fn resolved_command(&self) -> Option<Command> {
+        let current = self.current?;
+        let mut command = self.children[current].current()?.clone();
+        for child in self.children[current + 1..].iter() {
+            if !matches!(command, Command::Merge(..)) {
+                break;
+            }
+            if let Some(older) = child.current().filter(|c| c.key() == command.key()) {
+                command = self.merge_operator.fold(older.clone(), command);
+            }
+        }
+        Some(command)
+    }
+
+    fn step_forward(&mut self) {
+        let Some(key) = self.current_command().map(|c| c.key().clone()) else {
+            return;
+        };
+
+        if self.direction == Direction::Reverse {
+            // every other child sits before `key`, move them all past it
+            for child in self.children.iter_mut() {
+                child.seek(&key);
+            }
+        }
+        for child in self.children.iter_mut() {
+            if child.key() == Some(&key) {
+                child.next();
+            }
+        }
+        self.find_smallest();
+    }
+
+    fn step_backward(&mut self) {
+        let Some(key) = self.current_command().map(|c| c.key().clone()) else {
+            return;
+        };
+
+        if self.direction == Direction::Forward {
+            // every other child sits after `key`, move them all before it
+            for child in self.children.iter_mut() {
+                seek_child_for_prev(child.as_mut(), &key);
+            }
+        }
+        for child in self.children.iter_mut() {
+            if child.key() == Some(&key) {
+                child.prev();
+            }
+        }
+        self.find_largest();
+    }
+
+    fn current_deleted(&self) -> bool {
+        self.current_command()
+            .is_some_and(|c| c.is_deleted_at(self.now))
+    }
+
+    fn skip_deleted_forward(&mut self) {
+        while self.current_deleted() {
+            self.step_forward();
+        }
+    }
+
+    fn skip_deleted_backward(&mut self) {
+        while self.current_deleted() {
+            self.step_backward();
+        }
+    }
+
+    /// Newest child wins among equal keys since `min_by_key` keeps the first minimum
+    fn find_smallest(&mut self) {
+        self.direction = Direction::Forward;
+        self.current = self
+            .children
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, c)| Some((idx, c.key()?)))
+            .min_by_key(|&(_, key)| key)
+            .map(|(idx, _)| idx);
+    }
+
+    /// Newest child wins among equal keys, `max_by` would keep the last maximum
+    fn find_largest(&mut self) {
+        self.direction = Direction::Reverse;
+        self.current = self
+            .children
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, c)| Some((idx, c.key()?)))
+            .fold(
+                None,
+                |best: Option<(usize, &Bytes)>, (idx, key)| match best {
+                    Some((_, best_key)) if best_key >= key => best,
+                    _ => Some((idx, key)),
+                },
+            )
+            .map(|(idx, _)| idx);
+    }
+}
+
+/// Positions a child at the last command with a key <= `key`
+fn seek_child_for_prev(child: &mut dyn CommandCursor, key: &[u8]) {
+    child.seek(key);
+    match child.key() {
+        Some(found) if found[..] == *key => {}
+        Some(_) => child.prev(),
+        None => child.seek_to_last(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::Arc};
+
+    use tokio::sync::RwLock;
+
+    use super::*;
+    use crate::database::{build_tables, table::TableOptions};
+
+    const KEYS: u32 = 1000;
+
+    fn key(i: u32) -> Bytes {
+        Bytes::copy_from_slice(&i.to_be_bytes())
+    }
+
+    // long enough to spread each level over dozens of blocks
+    fn val(tag: &str, i: u32) -> Bytes {
+        Bytes::from(format!("{tag}{i:0>100}"))
+    }
+
+    /// Cursor over the memtable and two levels holding the same keys at different ages,
+    /// with the pairs it should see
+    fn cursor(dir: &std::path::Path) -> (Cursor, BTreeMap<Bytes, Bytes>) {
+        let level2: Vec<Command> = (0..KEYS)
+            .map(|i| Command::Put(key(i), val("l2-", i)))
+            .collect();
+        let level1: Vec<Command> = (0..KEYS)
+            .filter_map(|i| match i {
+                _ if i % 10 == 5 => Some(Command::Delete(key(i))),
+                _ if i % 3 == 0 => Some(Command::Put(key(i), val("l1-", i))),
+                _ => None,
+            })
+            .collect();
+        let mem: Vec<Command> = (0..KEYS + 5)
+            .filter_map(|i| match i {
+                _ if i % 5 == 0 || i >= KEYS => Some(Command::Put(key(i), val("mem-", i))),
+                _ if i % 7 == 0 => Some(Command::Delete(key(i))),
+                _ => None,
+            })
+            .collect();
+
+        let mut expected = BTreeMap::new();
+        for command in level2.iter().chain(&level1).chain(&mem) {
+            match command {
+                Command::Put(key, val) => expected.insert(key.clone(), val.clone()),
+                command => expected.remove(command.key()),
+            };
+        }
+
+        let levels = [level1, level2]
+            .into_iter()
+            .enumerate()
+            .map(|(idx, commands)| {
+                let mut level = DiskLevel::new(dir, idx as u32 + 1, TableOptions::default());
+                level.tables = build_tables(
+                    commands.into_iter(),
+                    &level.level_directory,
+                    TableOptions::default(),
+                );
+                assert!(level.tables[0].index.len() > 1);
+                Arc::new(RwLock::new(level)).try_read_owned().unwrap()
+            })
+            .collect();
+
+        let cursor = Cursor::new(mem, levels, MergeOperator::default());
+        (cursor, expected)
+    }
+
+    #[test]
+    fn iterates_live_pairs_in_both_directions() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut cursor, expected) = cursor(dir.path());
+
+        let mut forward = vec![];
+        cursor.seek_to_first();
+        while let Some(pair) = cursor.current() {
+            forward.push(pair);
+            cursor.next();
+        }
+        assert_eq!(forward, expected.clone().into_iter().collect::<Vec<_>>());
+
+        let mut backward = vec![];
+        cursor.seek_to_last();
+        while let Some(pair) = cursor.current() {
+            backward.push(pair);
+            cursor.prev();
+        }
+        assert_eq!(backward, expected.into_iter().rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn seeks_land_on_the_nearest_live_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut cursor, expected) = cursor(dir.path());
+
+        for i in [0, 1, 5, 7, 14, 15, 499, 500, 995, 999, 1004, 1005, 2000] {
+            cursor.seek(&key(i));
+            assert_eq!(
+                cursor.key(),
+                expected.range(key(i)..).next().map(|(k, _)| k.clone())
+            );
+
+            cursor.seek_for_prev(&key(i));
+            let prev = expected.range(..=key(i)).next_back();
+            assert_eq!(cursor.current(), prev.map(|(k, v)| (k.clone(), v.clone())));
+        }
+    }
+
+    #[test]
+    fn switches_direction_across_levels() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut cursor, expected) = cursor(dir.path());
+        let keys: Vec<Bytes> = expected.keys().cloned().collect();
+
+        let start = keys.iter().position(|k| *k >= key(400)).unwrap();
+        cursor.seek(&key(400));
+        let mut pos = start;
+        // wander back and forth, a few steps further each way
+        for (steps, forward) in [
+            (3, true),
+            (5, false),
+            (1, true),
+            (1, false),
+            (40, true),
+            (2, false),
+        ] {
+            for _ in 0..steps {
+                if forward {
+                    cursor.next();
+                    pos += 1;
+                } else {
+                    cursor.prev();
+                    pos -= 1;
+                }
+                assert_eq!(cursor.key(), Some(keys[pos].clone()));
+                assert_eq!(cursor.value(), Some(expected[&keys[pos]].clone()));
+            }
+        }
+
+        cursor.seek_for_prev(&keys[0]);
+        cursor.prev();
+        assert!(!cursor.valid());
+    }
+}
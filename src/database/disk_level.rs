@@ -1,3 +1,4 @@
+use bytes::Bytes;
 use std::{
     cmp::Ordering,
     fs,
@@ -7,7 +8,8 @@ use std::{
 use crate::config::{LEVEL1_FILE_CAPACITY, MAX_FILE_SIZE_BYTES, SIZE_MULTIPLIER};
 
 use super::{
-    table::{Command, Table},
+    counters::COUNTERS,
+    table::{unix_millis, Command, Table, TableOptions, TableView},
     GetResult,
 };
 
@@ -20,11 +22,12 @@ pub struct LocateResult {
 pub struct DiskLevel {
     pub level: u32,
     pub level_directory: PathBuf,
+    pub options: TableOptions,
     pub tables: Vec<Table>, // sorted array
 }
 
 impl DiskLevel {
-    pub fn new(data_directory: &Path, level: u32) -> Self {
+    pub fn new(data_directory: &Path, level: u32, options: TableOptions) -> Self {
         let mut level_directory = PathBuf::from(data_directory);
         level_directory.push(format!("level{level}"));
 
@@ -34,12 +37,13 @@ impl DiskLevel {
 
         for entry in fs::read_dir(&level_directory).unwrap() {
             let entry = entry.unwrap();
-            tables.push(Table::create_from_existing(&entry.path()));
+            tables.push(Table::create_from_existing(&entry.path(), options));
         }
 
         let mut res = Self {
             level,
             level_directory,
+            options,
             tables,
         };
         res.sort_tables();
@@ -47,7 +51,7 @@ impl DiskLevel {
     }
 
     pub fn sort_tables(&mut self) {
-        self.tables.sort_by_key(|t| t.min_key);
+        self.tables.sort_by(|a, b| a.min_key.cmp(&b.min_key));
     }
 
     pub fn is_over_file_capacity(&self) -> bool {
@@ -66,16 +70,11 @@ impl DiskLevel {
             / self.tables.len() as f32
     }
 
-    pub fn locate_nearest(&self, key: i32) -> Option<LocateResult> {
-        let table_index = match self.tables.binary_search_by(|t| {
-            if key >= t.min_key && key <= t.max_key {
-                Ordering::Equal
-            } else if key < t.min_key {
-                Ordering::Greater
-            } else {
-                Ordering::Less
-            }
-        }) {
+    pub fn locate_nearest(&self, key: &[u8]) -> Option<LocateResult> {
+        let table_index = match self
+            .tables
+            .binary_search_by(|t| fence_cmp(&t.min_key, &t.max_key, key))
+        {
             Ok(idx) => idx,
             Err(idx) => {
                 return if idx == self.tables.len() {
@@ -89,21 +88,13 @@ impl DiskLevel {
             }
         };
 
-        let block_index =
-            match self.tables[table_index]
-                .index
-                .binary_search_by(|&(min_key, max_key)| {
-                    if key >= min_key && key <= max_key {
-                        Ordering::Equal
-                    } else if key < min_key {
-                        Ordering::Greater
-                    } else {
-                        Ordering::Less
-                    }
-                }) {
-                Ok(idx) => idx,
-                Err(idx) => idx,
-            };
+        let block_index = match self.tables[table_index]
+            .index
+            .binary_search_by(|(min_key, max_key)| fence_cmp(min_key, max_key, key))
+        {
+            Ok(idx) => idx,
+            Err(idx) => idx,
+        };
 
         Some(LocateResult {
             table_index,
@@ -111,54 +102,114 @@ impl DiskLevel {
         })
     }
 
-    pub fn get(&self, key: i32) -> GetResult {
+    fn find_table(&self, key: &[u8]) -> Option<usize> {
+        self.tables
+            .binary_search_by(|t| fence_cmp(&t.min_key, &t.max_key, key))
+            .ok()
+    }
+
+    pub fn get(&self, key: &[u8]) -> GetResult {
         // find table
-        let table = match self.tables.binary_search_by(|t| {
-            if key >= t.min_key && key <= t.max_key {
-                Ordering::Equal
-            } else if key < t.min_key {
-                Ordering::Greater
-            } else {
-                Ordering::Less
-            }
-        }) {
-            Ok(idx) => &self.tables[idx],
-            _ => return GetResult::NotFound,
+        let Some(table_index) = self.find_table(key) else {
+            return GetResult::NotFound;
         };
 
-        // find block in table
-        if !table.bloom.maybe_contains(key) {
-            return GetResult::NotFound;
-        }
+        get_in_table(&self.tables[table_index], &mut None, key)
+    }
 
-        let block_num = match table.index.binary_search_by(|&(min_key, max_key)| {
-            if key >= min_key && key <= max_key {
-                Ordering::Equal
-            } else if key < min_key {
-                Ordering::Greater
-            } else {
-                Ordering::Less
+    /// Looks up sorted `keys` in one pass, keeping the current table open between
+    /// consecutive keys so neighbouring keys share file handles and block reads
+    pub fn get_many(&self, keys: &[Bytes]) -> Vec<GetResult> {
+        let mut results = Vec::with_capacity(keys.len());
+        let mut open_table = None;
+        let mut view = None;
+
+        for key in keys {
+            let Some(table_index) = self.find_table(key) else {
+                results.push(GetResult::NotFound);
+                continue;
+            };
+
+            if open_table != Some(table_index) {
+                open_table = Some(table_index);
+                view = None;
             }
-        }) {
-            Ok(idx) => idx,
-            _ => return GetResult::NotFound,
-        };
+            results.push(get_in_table(&self.tables[table_index], &mut view, key));
+        }
+
+        results
+    }
+}
 
-        // read block in table
-        for command in table.view().get_block_at(block_num).unwrap().iter() {
-            if command.key() > key {
-                // block is sorted, break early
-                break;
+/// Looks up a key within the bounds of `table`, opening `view` on the first block read
+fn get_in_table(table: &Table, view: &mut Option<TableView>, key: &[u8]) -> GetResult {
+    // probe filter
+    COUNTERS.add(&COUNTERS.filter_probes, 1);
+    if !table.filter.maybe_contains(key) {
+        COUNTERS.add(&COUNTERS.filter_negatives, 1);
+        return GetResult::NotFound;
+    }
+
+    if let Some(learned) = &table.learned {
+        let Some(predictions) = learned.predict(key) else {
+            return GetResult::NotFound;
+        };
+        let view = view.get_or_insert_with(|| table.view());
+        for prediction in predictions {
+            let block = view.get_block_at(prediction.block_index).unwrap();
+            let mut block_iter = block.iter();
+            block_iter.skip_records(prediction.first_record);
+
+            let window = prediction.last_record + 1 - prediction.first_record;
+            if let Some(res) = find_in_block(block_iter.take(window), key) {
+                return res;
             }
+        }
+        return GetResult::NotFound;
+    }
 
-            if command.key() == key {
-                match command {
-                    Command::Delete(..) => return GetResult::Deleted,
-                    Command::Put(_, val) => return GetResult::Value(val),
-                }
+    // find block in table
+    let block_num = match table
+        .index
+        .binary_search_by(|(min_key, max_key)| fence_cmp(min_key, max_key, key))
+    {
+        Ok(idx) => idx,
+        _ => return GetResult::NotFound,
+    };
+
+    // read block in table
+    let view = view.get_or_insert_with(|| table.view());
+    let block = view.get_block_at(block_num).unwrap();
+    find_in_block(block.iter(), key).unwrap_or(GetResult::NotFound)
+}
+
+/// `None` if the key can still be in a later block
+fn find_in_block<I: Iterator<Item = Command>>(commands: I, key: &[u8]) -> Option<GetResult> {
+    for command in commands {
+        match command.key()[..].cmp(key) {
+            // block is sorted, break early
+            Ordering::Greater => return Some(GetResult::NotFound),
+            Ordering::Less => continue,
+            Ordering::Equal => {
+                return Some(match command.at(unix_millis()) {
+                    Command::Delete(..) => GetResult::Deleted,
+                    Command::Put(_, val) | Command::Expiring(_, val, _) => GetResult::Value(val),
+                    Command::Merge(_, operand) => GetResult::Merge(operand),
+                });
             }
         }
+    }
+
+    None
+}
 
-        GetResult::NotFound
+/// Where `[min_key, max_key]` lies relative to `key`, for binary searches over fences
+fn fence_cmp(min_key: &[u8], max_key: &[u8], key: &[u8]) -> Ordering {
+    if key < min_key {
+        Ordering::Greater
+    } else if key > max_key {
+        Ordering::Less
+    } else {
+        Ordering::Equal
     }
 }
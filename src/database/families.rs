@@ -0,0 +1,168 @@
+use std::{collections::BTreeMap, fmt, fs, path::PathBuf, sync::Arc, time::Duration};
+
+use tokio::sync::RwLock;
+
+use super::{run_blocking, table::TableOptions, Database};
+use crate::config::{parse_table_flags, NUM_LEVELS};
+
+const FAMILIES_DIRECTORY: &str = "cf";
+const FLAGS_FILE: &str = "flags";
+const MAX_NAME_BYTES: usize = 64;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum FamilyError {
+    InvalidName,
+    InvalidFlags,
+    Exists,
+    NotFound,
+}
+
+impl fmt::Display for FamilyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::InvalidName => "invalid family name",
+            Self::InvalidFlags => "invalid family flags",
+            Self::Exists => "family exists",
+            Self::NotFound => "no such family",
+        })
+    }
+}
+
+/// Named column families next to the default one, each a separate [`Database`] with its own
+/// memtable, levels and table options.
+///
+/// The default family lives in the data directory itself so existing databases open
+/// unchanged, named ones in `cf/<name>` along with the flags they were created with.
+pub struct Families {
+    data_directory: PathBuf,
+    default: Arc<Database>,
+    /// `None` while the family is being removed, so the name can't be reused before its files
+    /// are gone
+    named: RwLock<BTreeMap<String, Option<Arc<Database>>>>,
+}
+
+impl Families {
+    pub fn new(data_directory: PathBuf, table_options: [TableOptions; NUM_LEVELS]) -> Self {
+        let families_directory = data_directory.join(FAMILIES_DIRECTORY);
+        fs::create_dir_all(&families_directory).unwrap();
+
+        let mut named = BTreeMap::new();
+        for entry in fs::read_dir(&families_directory).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_str().unwrap().to_owned();
+            // the flags file is written first and deleted first, without it the family was
+            // never fully created or was in the middle of being removed
+            let Ok(flags) = fs::read_to_string(path.join(FLAGS_FILE)) else {
+                fs::remove_dir_all(path).unwrap();
+                continue;
+            };
+            let options = parse_table_flags(&flags).unwrap();
+            named.insert(name, Some(Arc::new(Database::new(path, options))));
+        }
+
+        Self {
+            default: Arc::new(Database::new(data_directory.clone(), table_options)),
+            data_directory,
+            named: RwLock::new(named),
+        }
+    }
+
+    /// The family called `name`, or the default one for `None`
+    pub async fn get(&self, name: Option<&str>) -> Option<Arc<Database>> {
+        match name {
+            None => Some(self.default.clone()),
+            Some(name) => self.named.read().await.get(name).cloned().flatten(),
+        }
+    }
+
+    /// Creates an empty family tuned with `flags`, written like the server's table flags
+    /// (`--filters xor --mmap`)
+    pub async fn create(&self, name: &str, flags: &str) -> Result<(), FamilyError> {
+        if !valid_name(name) {
+            return Err(FamilyError::InvalidName);
+        }
+        let options = parse_table_flags(flags).ok_or(FamilyError::InvalidFlags)?;
+
+        let mut named = self.named.write().await;
+        if named.contains_key(name) {
+            return Err(FamilyError::Exists);
+        }
+
+        let directory = self.family_directory(name);
+        let flags = flags.to_owned();
+        let db = run_blocking(move || {
+            fs::create_dir_all(&directory).unwrap();
+            fs::write(directory.join(FLAGS_FILE), flags).unwrap();
+            Database::new(directory, options)
+        })
+        .await;
+        named.insert(name.to_owned(), Some(Arc::new(db)));
+        Ok(())
+    }
+
+    /// Removes a family and all of its data. New commands stop seeing it right away, the
+    /// files are only deleted once the commands already running on it are done.
+    pub async fn remove(&self, name: &str) -> Result<(), FamilyError> {
+        let mut db = self
+            .named
+            .write()
+            .await
+            .get_mut(name)
+            .and_then(Option::take)
+            .ok_or(FamilyError::NotFound)?;
+
+        // commands only hold a family while they run, so this doesn't wait for long
+        let db = loop {
+            match Arc::try_unwrap(db) {
+                Ok(db) => break db,
+                Err(shared) => db = shared,
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        };
+
+        let directory = self.family_directory(name);
+        run_blocking(move || {
+            // unmap and close the tables before their files go away
+            drop(db);
+            fs::remove_file(directory.join(FLAGS_FILE)).unwrap();
+            fs::remove_dir_all(directory).unwrap();
+        })
+        .await;
+        self.named.write().await.remove(name);
+        Ok(())
+    }
+
+    pub async fn names(&self) -> Vec<String> {
+        let named = self.named.read().await;
+        named
+            .iter()
+            .filter(|(_, db)| db.is_some())
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Dumps the memtable of every family, see [`Database::cleanup`]. Every command has to
+    /// be done by now.
+    pub fn cleanup(self) {
+        let named = self.named.into_inner().into_values().flatten();
+        let families = std::iter::once(self.default).chain(named);
+        for db in families {
+            Arc::try_unwrap(db)
+                .unwrap_or_else(|_| panic!("family still in use"))
+                .cleanup();
+        }
+    }
+
+    fn family_directory(&self, name: &str) -> PathBuf {
+        self.data_directory.join(FAMILIES_DIRECTORY).join(name)
+    }
+}
+
+/// Names become directory names, so keep them to a safe alphabet
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_BYTES
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
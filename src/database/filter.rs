@@ -0,0 +1,98 @@
+use std::{fmt::Debug, str::FromStr};
+
+use bytes::Bytes;
+
+use super::{bloom::Bloom, cuckoo::CuckooFilter, xor::XorFilter};
+use crate::config::BLOOM_CAPACITY;
+
+/// Approximate membership check consulted by `DiskLevel::get` before reading a block.
+/// A `false` answer must be exact, a `true` answer may be a false positive.
+pub trait PointFilter: Debug + Send + Sync {
+    fn kind(&self) -> FilterKind;
+
+    fn maybe_contains(&self, key: &[u8]) -> bool;
+
+    /// Heap memory used by the filter, reported in STATS
+    fn size_bytes(&self) -> usize;
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FilterKind {
+    #[default]
+    Bloom,
+    Xor,
+    Cuckoo,
+}
+
+impl FilterKind {
+    /// Builds a filter over all keys of a table. Keys are sorted and unique.
+    pub fn build(&self, keys: &[Bytes]) -> Box<dyn PointFilter> {
+        match self {
+            Self::Bloom => {
+                let mut bloom = Bloom::new(BLOOM_CAPACITY);
+                for key in keys {
+                    bloom.put(key);
+                }
+                Box::new(bloom)
+            }
+            Self::Xor => Box::new(XorFilter::new(keys)),
+            Self::Cuckoo => Box::new(CuckooFilter::new(keys)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Bloom => "bloom",
+            Self::Xor => "xor",
+            Self::Cuckoo => "cuckoo",
+        }
+    }
+}
+
+impl FromStr for FilterKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bloom" => Ok(Self::Bloom),
+            "xor" => Ok(Self::Xor),
+            "cuckoo" => Ok(Self::Cuckoo),
+            _ => Err(format!(
+                "Unknown filter {s:?}, expected bloom, xor or cuckoo"
+            )),
+        }
+    }
+}
+
+/// Seeded 64 bit hash of a key, shared by the static filters since they need to rehash
+/// with a fresh seed when construction fails. Mixes the key in 8 byte words with the
+/// murmur3 finalizer.
+pub fn hash_key(key: &[u8], seed: u64) -> u64 {
+    let mut h = seed ^ (key.len() as u64).wrapping_mul(0x9E3779B97F4A7C15);
+    for chunk in key.chunks(8) {
+        let mut word = [0; 8];
+        word[..chunk.len()].copy_from_slice(chunk);
+        h = fmix64(h ^ u64::from_le_bytes(word));
+    }
+    fmix64(h)
+}
+
+/// Order preserving integer of keys up to 8 bytes long, `None` for longer keys. Only
+/// injective among keys of the same length, so callers have to group keys by length.
+pub fn key_as_int(key: &[u8]) -> Option<u64> {
+    if key.len() > 8 {
+        return None;
+    }
+    let mut word = [0; 8];
+    word[8 - key.len()..].copy_from_slice(key);
+    Some(u64::from_be_bytes(word))
+}
+
+fn fmix64(mut h: u64) -> u64 {
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^= h >> 33;
+    h
+}
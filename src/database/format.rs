@@ -0,0 +1,203 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use bytes::Buf;
+
+use super::{
+    build_tables,
+    codec::Codec,
+    table::{Command, TableOptions},
+};
+use crate::config::{BLOCK_SIZE_BYTES, NUM_LEVELS};
+
+/// Version of the table and log formats, kept in `FORMAT_FILE` of every database directory
+pub const FORMAT_VERSION: u32 = 2;
+/// Version 1 stored fixed size i32 records in tables named `min:max`
+const LEGACY_VERSION: u32 = 1;
+
+const FORMAT_FILE: &str = "FORMAT";
+// Tables converted from version 1 are built here, then moved into place once all of them are
+const MIGRATE_DIRECTORY: &str = "migrate";
+const MIGRATED_DIRECTORY: &str = "migrated";
+
+/// Checks the format of the database in `data_directory` before it's opened, converting the
+/// tables of version 1 databases. Panics on versions this build can't read.
+///
+/// Directories without a format file are either new, from version 1, or from before the file
+/// was written with version 2 tables. Only version 1 names tables `min:max`, so those are
+/// converted whatever the rest of the directory holds.
+pub fn open(data_directory: &Path) {
+    fs::create_dir_all(data_directory).unwrap();
+    let format_file = data_directory.join(FORMAT_FILE);
+    if let Ok(version) = fs::read_to_string(&format_file) {
+        let version: u32 = version
+            .trim()
+            .parse()
+            .unwrap_or_else(|_| panic!("{} is not a format version file", format_file.display()));
+        if version != FORMAT_VERSION {
+            panic!(
+                "{} holds format version {version}, this build reads version {FORMAT_VERSION}",
+                data_directory.display()
+            );
+        }
+        return;
+    }
+
+    let migrated = data_directory.join(MIGRATED_DIRECTORY);
+    if !migrated.exists() && has_legacy_tables(data_directory) {
+        eprintln!(
+            "Converting the tables of {} from format version {LEGACY_VERSION} to {FORMAT_VERSION}",
+            data_directory.display()
+        );
+        let migrate = data_directory.join(MIGRATE_DIRECTORY);
+        let _ = fs::remove_dir_all(&migrate);
+        for level in 0..=NUM_LEVELS {
+            convert_level(
+                &level_directory(data_directory, level),
+                &level_directory(&migrate, level),
+            );
+        }
+        // the conversion is done once the directory has its final name, a restart before
+        // then starts over
+        fs::rename(&migrate, &migrated).unwrap();
+    }
+    if migrated.exists() {
+        for level in 0..=NUM_LEVELS {
+            let converted = level_directory(&migrated, level);
+            if converted.exists() {
+                let target = level_directory(data_directory, level);
+                let _ = fs::remove_dir_all(&target);
+                fs::rename(converted, target).unwrap();
+            }
+        }
+        fs::remove_dir_all(&migrated).unwrap();
+    }
+
+    let tmp_file = data_directory.join(format!("{FORMAT_FILE}.tmp"));
+    fs::write(&tmp_file, format!("{FORMAT_VERSION}\n")).unwrap();
+    fs::rename(tmp_file, format_file).unwrap();
+}
+
+fn level_directory(data_directory: &Path, level: usize) -> PathBuf {
+    data_directory.join(format!("level{level}"))
+}
+
+fn is_legacy_table(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.contains(':'))
+}
+
+fn has_legacy_tables(data_directory: &Path) -> bool {
+    (0..=NUM_LEVELS).any(|level| {
+        fs::read_dir(level_directory(data_directory, level))
+            .into_iter()
+            .flatten()
+            .any(|entry| is_legacy_table(&entry.unwrap().path()))
+    })
+}
+
+/// Writes the tables of `from` to `to` in the current format, with i32 keys and values
+/// encoded the way the server stores them
+fn convert_level(from: &Path, to: &Path) {
+    fs::create_dir_all(to).unwrap();
+    let Ok(entries) = fs::read_dir(from) else {
+        return;
+    };
+    for entry in entries {
+        let path = entry.unwrap().path();
+        if !is_legacy_table(&path) {
+            // a version 2 table can't share a level with version 1 ones, carry it over as is
+            fs::copy(&path, to.join(path.file_name().unwrap())).unwrap();
+            continue;
+        }
+        let commands = legacy_commands(&fs::read(&path).unwrap());
+        build_tables(commands.into_iter(), to, TableOptions::default());
+    }
+}
+
+/// Version 1 blocks hold records of a tag, a big endian i32 key and for puts an i32 value,
+/// padded with `0xFF` up to the block size
+fn legacy_commands(file: &[u8]) -> Vec<Command> {
+    let mut commands = vec![];
+    for mut block in file.chunks(BLOCK_SIZE_BYTES) {
+        while block.remaining() >= 5 {
+            match block.get_u8() {
+                0 if block.remaining() >= 8 => {
+                    let key = block.get_i32().encode();
+                    commands.push(Command::Put(key, block.get_i32().encode()));
+                }
+                1 => commands.push(Command::Delete(block.get_i32().encode())),
+                _ => break,
+            }
+        }
+    }
+    commands
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::Database;
+
+    fn legacy_block(records: &[(i32, Option<i32>)]) -> Vec<u8> {
+        let mut block = vec![];
+        for &(key, val) in records {
+            match val {
+                Some(val) => {
+                    block.push(0);
+                    block.extend_from_slice(&key.to_be_bytes());
+                    block.extend_from_slice(&val.to_be_bytes());
+                }
+                None => {
+                    block.push(1);
+                    block.extend_from_slice(&key.to_be_bytes());
+                }
+            }
+        }
+        block.resize(BLOCK_SIZE_BYTES, 0xFF);
+        block
+    }
+
+    #[tokio::test]
+    async fn converts_version_1_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let level1 = dir.path().join("level1");
+        fs::create_dir_all(&level1).unwrap();
+        let mut file = legacy_block(&[(-5, Some(50)), (3, Some(30))]);
+        file.extend(legacy_block(&[(7, Some(70))]));
+        fs::write(level1.join("-5:7"), file).unwrap();
+        let level2 = dir.path().join("level2");
+        fs::create_dir_all(&level2).unwrap();
+        fs::write(
+            level2.join("1:3"),
+            legacy_block(&[(1, Some(10)), (3, Some(31))]),
+        )
+        .unwrap();
+        let level0 = dir.path().join("level0");
+        fs::create_dir_all(&level0).unwrap();
+        fs::write(level0.join("3:3"), legacy_block(&[(3, None)])).unwrap();
+
+        let db: Database<i32, i32> =
+            Database::new(dir.path().to_owned(), [TableOptions::default(); NUM_LEVELS]);
+        assert_eq!(db.get(&-5).await, Some(50));
+        assert_eq!(db.get(&1).await, Some(10));
+        assert_eq!(db.get(&3).await, None);
+        assert_eq!(db.get(&7).await, Some(70));
+        assert_eq!(
+            fs::read_to_string(dir.path().join(FORMAT_FILE)).unwrap(),
+            format!("{FORMAT_VERSION}\n")
+        );
+        assert!(!has_legacy_tables(dir.path()));
+    }
+
+    #[test]
+    #[should_panic(expected = "holds format version 3")]
+    fn refuses_newer_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(FORMAT_FILE), "3\n").unwrap();
+        open(dir.path());
+    }
+}
@@ -0,0 +1,160 @@
+use bytes::Bytes;
+
+use super::filter::key_as_int;
+use crate::config::LEARNED_INDEX_EPSILON;
+
+/// Longer keys don't convert to `f64` exactly
+const MAX_KEY_LEN: usize = 6;
+
+/// One linear piece of the model, valid from `first_key` up to the next segment's first key
+#[derive(Debug)]
+struct Segment {
+    first_key: u64,
+    first_pos: u32,
+    slope: f64,
+}
+
+/// Piecewise linear model from a key to its record position within a table, built with the
+/// shrinking cone algorithm of FITing-Tree (Galakatos et al., 2019) so every prediction is
+/// off by at most `LEARNED_INDEX_EPSILON` records.
+///
+/// Keys are modelled as integers, so only tables whose keys all have the same length of at
+/// most `MAX_KEY_LEN` bytes (like the i32 keys of the original protocol) get an index.
+#[derive(Debug)]
+pub struct LearnedIndex {
+    key_len: usize,
+    segments: Vec<Segment>,
+    block_starts: Vec<u32>, // position of the first record of each block
+    records: usize,
+    max_error: u32,
+}
+
+/// Block and record range within it that must hold the key if the table has it
+pub struct Prediction {
+    pub block_index: usize,
+    pub first_record: usize,
+    pub last_record: usize,
+}
+
+impl LearnedIndex {
+    /// `keys` are all keys of the table in order, `block_lens` the number of records per block.
+    /// `None` if the keys can't be modelled.
+    pub fn new(keys: &[Bytes], block_lens: &[u32]) -> Option<Self> {
+        let key_len = keys.first()?.len();
+        if key_len > MAX_KEY_LEN {
+            return None;
+        }
+        let keys: Vec<u64> = keys
+            .iter()
+            .map(|k| (k.len() == key_len).then(|| key_as_int(k)).flatten())
+            .collect::<Option<_>>()?;
+
+        let block_starts = block_lens
+            .iter()
+            .scan(0, |start, &len| {
+                let block_start = *start;
+                *start += len;
+                Some(block_start)
+            })
+            .collect();
+
+        let epsilon = LEARNED_INDEX_EPSILON as f64;
+        let mut segments: Vec<Segment> = vec![];
+        let (mut slope_low, mut slope_high) = (0_f64, f64::INFINITY);
+
+        for (pos, &key) in keys.iter().enumerate() {
+            if let Some(segment) = segments.last_mut() {
+                let dx = key as f64 - segment.first_key as f64;
+                let dy = pos as f64 - segment.first_pos as f64;
+                let low = slope_low.max((dy - epsilon) / dx);
+                let high = slope_high.min((dy + epsilon) / dx);
+
+                if low <= high {
+                    (slope_low, slope_high) = (low, high);
+                    segment.slope = if high.is_finite() {
+                        (low + high) / 2.0
+                    } else {
+                        low
+                    };
+                    continue;
+                }
+            }
+
+            // point falls outside the cone, start a new segment at it
+            segments.push(Segment {
+                first_key: key,
+                first_pos: pos as u32,
+                slope: 0.0,
+            });
+            (slope_low, slope_high) = (0_f64, f64::INFINITY);
+        }
+
+        let mut res = Self {
+            key_len,
+            segments,
+            block_starts,
+            records: keys.len(),
+            max_error: 0,
+        };
+        res.max_error = keys
+            .iter()
+            .enumerate()
+            .map(|(pos, &key)| res.predict_position(key).abs_diff(pos) as u32)
+            .max()
+            .unwrap_or(0);
+        Some(res)
+    }
+
+    fn predict_position(&self, key: u64) -> usize {
+        let segment_index = self
+            .segments
+            .partition_point(|s| s.first_key <= key)
+            .saturating_sub(1);
+        let segment = &self.segments[segment_index];
+
+        let pos =
+            segment.first_pos as f64 + segment.slope * (key as f64 - segment.first_key as f64);
+        pos.round().max(0.0) as usize
+    }
+
+    /// Blocks covered by the error window of the prediction, in order. `None` if the key
+    /// can't be in the table since it doesn't have the length of its keys.
+    pub fn predict(&self, key: &[u8]) -> Option<impl Iterator<Item = Prediction> + '_> {
+        if key.len() != self.key_len {
+            return None;
+        }
+        let pos = self.predict_position(key_as_int(key).unwrap());
+        let high = (pos + self.max_error as usize).min(self.records.saturating_sub(1));
+        let low = pos.saturating_sub(self.max_error as usize).min(high);
+
+        let first_block = self.block_of(low);
+        let last_block = self.block_of(high);
+
+        Some((first_block..=last_block).map(move |block_index| {
+            let block_start = self.block_starts[block_index] as usize;
+            Prediction {
+                block_index,
+                first_record: low.saturating_sub(block_start),
+                last_record: high - block_start,
+            }
+        }))
+    }
+
+    fn block_of(&self, pos: usize) -> usize {
+        self.block_starts
+            .partition_point(|&start| start as usize <= pos)
+            .saturating_sub(1)
+    }
+
+    pub fn segments(&self) -> usize {
+        self.segments.len()
+    }
+
+    pub fn max_error(&self) -> u32 {
+        self.max_error
+    }
+
+    pub fn size_bytes(&self) -> usize {
+        self.segments.len() * std::mem::size_of::<Segment>() + self.block_starts.len() * 4
+    }
+}
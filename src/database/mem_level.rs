@@ -1,88 +1,166 @@
-use std::{collections::BTreeMap, fs, ops::Deref, path::Path};
+use std::{
+    collections::BTreeMap,
+    fs,
+    ops::{Bound, Deref},
+    path::Path,
+};
+
+use bytes::Bytes;
 
 use super::{
-    table::{BlockMut, Command, Table, TableBuilder, TableView},
+    build_tables,
+    merge_operator::MergeOperator,
+    table::{unix_millis, Command, Table, TableOptions, TableView},
+    wal::Wal,
     GetResult,
 };
+use crate::config::{MAX_KEY_BYTES, MAX_VALUE_BYTES, MEM_CAPACITY_BYTES};
 
 pub struct MemLevel {
-    data: BTreeMap<i32, Option<i32>>,
+    /// Latest command on every key, merges folded into whatever they were written over
+    data: BTreeMap<Bytes, Command>,
+    size_bytes: usize, // encoded size of every record
+    wal: Wal,
+    merge_operator: MergeOperator,
+    /// Times the memtable was swapped out to be flushed, so callers that let go of the lock
+    /// can tell whether the records they saw have moved to disk since
+    generation: u64,
 }
 
 impl Deref for MemLevel {
-    type Target = BTreeMap<i32, Option<i32>>;
+    type Target = BTreeMap<Bytes, Command>;
 
     fn deref(&self) -> &Self::Target {
-        return &self.data;
+        &self.data
     }
 }
 
 impl MemLevel {
-    pub fn new(data_directory: &Path) -> Self {
+    pub fn new(data_directory: &Path, merge_operator: MergeOperator, sync_wal: bool) -> Self {
         let level_directory = data_directory.join("level0");
         fs::create_dir_all(&level_directory).unwrap();
 
+        let (wal, batches) = Wal::open(data_directory, sync_wal);
         let mut res = Self {
             data: BTreeMap::new(),
+            size_bytes: 0,
+            wal,
+            merge_operator,
+            generation: 0,
         };
 
-        if let Some(Ok(entry)) = fs::read_dir(&level_directory).unwrap().into_iter().next() {
-            for command in
-                TableView::new(entry.path(), 0).flat_map(|b| unsafe { b.as_ref().unwrap().iter() })
-            {
-                match command {
-                    Command::Delete(key) => res.delete(key),
-                    Command::Put(key, val) => res.insert(key, val),
-                };
+        // large memtables are dumped into several tables with disjoint keys. They are older
+        // than anything in the logs, which are only discarded after their memtable is dumped.
+        let dumped: Vec<_> = fs::read_dir(&level_directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        for path in dumped.iter() {
+            for command in TableView::new(path.clone(), 0).flat_map(|b| b.iter()) {
+                res.apply(command);
             }
-            let _ = fs::remove_file(&entry.path());
+        }
+        for command in batches.into_iter().flatten() {
+            res.apply(command);
+        }
+
+        // the dumps and old logs can go once the new log holds everything they did
+        let snapshot: Vec<Command> = res.values().cloned().collect();
+        res.wal.checkpoint(&snapshot);
+        for path in dumped {
+            let _ = fs::remove_file(path);
+        }
+
+        res
+    }
+
+    /// Logs `batch` as one record and applies it. Callers hold the write lock, so readers
+    /// see either none or all of the batch.
+    pub fn write(&mut self, batch: Vec<Command>) {
+        for command in batch.iter() {
+            assert!(
+                command.key().len() <= MAX_KEY_BYTES
+                    && command.value().is_none_or(|v| v.len() <= MAX_VALUE_BYTES),
+                "record doesn't fit in a block"
+            );
+        }
+
+        self.wal.append(&batch);
+        for command in batch {
+            self.apply(command);
+        }
+    }
+
+    fn apply(&mut self, command: Command) {
+        let command = match self.data.remove(command.key()) {
+            Some(old) => {
+                self.size_bytes -= old.encoded_len();
+                self.merge_operator.fold(old, command)
+            }
+            None => command,
         };
+        self.size_bytes += command.encoded_len();
+        self.data.insert(command.key().clone(), command);
+    }
 
-        return res;
+    pub fn size_bytes(&self) -> usize {
+        self.size_bytes
     }
 
-    pub fn insert(&mut self, key: i32, value: i32) {
-        self.data.insert(key, Some(value));
+    pub fn generation(&self) -> u64 {
+        self.generation
     }
 
-    pub fn delete(&mut self, key: i32) {
-        self.data.insert(key, None);
+    pub fn is_full(&self) -> bool {
+        self.size_bytes >= MEM_CAPACITY_BYTES
     }
 
-    pub fn get(&self, key: i32) -> GetResult {
-        match self.data.get(&key).cloned() {
+    pub fn get(&self, key: &[u8]) -> GetResult {
+        match self.data.get(key).cloned().map(|c| c.at(unix_millis())) {
             None => GetResult::NotFound,
-            Some(None) => GetResult::Deleted,
-            Some(Some(val)) => GetResult::Value(val),
+            Some(Command::Delete(..)) => GetResult::Deleted,
+            Some(Command::Put(_, val) | Command::Expiring(_, val, _)) => GetResult::Value(val),
+            Some(Command::Merge(_, operand)) => GetResult::Merge(operand),
         }
     }
 
-    pub fn write_to_table(&self, to_dir: &Path) -> Table {
-        let mut iter = self.iter();
-        let mut tb = TableBuilder::new(to_dir);
-
-        let mut block = BlockMut::new();
-        while let Some((&key, &val)) = iter.next() {
-            let command = match val {
-                None => Command::Delete(key),
-                Some(val) => Command::Put(key, val),
-            };
-
-            if !block.push_command(command) {
-                tb.insert_block(&block);
-                block.clear();
-                block.push_command(command);
-            }
-        }
-        if !block.is_empty() {
-            tb.insert_block(&block);
+    /// Copies the commands of `[min_key, max_key)` out so the lock can be released,
+    /// no `max_key` means no upper bound
+    pub fn range_commands(&self, min_key: &[u8], max_key: Option<&[u8]>) -> Vec<Command> {
+        if max_key.is_some_and(|max_key| min_key >= max_key) {
+            return vec![];
         }
 
-        tb.build()
+        let upper = max_key.map_or(Bound::Unbounded, Bound::Excluded);
+        self.data
+            .range::<[u8], _>((Bound::Included(min_key), upper))
+            .map(|(_, command)| command.clone())
+            .collect()
     }
 
+    /// Writes the memtable out as tables of at most `MAX_FILE_SIZE_BYTES`
+    pub fn write_to_table(&self, to_dir: &Path, options: TableOptions) -> Vec<Table> {
+        build_tables(self.values().cloned(), to_dir, options)
+    }
+
+    /// Freezes the current contents into the returned memtable, along with the logs holding
+    /// them. Logging continues in a new file.
     pub fn clear(&mut self) -> MemLevel {
         let data = std::mem::take(&mut self.data);
-        MemLevel { data }
+        let size_bytes = std::mem::take(&mut self.size_bytes);
+        let wal = self.wal.rotate();
+        self.generation += 1;
+        MemLevel {
+            data,
+            size_bytes,
+            wal,
+            merge_operator: self.merge_operator,
+            generation: self.generation,
+        }
+    }
+
+    /// Drops the logs of a memtable whose records are in tables now
+    pub fn discard_wal(self) {
+        self.wal.discard();
     }
 }
@@ -1,6 +1,6 @@
 use std::{cmp::Ordering, iter::Peekable};
 
-use super::table::Command;
+use super::{merge_operator::MergeOperator, table::Command};
 
 pub struct MergeCommands<I1, I2>
 where
@@ -9,6 +9,7 @@ where
 {
     iter1: Peekable<I1>,
     iter2: Peekable<I2>,
+    operator: MergeOperator,
 }
 
 impl<I1, I2> Iterator for MergeCommands<I1, I2>
@@ -20,12 +21,14 @@ where
 
     fn next(&mut self) -> Option<Self::Item> {
         match (self.iter1.peek(), self.iter2.peek()) {
-            (Some(&v1), Some(&v2)) => match v1.key().cmp(&v2.key()) {
+            (Some(v1), Some(v2)) => match v1.key().cmp(v2.key()) {
                 Ordering::Less => self.iter1.next(),
                 Ordering::Greater => self.iter2.next(),
                 Ordering::Equal => {
-                    self.iter2.next(); // ignore older command in iter2
-                    self.iter1.next()
+                    // the newer command in iter1 shadows the older one unless it's a merge
+                    let older = self.iter2.next().unwrap();
+                    let newer = self.iter1.next().unwrap();
+                    Some(self.operator.fold(older, newer))
                 }
             },
             (Some(_), None) => self.iter1.next(),
@@ -35,7 +38,13 @@ where
     }
 }
 
-pub fn merge_sorted_commands<I1, I2>(iter1: I1, iter2: I2) -> MergeCommands<I1, I2>
+/// Merges the sorted commands of `iter1` with the older ones of `iter2`, folding merge
+/// records on equal keys with `operator`
+pub fn merge_sorted_commands<I1, I2>(
+    iter1: I1,
+    iter2: I2,
+    operator: MergeOperator,
+) -> MergeCommands<I1, I2>
 where
     I1: Iterator<Item = Command>,
     I2: Iterator<Item = Command>,
@@ -43,5 +52,6 @@ where
     MergeCommands {
         iter1: iter1.peekable(),
         iter2: iter2.peekable(),
+        operator,
     }
 }
@@ -0,0 +1,105 @@
+use std::str::FromStr;
+
+use bytes::Bytes;
+
+use super::{
+    codec::Codec,
+    table::{unix_millis, Command},
+};
+
+/// Combines merge operands written with [`Database::merge`](super::Database::merge). Merges
+/// are stored as records of their own and only folded together when read or compacted, in
+/// whatever grouping those happen to see them, so every operator is associative.
+///
+/// Operands that are both 4 or both 8 bytes long are integers in the i32 or i64 encoding of
+/// [`Codec`]. Max and min compare any other operands as bytes, or ors them byte by byte with
+/// the shorter one padded with zeros, and add lets the newer operand replace the older one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MergeOperator {
+    #[default]
+    Add,
+    Max,
+    Min,
+    Or,
+}
+
+impl MergeOperator {
+    /// Combines an `older` value or operand with a `newer` operand
+    pub fn merge(self, older: &[u8], newer: &[u8]) -> Bytes {
+        match self {
+            // the integer encodings preserve order, so comparing bytes is enough
+            Self::Max => Bytes::copy_from_slice(older.max(newer)),
+            Self::Min => Bytes::copy_from_slice(older.min(newer)),
+            Self::Add => integers(older, newer, i32::wrapping_add, i64::wrapping_add)
+                .unwrap_or_else(|| Bytes::copy_from_slice(newer)),
+            Self::Or => integers(older, newer, |a, b| a | b, |a, b| a | b).unwrap_or_else(|| {
+                let (long, short) = if older.len() >= newer.len() {
+                    (older, newer)
+                } else {
+                    (newer, older)
+                };
+                let mut res = long.to_vec();
+                for (byte, other) in res.iter_mut().zip(short) {
+                    *byte |= other;
+                }
+                res.into()
+            }),
+        }
+    }
+
+    /// Folds a `newer` command on a key into the `older` one below it. Anything but a merge
+    /// shadows the older command, a merge on top of a tombstone (or an expired put) starts
+    /// from nothing and one on top of an expiring put keeps its expiry.
+    pub fn fold(self, older: Command, newer: Command) -> Command {
+        let Command::Merge(key, operand) = newer else {
+            return newer;
+        };
+        match older.at(unix_millis()) {
+            Command::Put(_, val) => Command::Put(key, self.merge(&val, &operand)),
+            Command::Expiring(_, val, expires_at) => {
+                Command::Expiring(key, self.merge(&val, &operand), expires_at)
+            }
+            Command::Delete(_) => Command::Put(key, operand),
+            Command::Merge(_, older) => Command::Merge(key, self.merge(&older, &operand)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Add => "add",
+            Self::Max => "max",
+            Self::Min => "min",
+            Self::Or => "or",
+        }
+    }
+}
+
+impl FromStr for MergeOperator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "add" => Ok(Self::Add),
+            "max" => Ok(Self::Max),
+            "min" => Ok(Self::Min),
+            "or" => Ok(Self::Or),
+            _ => Err(format!(
+                "Unknown merge operator {s:?}, expected add, max, min or or"
+            )),
+        }
+    }
+}
+
+/// Applies `op32` or `op64` when both operands are i32 or both are i64 encodings
+fn integers(
+    older: &[u8],
+    newer: &[u8],
+    op32: impl FnOnce(i32, i32) -> i32,
+    op64: impl FnOnce(i64, i64) -> i64,
+) -> Option<Bytes> {
+    match (older.len(), newer.len()) {
+        (4, 4) => Some(op32(i32::decode_slice(older)?, i32::decode_slice(newer)?).encode()),
+        (8, 8) => Some(op64(i64::decode_slice(older)?, i64::decode_slice(newer)?).encode()),
+        _ => None,
+    }
+}
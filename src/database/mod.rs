@@ -2,281 +2,905 @@ use std::fmt::Write;
 use std::{
     cmp::Ordering,
     collections::HashMap,
+    marker::PhantomData,
+    ops::{ControlFlow, Deref},
     path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
-use bytes::Buf;
+use bytes::Bytes;
+use codec::{decode_stored, Codec};
+use counters::COUNTERS;
+use cursor::Cursor;
 use disk_level::DiskLevel;
+use filter::FilterKind;
+use learned_index::LearnedIndex;
 use mem_level::MemLevel;
 use merge_iter::merge_sorted_commands;
-use table::{BlockMut, Command, Table, TableBuilder};
-use tokio::sync::RwLock;
+use merge_operator::MergeOperator;
+use range_filter::RangeFilter;
+use range_iter::RangeIter;
+use table::{unix_millis, BlockMut, Command, Table, TableBuilder, TableOptions};
+use tokio::{
+    runtime::Handle,
+    sync::{
+        OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+};
+use transaction::{Transaction, TransactionConflict, WriteTracker};
+use value_index::ValueIndex;
+use write_batch::WriteBatch;
 
-use crate::config::{MAX_FILE_SIZE_BYTES, MEM_CAPACITY, NUM_LEVELS};
+use crate::config::{MAX_FILE_SIZE_BYTES, NUM_LEVELS};
 
 pub mod bloom;
+pub mod codec;
+pub mod counters;
+pub mod cuckoo;
+pub mod cursor;
 pub mod disk_level;
+pub mod families;
+pub mod filter;
+pub mod format;
+pub mod learned_index;
 pub mod mem_level;
 pub mod merge_iter;
+pub mod merge_operator;
 pub mod once_done;
+pub mod range_filter;
+pub mod range_iter;
 pub mod table;
+pub mod transaction;
+pub mod value_index;
+pub mod wal;
+pub mod write_batch;
+pub mod xor;
 
-/// TODO: explain how I compact levels
+// TODO: explain how I compact levels
 
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum GetResult {
     NotFound,
     Deleted,
-    Value(i32),
+    Value(Bytes),
+    /// Merge operands folded so far, the value they apply to is further down
+    Merge(Bytes),
+}
+
+impl GetResult {
+    /// Folds the `pending` merge operand of the levels above into this result. Breaks with
+    /// the value once it's resolved, continues with the operand to pass further down.
+    fn fold(
+        self,
+        pending: Option<Bytes>,
+        operator: MergeOperator,
+    ) -> ControlFlow<Option<Bytes>, Option<Bytes>> {
+        let merge = |older: Bytes| match &pending {
+            Some(newer) => operator.merge(&older, newer),
+            None => older,
+        };
+        match self {
+            Self::NotFound => ControlFlow::Continue(pending),
+            Self::Deleted => ControlFlow::Break(pending),
+            Self::Value(val) => ControlFlow::Break(Some(merge(val))),
+            Self::Merge(operand) => ControlFlow::Continue(Some(merge(operand))),
+        }
+    }
+}
+
+/// Sorted unique keys of a batch and the values it replaces, for the value index to move
+type IndexedValues = (Vec<Bytes>, Vec<Option<Bytes>>);
+
+/// Records and encoded bytes of the memtable, then the tables of every disk level
+#[derive(Clone, Debug)]
+pub struct LevelSizes {
+    pub mem_records: usize,
+    pub mem_bytes: usize,
+    pub levels: Vec<DiskLevelSizes>,
+}
+
+#[derive(Clone, Debug)]
+pub struct DiskLevelSizes {
+    pub level: u32,
+    pub filter: FilterKind,
+    /// Memory taken by the point filters of the tables
+    pub filter_bytes: usize,
+    pub tables: Vec<TableSizes>,
+}
+
+impl DiskLevelSizes {
+    pub fn file_bytes(&self) -> u64 {
+        self.tables.iter().map(|t| t.file_size).sum()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TableSizes {
+    pub id: u64,
+    pub file_size: u64,
+    pub min_key: Bytes,
+    pub max_key: Bytes,
 }
 
-pub struct Database {
+/// Keys and values are stored as their [`Codec`] encodings and compared as bytes. A database
+/// has to be opened with the same `K` and `V` it was written with, reading anything that
+/// doesn't decode panics. The server works on the raw bytes.
+pub struct Database<K = Bytes, V = Bytes> {
     data_directory: PathBuf,
     memory: RwLock<MemLevel>,
-    disk: [RwLock<DiskLevel>; NUM_LEVELS],
+    disk: [Arc<RwLock<DiskLevel>>; NUM_LEVELS],
+    tracker: Arc<Mutex<WriteTracker>>,
+    merge_operator: MergeOperator,
+    value_index: Option<Box<ValueIndex>>,
+    _codec: PhantomData<fn() -> (K, V)>,
 }
 
-impl Database {
-    pub fn new(data_directory: PathBuf) -> Self {
-        let memory = MemLevel::new(&data_directory);
-        let disk: [RwLock<DiskLevel>; NUM_LEVELS] = std::array::from_fn(|idx| {
-            RwLock::new(DiskLevel::new(&data_directory, (idx + 1) as u32))
+impl<K: Codec, V: Codec> Database<K, V> {
+    /// Merges are folded with the merge operator of the first level's options, which also
+    /// decide whether values are indexed
+    pub fn new(data_directory: PathBuf, table_options: [TableOptions; NUM_LEVELS]) -> Self {
+        format::open(&data_directory);
+        let merge_operator = table_options[0].merge_operator;
+        let value_index = table_options[0]
+            .value_index
+            .then(|| Box::new(ValueIndex::new(&data_directory, table_options)));
+        let memory = MemLevel::new(&data_directory, merge_operator, table_options[0].sync_wal);
+        let disk: [Arc<RwLock<DiskLevel>>; NUM_LEVELS] = std::array::from_fn(|idx| {
+            Arc::new(RwLock::new(DiskLevel::new(
+                &data_directory,
+                (idx + 1) as u32,
+                table_options[idx],
+            )))
         });
 
         Self {
             data_directory,
             memory: RwLock::new(memory),
             disk,
+            tracker: Arc::default(),
+            merge_operator,
+            value_index,
+            _codec: PhantomData,
         }
     }
 
-    pub async fn insert(&self, key: i32, value: i32) {
-        let mut mem_write = self.memory.write().await;
-        mem_write.insert(key, value);
+    /// Encoded keys can be up to `MAX_KEY_BYTES` and values up to `MAX_VALUE_BYTES` long
+    pub async fn insert(&self, key: K, value: V) {
+        let mut batch = WriteBatch::new();
+        batch.put(key, value);
+        self.write(batch).await;
+    }
 
-        // keep holding the write guard so
-        if mem_write.len() >= MEM_CAPACITY as usize {
-            let old_mem = mem_write.clear();
-            drop(mem_write);
-            self.handle_overflow(old_mem).await;
+    /// Like [`Database::insert`], but `key` reads as deleted once `ttl` has passed. Expired
+    /// pairs are dropped by compactions.
+    pub async fn insert_with_ttl(&self, key: K, value: V, ttl: Duration) {
+        let mut batch = WriteBatch::new();
+        batch.put_with_ttl(key, value, ttl);
+        self.write(batch).await;
+    }
+
+    /// All pairs become visible at once, like a [`WriteBatch`]
+    pub async fn load(&self, pairs: Vec<(K, V)>) {
+        let mut batch = WriteBatch::new();
+        for (key, val) in pairs {
+            batch.put(key, val);
         }
+        self.write(batch).await;
     }
 
-    pub async fn load(&self, mut data: &[u8]) {
-        // a bit better than multiple calls to insert as locks are kept to a minimum
-        let mut mem_write = self.memory.write().await;
-        while data.has_remaining() {
-            let key = data.get_i32();
-            let val = data.get_i32();
+    pub async fn delete(&self, key: K) {
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.write(batch).await;
+    }
 
-            mem_write.insert(key, val);
+    /// Combines `operand` with the value of `key` without reading it, see [`MergeOperator`]
+    pub async fn merge(&self, key: K, operand: V) {
+        let mut batch = WriteBatch::new();
+        batch.merge(key, operand);
+        self.write(batch).await;
+    }
 
-            if mem_write.len() >= MEM_CAPACITY as usize {
-                let old_mem = mem_write.clear();
-                drop(mem_write);
-                self.handle_overflow(old_mem).await;
-                mem_write = self.memory.write().await;
-            }
+    /// Applies every command of `batch` atomically, see [`WriteBatch`]
+    pub async fn write(&self, batch: WriteBatch<K, V>) {
+        let (mem_write, indexed) = self.lock_for_write(&batch.commands).await;
+        self.tracker.lock().unwrap().record(&batch.commands);
+        self.apply(mem_write, batch.commands, indexed).await;
+    }
+
+    /// Starts an optimistic transaction, see [`Transaction`]
+    pub async fn begin(&self) -> Transaction<K, V> {
+        // writers record their batch before applying it, a transaction starting in between
+        // could read the old values without the commit noticing the write
+        let _mem = self.memory.read().await;
+        Transaction::new(self.tracker.clone())
+    }
+
+    /// Applies the writes of `tx` as one batch, unless a key it read was written since it began
+    pub async fn commit(&self, tx: Transaction<K, V>) -> Result<(), TransactionConflict> {
+        let commands = tx.commands();
+        let (mem_write, indexed) = self.lock_for_write(&commands).await;
+        {
+            let mut tracker = self.tracker.lock().unwrap();
+            tx.validate(&self.tracker, &tracker)?;
+            tracker.record(&commands);
         }
+        self.apply(mem_write, commands, indexed).await;
+        Ok(())
     }
 
-    pub async fn delete(&self, key: i32) {
-        let mut mem_write = self.memory.write().await;
-        mem_write.delete(key);
-        if mem_write.len() >= MEM_CAPACITY as usize {
-            let old_mem = mem_write.clear();
-            drop(mem_write);
-            self.handle_overflow(old_mem).await;
+    /// Takes the memtable write lock to write `commands`. With a value index the current values
+    /// of their keys come with it, see [`Database::lock_with_values`].
+    async fn lock_for_write(
+        &self,
+        commands: &[Command],
+    ) -> (RwLockWriteGuard<'_, MemLevel>, Option<IndexedValues>) {
+        if self.value_index.is_none() {
+            return (self.memory.write().await, None);
         }
+        let mut keys: Vec<Bytes> = commands.iter().map(|c| c.key().clone()).collect();
+        keys.sort_unstable();
+        keys.dedup();
+        let (mem_write, current) = self.lock_with_values(keys.clone()).await;
+        (mem_write, Some((keys, current)))
     }
 
-    async fn handle_overflow(&self, mem: MemLevel) {
-        let l0_table = mem.write_to_table(self.data_directory.join("level0").as_path());
+    /// Writes `commands` to the memtable, flushing it once full. With a value index `indexed`
+    /// holds the values the commands replace, to move their entries.
+    async fn apply(
+        &self,
+        mut mem_write: RwLockWriteGuard<'_, MemLevel>,
+        commands: Vec<Command>,
+        indexed: Option<IndexedValues>,
+    ) {
+        if let (Some(index), Some((keys, current))) = (&self.value_index, indexed) {
+            index
+                .update(&commands, keys, current, self.merge_operator)
+                .await;
+        }
 
-        let mut cur = self.disk[0].write().await;
-        merge(&mut vec![l0_table], &mut cur);
+        mem_write.write(commands);
+        if mem_write.is_full() {
+            self.flush(mem_write).await;
+        }
+    }
 
-        for i in 0..(NUM_LEVELS - 1) {
-            if cur.is_over_file_capacity() {
-                if cur.average_table_utilization() <= 0.5 {
-                    compact_in_place(&mut cur);
-                    assert!(!cur.is_over_file_capacity());
+    /// Swaps out the memtable and flushes it. Level0 is locked before the memtable is
+    /// released, so readers never see the records in neither of them.
+    async fn flush(&self, mut mem_write: RwLockWriteGuard<'_, MemLevel>) {
+        let old_mem = mem_write.clear();
+        let level0 = self.disk[0].clone().write_owned().await;
+        drop(mem_write);
+        self.handle_overflow(old_mem, level0).await;
+    }
+
+    /// Flushes `mem` and runs the compactions it triggers on the blocking pool
+    async fn handle_overflow(&self, mem: MemLevel, level0: OwnedRwLockWriteGuard<DiskLevel>) {
+        let level0_directory = self.data_directory.join("level0");
+        let disk = self.disk.clone();
+
+        run_blocking(move || {
+            // levels below the ones being compacted are only read locked, in level order
+            let bottom = |level: &DiskLevel| {
+                disk[level.level as usize..]
+                    .iter()
+                    .all(|l| l.blocking_read().tables.is_empty())
+            };
+
+            let mut cur = level0;
+            let mut l0_tables = mem.write_to_table(&level0_directory, cur.options);
+            mem.discard_wal();
+            COUNTERS.add(&COUNTERS.flushes, 1);
+
+            let at_bottom = bottom(&cur);
+            merge(&mut l0_tables, &mut cur, at_bottom);
+
+            for i in 0..(NUM_LEVELS - 1) {
+                if cur.is_over_file_capacity() {
+                    if cur.average_table_utilization() <= 0.5 {
+                        let at_bottom = bottom(&cur);
+                        compact_in_place(&mut cur, at_bottom);
+                        COUNTERS.add_compaction(cur.level);
+                        assert!(!cur.is_over_file_capacity());
+                        break;
+                    }
+                    let mut next = Handle::current().block_on(disk[i + 1].clone().write_owned());
+                    let at_bottom = bottom(&next);
+                    merge(&mut cur.tables, &mut next, at_bottom);
+                    COUNTERS.add_compaction(next.level);
+                    cur = next;
+                } else {
                     break;
                 }
-                let mut next = self.disk[i + 1].write().await;
-                merge(&mut cur.tables, &mut next);
-                cur = next;
-            } else {
-                break;
             }
-        }
 
-        if cur.is_over_file_capacity() {
-            compact_in_place(&mut cur);
-        }
+            if cur.is_over_file_capacity() {
+                let at_bottom = bottom(&cur);
+                compact_in_place(&mut cur, at_bottom);
+                COUNTERS.add_compaction(cur.level);
+            }
+        })
+        .await
+    }
+
+    pub async fn get(&self, key: &K) -> Option<V> {
+        self.get_encoded(key.encode()).await
     }
 
-    pub async fn get(&self, key: i32) -> Option<i32> {
-        match self.memory.read().await.get(key) {
-            GetResult::Deleted => return None,
-            GetResult::Value(val) => return Some(val),
-            GetResult::NotFound => {}
+    async fn get_encoded(&self, key: Bytes) -> Option<V> {
+        let found = self.memory.read().await.get(&key);
+        let val = match found.fold(None, self.merge_operator) {
+            ControlFlow::Break(val) => val,
+            ControlFlow::Continue(pending) => self.get_from_disk(key, pending).await,
         };
+        val.map(decode_stored)
+    }
 
-        for i in 0..NUM_LEVELS {
-            match self.disk[i].read().await.get(key) {
-                GetResult::Deleted => return None,
-                GetResult::Value(val) => return Some(val),
-                GetResult::NotFound => {}
-            };
-        }
+    /// Looks `key` up in the disk levels, folding in the `pending` merge operand of the
+    /// memtable
+    async fn get_from_disk(&self, key: Bytes, mut pending: Option<Bytes>) -> Option<Bytes> {
+        let disk = self.disk.clone();
+        let operator = self.merge_operator;
+        run_blocking(move || {
+            for level in disk.iter() {
+                match level.blocking_read().get(&key).fold(pending, operator) {
+                    ControlFlow::Break(val) => return val,
+                    ControlFlow::Continue(operand) => pending = operand,
+                }
+            }
+
+            pending
+        })
+        .await
+    }
+
+    /// Puts `new` only if the value of `key` is `expected`, `None` meaning the key is absent.
+    /// Returns the current value when it isn't.
+    pub async fn compare_and_set(
+        &self,
+        key: K,
+        expected: Option<&V>,
+        new: V,
+    ) -> Result<(), Option<V>> {
+        let expected = expected.map(|v| v.encode());
+        let new = Command::Put(key.encode(), new.encode());
+        self.read_modify_write(key.encode(), |current| {
+            if current == expected.as_ref() {
+                (Some(new), Ok(()))
+            } else {
+                (None, Err(current.cloned().map(decode_stored)))
+            }
+        })
+        .await
+    }
 
-        None
+    /// Puts `value` and returns the value it replaced
+    pub async fn get_set(&self, key: K, value: V) -> Option<V> {
+        let key = key.encode();
+        let new = Command::Put(key.clone(), value.encode());
+        self.read_modify_write(key, |current| {
+            (Some(new), current.cloned().map(decode_stored))
+        })
+        .await
     }
 
-    pub async fn range(
+    /// Deletes `key` and returns the value it had
+    pub async fn get_delete(&self, key: K) -> Option<V> {
+        let key = key.encode();
+        let delete = Command::Delete(key.clone());
+        self.read_modify_write(key, |current| {
+            // nothing to delete, don't bother writing a tombstone
+            let command = current.is_some().then_some(delete);
+            (command, current.cloned().map(decode_stored))
+        })
+        .await
+    }
+
+    /// Looks `key` up and applies the command `f` picks based on its value while holding
+    /// the memtable write lock, so no other write can come in between
+    async fn read_modify_write<R>(
         &self,
-        min_key: i32,
-        max_key: i32,
-    ) -> Option<impl Iterator<Item = (i32, i32)>> {
-        if min_key > max_key {
-            return None;
+        key: Bytes,
+        f: impl FnOnce(Option<&Bytes>) -> (Option<Command>, R),
+    ) -> R {
+        let (mem_write, mut current) = self.lock_with_values(vec![key.clone()]).await;
+        let current = current.pop().unwrap();
+
+        let (command, res) = f(current.as_ref());
+        if let Some(command) = command {
+            let batch = vec![command];
+            self.tracker.lock().unwrap().record(&batch);
+            self.apply(mem_write, batch, Some((vec![key], vec![current])))
+                .await;
         }
+        res
+    }
 
-        let mut res: HashMap<i32, Option<i32>> = HashMap::new();
+    /// Takes the memtable write lock along with the current values of `keys`. Keys the
+    /// memtable doesn't settle are read from the levels with the lock released, so writers
+    /// aren't stalled on disk reads. The lookups are redone if a flush or a write to one of the
+    /// keys came in before the lock was taken back.
+    async fn lock_with_values(
+        &self,
+        keys: Vec<Bytes>,
+    ) -> (RwLockWriteGuard<'_, MemLevel>, Vec<Option<Bytes>>) {
+        let mut mem_write = self.memory.write().await;
+        loop {
+            let found: Vec<GetResult> = keys.iter().map(|key| mem_write.get(key)).collect();
+            let in_memory: Option<Vec<Option<Bytes>>> = found
+                .iter()
+                .map(
+                    |found| match found.clone().fold(None, self.merge_operator) {
+                        ControlFlow::Break(val) => Some(val),
+                        ControlFlow::Continue(_) => None,
+                    },
+                )
+                .collect();
+            if let Some(values) = in_memory {
+                return (mem_write, values);
+            }
 
-        let mem = self.memory.read().await;
-        for (&key, &val) in mem.range(min_key..=max_key) {
-            res.insert(key, val);
+            let generation = mem_write.generation();
+            drop(mem_write);
+            let first_level = self.disk[0].clone().read_owned().await;
+            let values = self
+                .resolve_on_disk(keys.clone(), found.clone(), first_level)
+                .await;
+
+            mem_write = self.memory.write().await;
+            let unchanged = mem_write.generation() == generation
+                && keys
+                    .iter()
+                    .zip(&found)
+                    .all(|(key, found)| mem_write.get(key) == *found);
+            if unchanged {
+                return (mem_write, values);
+            }
         }
+    }
 
-        let mut cur_level = self.disk[0].read().await;
-        drop(mem); // drop here instead of before so no writer can write to lvl1
-
-        for i in 0..NUM_LEVELS {
-            if !cur_level.tables.is_empty() {
-                if let Some(locate_min) = cur_level.locate_nearest(min_key) {
-                    for command in cur_level.tables[locate_min.table_index]
-                        .iter_commands_from(locate_min.block_index, false)
-                        .chain(
-                            (&cur_level
-                                .tables
-                                .get(locate_min.table_index..)
-                                .unwrap_or(&[]))
-                                .iter()
-                                .flat_map(|t| t.iter_commands_from(0, false)),
-                        )
-                    {
-                        if command.key() < min_key {
-                            continue;
-                        }
+    /// Values of `keys` in request order. The memtable and every level are locked once for
+    /// the whole batch and only probed with the keys still unresolved above them.
+    pub async fn multi_get(&self, keys: &[K]) -> Vec<Option<V>> {
+        let keys = keys.iter().map(|k| k.encode()).collect();
+        let mem = self.memory.read().await;
+        self.get_many_encoded(mem, keys)
+            .await
+            .into_iter()
+            .map(|val| val.map(decode_stored))
+            .collect()
+    }
 
-                        if command.key() > max_key {
-                            break;
-                        }
+    /// [`Database::multi_get`] on encoded keys. `mem` is released once level0 is locked, unless
+    /// it's borrowed from a guard the caller keeps.
+    async fn get_many_encoded(
+        &self,
+        mem: impl Deref<Target = MemLevel>,
+        keys: Vec<Bytes>,
+    ) -> Vec<Option<Bytes>> {
+        let found = keys.iter().map(|key| mem.get(key)).collect();
+        let first_level = self.disk[0].clone().read_owned().await;
+        drop(mem);
+        self.resolve_on_disk(keys, found, first_level).await
+    }
 
-                        res.entry(command.key()).or_insert(command.value());
+    /// Finishes the lookups of `keys` given what the memtable `found` for them, reading the
+    /// levels from the locked `first_level` down for the keys it didn't settle
+    async fn resolve_on_disk(
+        &self,
+        keys: Vec<Bytes>,
+        found: Vec<GetResult>,
+        first_level: OwnedRwLockReadGuard<DiskLevel>,
+    ) -> Vec<Option<Bytes>> {
+        let mut results: Vec<Option<Bytes>> = vec![None; keys.len()];
+
+        // merge operands of the keys still unresolved
+        let mut pending: Vec<Option<Bytes>> = vec![None; keys.len()];
+        let operator = self.merge_operator;
+
+        let mut remaining: Vec<usize> = (0..keys.len()).collect();
+        remaining.sort_by(|&a, &b| keys[a].cmp(&keys[b]));
+
+        let mut found: Vec<Option<GetResult>> = found.into_iter().map(Some).collect();
+        remaining.retain(
+            |&idx| match found[idx].take().unwrap().fold(None, operator) {
+                ControlFlow::Break(val) => {
+                    results[idx] = val;
+                    false
+                }
+                ControlFlow::Continue(operand) => {
+                    pending[idx] = operand;
+                    true
+                }
+            },
+        );
+
+        let disk = self.disk.clone();
+        run_blocking(move || {
+            let mut cur_level = first_level;
+            for i in 0..NUM_LEVELS {
+                if remaining.is_empty() {
+                    break;
+                }
+
+                let sorted_keys: Vec<Bytes> =
+                    remaining.iter().map(|&idx| keys[idx].clone()).collect();
+                let mut level_results = cur_level.get_many(&sorted_keys).into_iter();
+                remaining.retain(|&idx| {
+                    let found = level_results.next().unwrap();
+                    match found.fold(pending[idx].take(), operator) {
+                        ControlFlow::Break(val) => {
+                            results[idx] = val;
+                            false
+                        }
+                        ControlFlow::Continue(operand) => {
+                            pending[idx] = operand;
+                            true
+                        }
                     }
+                });
+
+                if let Some(next) = disk.get(i + 1) {
+                    let next_level = Handle::current().block_on(next.clone().read_owned());
+                    cur_level = next_level;
                 }
             }
 
-            if let Some(next) = self.disk.get(i + 1) {
-                let next_level = next.read().await;
-                cur_level = next_level;
+            // merges with nothing below them
+            for idx in remaining {
+                results[idx] = pending[idx].take();
+            }
+            results
+        })
+        .await
+    }
+
+    /// Keys holding `value` in ascending order, `None` if the database has no value index
+    pub async fn keys_with_value(&self, value: &V) -> Option<Vec<K>> {
+        let pairs = self.range_by_value(value, Some(value), None).await?;
+        Some(pairs.into_iter().map(|(key, _)| key).collect())
+    }
+
+    /// Pairs with a value in `[min_val, max_val]` ordered by value and then key, at most
+    /// `limit` of them. No `max_val` means no upper bound, `None` if the database has no
+    /// value index.
+    pub async fn range_by_value(
+        &self,
+        min_val: &V,
+        max_val: Option<&V>,
+        limit: Option<usize>,
+    ) -> Option<Vec<(K, V)>> {
+        let index = self.value_index.as_ref()?;
+        let mut from: Bytes = value_index::value_prefix(&min_val.encode()).into();
+        let end: Option<Bytes> = max_val.map(|max| value_index::value_end(&max.encode()).into());
+        let limit = limit.unwrap_or(usize::MAX);
+
+        // entries are read a chunk at a time and checked against the pairs with the index
+        // unlocked, writers lock this database before the index
+        let mut pairs = vec![];
+        while pairs.len() < limit {
+            let take = (limit - pairs.len()).min(value_index::CHUNK_ENTRIES);
+            let chunk = index.entries(from.clone(), end.clone(), take).await;
+            let Some((last_key, last_val)) = chunk.last() else {
+                break;
+            };
+            from = value_index::entry_after(last_val, last_key);
+
+            let keys = chunk.iter().map(|(key, _)| key.clone()).collect();
+            let current = self.get_many_encoded(self.memory.read().await, keys).await;
+            let exhausted = chunk.len() < take;
+            pairs.extend(
+                chunk
+                    .into_iter()
+                    .zip(current)
+                    .filter(|((_, val), current)| current.as_ref() == Some(val))
+                    .map(|((key, val), _)| (decode_stored(key), decode_stored(val))),
+            );
+            if exhausted {
+                break;
             }
         }
+        Some(pairs)
+    }
 
-        if res.is_empty() {
-            None
-        } else {
-            Some(res.into_iter().filter_map(|(key, val)| Some((key, val?))))
+    /// Ascending `(key, value)` pairs of `[min_key, max_key)`, no `max_key` means no upper
+    /// bound. The iterator reads tables as it goes, so drive it from the blocking pool
+    /// (see [`run_blocking`]).
+    pub async fn range(&self, min_key: &K, max_key: Option<&K>) -> RangeIter<K, V> {
+        let (min_key, max_key) = (min_key.encode(), max_key.map(|k| k.encode()));
+        let mem = self.memory.read().await;
+        let mem_commands = mem.range_commands(&min_key, max_key.as_deref());
+
+        let levels = self.read_levels(mem).await;
+        RangeIter::new(mem_commands, levels, min_key, max_key, self.merge_operator)
+    }
+
+    /// Cursor over every live pair, see [`Cursor`] for what it locks
+    pub async fn iter(&self) -> Cursor<K, V> {
+        self.cursor(&[], None).await
+    }
+
+    /// Descending `(key, value)` pairs of `[min_key, max_key)`, reads tables like [`Database::range`]
+    pub async fn range_rev(
+        &self,
+        min_key: &K,
+        max_key: Option<&K>,
+    ) -> impl Iterator<Item = (K, V)> + Send + 'static {
+        let (min_key, max_key) = (min_key.encode(), max_key.map(|k| k.encode()));
+        // bounds are compared before decoding
+        let mut cursor: Cursor = self.cursor(&min_key, max_key.as_deref()).await;
+        let mut positioned = false;
+
+        std::iter::from_fn(move || {
+            // seek lazily so no table is read before the iterator is driven
+            if !positioned {
+                match &max_key {
+                    Some(max_key) => {
+                        cursor.seek(max_key);
+                        if cursor.valid() {
+                            cursor.prev();
+                        } else {
+                            cursor.seek_to_last();
+                        }
+                    }
+                    None => cursor.seek_to_last(),
+                }
+                positioned = true;
+            }
+
+            let (key, val) = cursor.current().filter(|(key, _)| *key >= min_key)?;
+            cursor.prev();
+            Some((decode_stored(key), decode_stored(val)))
+        })
+    }
+
+    /// Only copies the memtable within `[min_key, max_key)`, so the cursor must not leave it
+    async fn cursor<CK: Codec, CV: Codec>(
+        &self,
+        min_key: &[u8],
+        max_key: Option<&[u8]>,
+    ) -> Cursor<CK, CV> {
+        let mem = self.memory.read().await;
+        let mem_commands = mem.range_commands(min_key, max_key);
+
+        let levels = self.read_levels(mem).await;
+        Cursor::new(mem_commands, levels, self.merge_operator)
+    }
+
+    /// Read locks every disk level, releasing the memtable once the first one is held so
+    /// no flush can slip in between
+    async fn read_levels(
+        &self,
+        mem: RwLockReadGuard<'_, MemLevel>,
+    ) -> Vec<OwnedRwLockReadGuard<DiskLevel>> {
+        let mut levels = Vec::with_capacity(NUM_LEVELS);
+        levels.push(self.disk[0].clone().read_owned().await);
+        drop(mem);
+
+        for level in self.disk[1..].iter() {
+            levels.push(level.clone().read_owned().await);
+        }
+        levels
+    }
+
+    /// Sizes of the memtable and the disk levels, cheap next to `write_stats`. Each level is
+    /// read under its own lock, so they may be a flush or compaction apart.
+    pub async fn level_sizes(&self) -> LevelSizes {
+        let (mem_records, mem_bytes) = {
+            let mem = self.memory.read().await;
+            (mem.len(), mem.size_bytes())
+        };
+        let mut levels = Vec::with_capacity(NUM_LEVELS);
+        for level in self.disk.iter() {
+            let level = level.read().await;
+            levels.push(DiskLevelSizes {
+                level: level.level,
+                filter: level.options.filter,
+                filter_bytes: level.tables.iter().map(|t| t.filter.size_bytes()).sum(),
+                tables: level
+                    .tables
+                    .iter()
+                    .map(|t| TableSizes {
+                        id: t.id,
+                        file_size: t.file_size,
+                        min_key: t.min_key.clone(),
+                        max_key: t.max_key.clone(),
+                    })
+                    .collect(),
+            });
+        }
+        LevelSizes {
+            mem_records,
+            mem_bytes,
+            levels,
         }
     }
 
     pub async fn write_stats(&self, to: &mut String) {
-        let mut tally: HashMap<i32, bool> = HashMap::new();
+        let mut tally: HashMap<Bytes, bool> = HashMap::new();
         let mut level_counts = [0_usize; NUM_LEVELS + 1];
 
+        // expired puts are left out like deletes
+        let now = unix_millis();
+        let live = move |command: &Command| !command.is_deleted_at(now);
+
         to.push_str("\n---------------- Dump ----------------\n");
 
         let mem = self.memory.read().await;
-        for (&key, &val) in mem.iter() {
-            if let Some(value) = val {
-                write!(to, "{key}:{value}:L0 ").unwrap();
+        for (key, command) in mem.iter() {
+            if let Some(value) = command.value().filter(|_| live(command)) {
+                write_pair(to, key, value, 0);
                 level_counts[0] += 1;
             }
-            tally.insert(key, val.is_some());
+            tally.insert(key.clone(), live(command));
         }
 
         to.push_str("\n\n");
 
-        let mut cur_level = self.disk[0].read().await;
+        let first_level = self.disk[0].clone().read_owned().await;
         drop(mem);
 
-        for i in 0..NUM_LEVELS {
-            if !cur_level.tables.is_empty() {
-                for command in cur_level
-                    .tables
-                    .iter()
-                    .flat_map(|t| t.iter_commands_from(0, false))
-                {
-                    if let Command::Put(key, val) = command {
-                        write!(to, "{key}:{val}:L{} ", i + 1).unwrap();
-                        level_counts[i + 1] += 1;
+        let disk = self.disk.clone();
+        let mut out = std::mem::take(to);
+        *to = run_blocking(move || {
+            let to = &mut out;
+            let mut cur_level = first_level;
+
+            for i in 0..NUM_LEVELS {
+                if !cur_level.tables.is_empty() {
+                    for command in cur_level
+                        .tables
+                        .iter()
+                        .flat_map(|t| t.iter_commands_from(0, false))
+                    {
+                        if let Some(val) = command.value().filter(|_| live(&command)) {
+                            write_pair(to, command.key(), val, i + 1);
+                            level_counts[i + 1] += 1;
+                        }
+                        tally.entry(command.key().clone()).or_insert(live(&command));
                     }
-                    tally
-                        .entry(command.key())
-                        .or_insert(command.value().is_some());
+                    to.push_str("\n\n");
                 }
-                to.push_str("\n\n");
-            }
 
-            if let Some(next) = self.disk.get(i + 1) {
-                let next_level = next.read().await;
-                cur_level = next_level;
+                if let Some(next) = disk.get(i + 1) {
+                    let next_level = Handle::current().block_on(next.clone().read_owned());
+                    cur_level = next_level;
+                }
+            }
+            drop(cur_level);
+
+            to.push_str("\n---------------- TLDR ----------------\n");
+
+            writeln!(
+                to,
+                "Logical Pairs: {}",
+                tally.into_values().filter(|v| *v).count()
+            )
+            .unwrap();
+            for (idx, counts) in level_counts.into_iter().enumerate() {
+                if counts == 0 {
+                    continue;
+                }
+                writeln!(to, "LVL{idx}: {counts}").unwrap();
             }
-        }
 
-        to.push_str("\n---------------- TLDR ----------------\n");
+            for level in disk.iter() {
+                let level = level.blocking_read();
+                if level.tables.is_empty() {
+                    continue;
+                }
+                writeln!(
+                    to,
+                    "LVL{} filter: {} ({} bytes)",
+                    level.level,
+                    level.options.filter.name(),
+                    level
+                        .tables
+                        .iter()
+                        .map(|t| t.filter.size_bytes())
+                        .sum::<usize>()
+                )
+                .unwrap();
+
+                let range_filters: Vec<&RangeFilter> = level
+                    .tables
+                    .iter()
+                    .filter_map(|t| t.range_filter.as_ref())
+                    .collect();
+                if !range_filters.is_empty() {
+                    writeln!(
+                        to,
+                        "LVL{} range filter: {} bytes",
+                        level.level,
+                        range_filters.iter().map(|f| f.size_bytes()).sum::<usize>()
+                    )
+                    .unwrap();
+                }
 
-        writeln!(
-            to,
-            "Logical Pairs: {}",
-            tally.into_values().filter(|v| *v).count()
-        )
-        .unwrap();
-        for (idx, counts) in level_counts.into_iter().enumerate() {
-            if counts == 0 {
-                continue;
+                let learned: Vec<&LearnedIndex> = level
+                    .tables
+                    .iter()
+                    .filter_map(|t| t.learned.as_ref())
+                    .collect();
+                if !learned.is_empty() {
+                    writeln!(
+                        to,
+                        "LVL{} learned index: {} segments, max error {} records ({} bytes)",
+                        level.level,
+                        learned.iter().map(|l| l.segments()).sum::<usize>(),
+                        learned.iter().map(|l| l.max_error()).max().unwrap(),
+                        learned.iter().map(|l| l.size_bytes()).sum::<usize>()
+                    )
+                    .unwrap();
+                }
             }
-            writeln!(to, "LVL{idx}: {counts}").unwrap();
-        }
+
+            out
+        })
+        .await;
     }
 
     pub fn cleanup(self) {
         let mem = self.memory.into_inner();
 
         if !mem.is_empty() {
-            mem.write_to_table(self.data_directory.join("level0").as_path());
+            mem.write_to_table(
+                self.data_directory.join("level0").as_path(),
+                TableOptions::default(),
+            );
+        }
+        mem.discard_wal();
+
+        if let Some(index) = self.value_index {
+            index.cleanup();
         }
     }
 }
 
-fn build_tables<I: Iterator<Item = Command>>(mut iter: I, to_dir: &Path) -> Vec<Table> {
+/// i32 keys and values are printed as numbers, anything else escaped, see [`codec::write_display`]
+fn write_pair(to: &mut String, key: &[u8], val: &[u8], level: usize) {
+    codec::write_display(to, key);
+    to.push(':');
+    codec::write_display(to, val);
+    write!(to, ":L{level} ").unwrap();
+}
+
+/// Runs synchronous disk IO on tokio's blocking pool, keeping the worker threads that
+/// serve connections free while the call waits for the result
+pub async fn run_blocking<T, F>(f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
+
+fn build_tables<I: Iterator<Item = Command>>(
+    iter: I,
+    to_dir: &Path,
+    options: TableOptions,
+) -> Vec<Table> {
     let mut block = BlockMut::new();
     let mut new_tables = vec![];
 
-    let mut tb = TableBuilder::new(to_dir);
-    while let Some(command) = iter.next() {
-        if !block.push_command(command) {
+    let mut tb = TableBuilder::new(to_dir, options);
+    for command in iter {
+        if !block.push_command(&command) {
             tb.insert_block(&block);
 
             if tb.full() {
                 let new_table = tb.build();
-                tb = TableBuilder::new(to_dir);
+                tb = TableBuilder::new(to_dir, options);
                 new_tables.push(new_table);
             }
             block.clear();
-            block.push_command(command);
+            assert!(
+                block.push_command(&command),
+                "record doesn't fit in a block"
+            );
         }
     }
     if !block.is_empty() {
@@ -287,10 +911,22 @@ fn build_tables<I: Iterator<Item = Command>>(mut iter: I, to_dir: &Path) -> Vec<
         new_tables.push(tb.build());
     }
 
+    let written = new_tables.iter().map(|t| t.file_size).sum();
+    COUNTERS.add(&COUNTERS.table_bytes_written, written);
     new_tables
 }
 
-fn compact_in_place(level: &mut DiskLevel) {
+/// Rewrites a command for a table being compacted into: puts that expired turn into
+/// tombstones, which are only kept while a level further down may hold their key
+fn compacted(command: Command, now: u64, bottom: bool) -> Option<Command> {
+    match command.at(now) {
+        Command::Delete(_) if bottom => None,
+        command => Some(command),
+    }
+}
+
+/// `bottom` if no level below `level` holds any table
+fn compact_in_place(level: &mut DiskLevel, bottom: bool) {
     let first_partial_table = level
         .tables
         .iter()
@@ -298,15 +934,19 @@ fn compact_in_place(level: &mut DiskLevel) {
         .unwrap();
     let partial_tables = level.tables.split_off(first_partial_table);
 
+    let now = unix_millis();
     let commands = partial_tables
         .iter()
-        .flat_map(|t| t.iter_commands_from(0, true));
+        .flat_map(|t| t.iter_commands_from(0, true))
+        .filter_map(|command| compacted(command, now, bottom));
 
-    let mut new_tables = build_tables(commands, &level.level_directory);
+    let mut new_tables = build_tables(commands, &level.level_directory, level.options);
     level.tables.append(&mut new_tables);
 }
 
-fn merge(l1: &mut Vec<Table>, l2: &mut DiskLevel) {
+/// Moves or merges every table of `l1` into `l2`, `bottom` if no level below `l2` holds any
+/// table. Tables that don't overlap `l2` are moved as they are.
+fn merge(l1: &mut Vec<Table>, l2: &mut DiskLevel, bottom: bool) {
     let intersections = find_intersections(l1, &l2.tables);
 
     match intersections {
@@ -314,25 +954,41 @@ fn merge(l1: &mut Vec<Table>, l2: &mut DiskLevel) {
             for &idx in indices.iter().rev() {
                 let table = &mut l1[idx];
                 table.rename(&l2.level_directory);
+                if table.filter.kind() != l2.options.filter {
+                    table.rebuild_filter(l2.options.filter);
+                }
                 l2.tables.push(l1.remove(idx));
             }
+
+            // the tables left all overlap `l2`, they have to be merged in as well
+            if !l1.is_empty() {
+                l2.sort_tables();
+                merge(l1, l2, bottom);
+            }
         }
         IntersectionResult::IntersectingGroups(groups) => {
             let mut new_tables = vec![];
 
             for group in groups.iter() {
                 let (slice_start, slice_end) = group.tables1;
-                let l1_commands = (&mut l1[slice_start..slice_end])
+                let l1_commands = l1[slice_start..slice_end]
                     .iter()
                     .flat_map(|t| t.iter_commands_from(0, true));
 
                 let (slice_start, slice_end) = group.tables2;
-                let l2_commands = (&mut l2.tables[slice_start..slice_end])
+                let l2_commands = l2.tables[slice_start..slice_end]
                     .iter()
                     .flat_map(|t| t.iter_commands_from(0, true));
 
-                let merge_commands_iter = merge_sorted_commands(l1_commands, l2_commands);
-                new_tables.append(&mut build_tables(merge_commands_iter, &l2.level_directory));
+                let now = unix_millis();
+                let merge_commands_iter =
+                    merge_sorted_commands(l1_commands, l2_commands, l2.options.merge_operator)
+                        .filter_map(|command| compacted(command, now, bottom));
+                new_tables.append(&mut build_tables(
+                    merge_commands_iter,
+                    &l2.level_directory,
+                    l2.options,
+                ));
             }
 
             for idx in groups.iter().flat_map(|g| g.tables1.0..g.tables1.1).rev() {
@@ -423,3 +1079,98 @@ fn find_intersections<'a>(tables_l1: &'a [Table], tables_l2: &'a [Table]) -> Int
         IntersectionResult::IntersectingGroups(intersecting_groups)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn open(dir: &tempfile::TempDir) -> Database<i32, i32> {
+        Database::new(dir.path().to_owned(), [TableOptions::default(); NUM_LEVELS])
+    }
+
+    async fn flush(db: &Database<i32, i32>) {
+        db.flush(db.memory.write().await).await;
+    }
+
+    #[tokio::test]
+    async fn read_modify_writes_see_values_on_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.insert(1, 10).await;
+        db.insert(2, 20).await;
+        db.insert(3, 30).await;
+        flush(&db).await;
+
+        assert_eq!(db.compare_and_set(1, Some(&10), 11).await, Ok(()));
+        assert_eq!(db.compare_and_set(1, Some(&10), 12).await, Err(Some(11)));
+        assert_eq!(db.compare_and_set(4, None, 40).await, Ok(()));
+        assert_eq!(db.get_set(2, 21).await, Some(20));
+        assert_eq!(db.get_delete(3).await, Some(30));
+        assert_eq!(db.get_delete(3).await, None);
+        assert_eq!(db.get(&1).await, Some(11));
+        assert_eq!(db.get(&2).await, Some(21));
+        assert_eq!(db.get(&4).await, Some(40));
+    }
+
+    #[tokio::test]
+    async fn writers_go_ahead_while_read_modify_writes_read_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Arc::new(open(&dir));
+        db.insert(1, 10).await;
+        flush(&db).await;
+
+        // stalls every disk read until dropped
+        let level = db.disk[0].clone().write_owned().await;
+        let cas = tokio::spawn({
+            let db = db.clone();
+            async move { db.compare_and_set(1, Some(&10), 11).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        tokio::time::timeout(Duration::from_secs(1), db.insert(2, 20))
+            .await
+            .expect("writer stalled behind a disk read");
+        // the value the compare and set is reading is overwritten before it gets the lock back
+        db.insert(1, 12).await;
+        drop(level);
+
+        assert_eq!(cas.await.unwrap(), Err(Some(12)));
+        assert_eq!(db.get(&1).await, Some(12));
+    }
+
+    #[tokio::test]
+    async fn largest_expiring_records_fit_in_a_block() {
+        use crate::config::{MAX_KEY_BYTES, MAX_VALUE_BYTES};
+
+        let dir = tempfile::tempdir().unwrap();
+        let db: Database =
+            Database::new(dir.path().to_owned(), [TableOptions::default(); NUM_LEVELS]);
+        let keys: Vec<Bytes> = (0..3u8)
+            .map(|byte| vec![byte; MAX_KEY_BYTES].into())
+            .collect();
+        let val = Bytes::from(vec![7; MAX_VALUE_BYTES]);
+        for key in &keys {
+            db.insert_with_ttl(key.clone(), val.clone(), Duration::from_secs(3600))
+                .await;
+        }
+        db.flush(db.memory.write().await).await;
+
+        for key in &keys {
+            assert_eq!(db.get(key).await, Some(val.clone()));
+        }
+    }
+
+    #[tokio::test]
+    async fn ttls_past_the_end_of_the_clock_never_expire() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        // a whole multiple of 2^64 milliseconds
+        db.insert_with_ttl(1, 10, Duration::from_secs(1 << 61))
+            .await;
+        db.insert_with_ttl(2, 20, Duration::MAX).await;
+        assert_eq!(db.get(&1).await, Some(10));
+        assert_eq!(db.get(&2).await, Some(20));
+    }
+}
@@ -0,0 +1,136 @@
+use bytes::Bytes;
+use fixedbitset::FixedBitSet;
+
+use super::filter::{hash_key, key_as_int};
+use crate::config::{RANGE_FILTER_BUCKET_BITS, RANGE_FILTER_MAX_PROBES};
+
+const BITS_PER_BUCKET: usize = 10;
+const NUM_HASHES: u64 = 3;
+
+/// Keys of 8 bytes or more are bucketed by their first 8 bytes
+const LONG_KEYS: usize = 8;
+
+/// Prefix bloom filter: a bloom filter over the buckets of `2^RANGE_FILTER_BUCKET_BITS`
+/// consecutive keys that hold at least one key of the table. A short range can be ruled out
+/// by probing every bucket it overlaps, long ranges are always reported as maybe present.
+///
+/// Keys are read as integers, which only orders keys of the same length the same way the
+/// bytes do, so the filter can only rule out ranges over tables whose keys are all as long
+/// as the range bounds (like the i32 keys of the original protocol).
+#[derive(Debug)]
+pub struct RangeFilter {
+    bits: FixedBitSet,
+    lengths: u16, // bit n set if the table has a key of `length_class` n
+}
+
+impl RangeFilter {
+    /// `keys` must be sorted
+    pub fn new(keys: &[Bytes]) -> Self {
+        let mut lengths = 0;
+        let mut buckets: Vec<(usize, u64)> = keys
+            .iter()
+            .map(|k| {
+                let class = length_class(k);
+                lengths |= 1 << class;
+                (class, bucket(k))
+            })
+            .collect();
+        buckets.sort_unstable();
+        buckets.dedup();
+
+        let mut res = Self {
+            bits: FixedBitSet::with_capacity((buckets.len() * BITS_PER_BUCKET).max(64)),
+            lengths,
+        };
+        for (_, bucket) in buckets {
+            for idx in indices(bucket, res.bits.len()) {
+                res.bits.insert(idx);
+            }
+        }
+        res
+    }
+
+    /// `false` only if the table has no key in `[min_key, max_key)`, no `max_key` means
+    /// no upper bound
+    pub fn may_intersect(&self, min_key: &[u8], max_key: Option<&[u8]>) -> bool {
+        let Some(max_key) = max_key else {
+            return true;
+        };
+        if min_key >= max_key {
+            return false;
+        }
+
+        let class = length_class(min_key);
+        if length_class(max_key) != class || self.lengths != 1 << class {
+            // keys of other lengths sort in between
+            return true;
+        }
+
+        let first = bucket(min_key);
+        let last = if class == LONG_KEYS {
+            // the prefix of the exclusive bound can still be shared by keys in range
+            bucket(max_key)
+        } else {
+            (key_as_int(max_key).unwrap() - 1) >> RANGE_FILTER_BUCKET_BITS
+        };
+        if last - first >= RANGE_FILTER_MAX_PROBES as u64 {
+            return true;
+        }
+
+        (first..=last).any(|bucket| indices(bucket, self.bits.len()).all(|idx| self.bits[idx]))
+    }
+
+    pub fn size_bytes(&self) -> usize {
+        self.bits.len() / 8
+    }
+}
+
+/// Double hashing, see Kirsch & Mitzenmacher
+fn indices(bucket: u64, len: usize) -> impl Iterator<Item = usize> {
+    let hash = hash_key(&bucket.to_le_bytes(), 0);
+    let (h1, h2) = (hash as u32 as u64, hash >> 32);
+    (0..NUM_HASHES).map(move |i| (h1.wrapping_add(i * h2) % len as u64) as usize)
+}
+
+fn length_class(key: &[u8]) -> usize {
+    key.len().min(LONG_KEYS)
+}
+
+/// Order preserving among keys of the same `length_class`
+fn bucket(key: &[u8]) -> u64 {
+    key_as_int(&key[..key.len().min(LONG_KEYS)]).unwrap() >> RANGE_FILTER_BUCKET_BITS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(keys: &[&[u8]]) -> Vec<Bytes> {
+        keys.iter().map(|k| Bytes::copy_from_slice(k)).collect()
+    }
+
+    #[test]
+    fn ranges_holding_a_key_intersect() {
+        let filter = RangeFilter::new(&keys(&[&[0, 0, 1, 0], &[0, 0, 9, 0], &[0, 1, 0, 0]]));
+        assert!(filter.may_intersect(&[0, 0, 1, 0], Some(&[0, 0, 1, 1])));
+        assert!(filter.may_intersect(&[0, 0, 8, 0], Some(&[0, 0, 10, 0])));
+        assert!(filter.may_intersect(&[0, 0, 0, 0], None));
+    }
+
+    #[test]
+    fn reversed_or_empty_ranges_dont_intersect() {
+        let short = RangeFilter::new(&keys(&[&[0, 0, 1, 0]]));
+        assert!(!short.may_intersect(&[0, 0, 2, 0], Some(&[0, 0, 1, 0])));
+        assert!(!short.may_intersect(&[0, 0, 1, 0], Some(&[0, 0, 1, 0])));
+
+        let long = RangeFilter::new(&keys(&[b"key-0001-a", b"key-0002-a"]));
+        assert!(!long.may_intersect(b"key-0002-b", Some(b"key-0001-b")));
+        assert!(long.may_intersect(b"key-0001-0", Some(b"key-0001-z")));
+    }
+
+    #[test]
+    fn keys_of_other_lengths_are_never_ruled_out() {
+        let filter = RangeFilter::new(&keys(&[&[0, 0, 1, 0], &[0, 5]]));
+        assert!(filter.may_intersect(&[0, 0, 7, 0], Some(&[0, 0, 7, 1])));
+    }
+}
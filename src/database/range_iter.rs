@@ -0,0 +1,121 @@
+use std::{marker::PhantomData, path::PathBuf};
+
+use bytes::Bytes;
+use tokio::sync::OwnedRwLockReadGuard;
+
+use super::{
+    codec::{decode_stored, Codec},
+    disk_level::DiskLevel,
+    merge_iter::merge_sorted_commands,
+    merge_operator::MergeOperator,
+    table::{unix_millis, Command, TableView},
+};
+
+type Commands = Box<dyn Iterator<Item = Command> + Send>;
+
+/// Ascending `(key, value)` pairs of `[min_key, max_key)`, merged lazily from a copy of the
+/// memtable range and every disk level. Holds a read lock on every level until dropped
+/// so compactions can't delete the tables being streamed.
+pub struct RangeIter<K = Bytes, V = Bytes> {
+    _levels: Vec<OwnedRwLockReadGuard<DiskLevel>>,
+    commands: Commands,
+    max_key: Option<Bytes>,
+    /// Expiries are checked against the time the range was started
+    now: u64,
+    _codec: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K: Codec, V: Codec> RangeIter<K, V> {
+    pub fn new(
+        mem_commands: Vec<Command>,
+        levels: Vec<OwnedRwLockReadGuard<DiskLevel>>,
+        min_key: Bytes,
+        max_key: Option<Bytes>,
+        operator: MergeOperator,
+    ) -> Self {
+        // fold from the oldest level up so newer commands shadow older ones on equal keys
+        let mut commands: Commands = Box::new(std::iter::empty());
+        for level in levels.iter().rev() {
+            let min = min_key.clone();
+            let level_commands = level_commands(level_tables(level, &min_key, max_key.as_deref()))
+                .skip_while(move |c| *c.key() < min);
+            commands = Box::new(merge_sorted_commands(level_commands, commands, operator));
+        }
+        commands = Box::new(merge_sorted_commands(
+            mem_commands.into_iter(),
+            commands,
+            operator,
+        ));
+
+        Self {
+            _levels: levels,
+            commands,
+            max_key,
+            now: unix_millis(),
+            _codec: PhantomData,
+        }
+    }
+}
+
+impl<K: Codec, V: Codec> Iterator for RangeIter<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let command = self.commands.next()?;
+            if self
+                .max_key
+                .as_ref()
+                .is_some_and(|max| command.key() >= max)
+            {
+                return None;
+            }
+
+            // a merge left over has nothing below it, its operand is the value
+            if let Command::Put(key, val)
+            | Command::Merge(key, val)
+            | Command::Expiring(key, val, _) = command.at(self.now)
+            {
+                return Some((decode_stored(key), decode_stored(val)));
+            }
+        }
+    }
+}
+
+type TableStart = (PathBuf, Option<Bytes>, usize);
+
+/// Paths (and mappings) of the tables intersecting the range, with the block to start reading
+/// them from. Tables whose range filter rules out the range are skipped entirely.
+fn level_tables(level: &DiskLevel, min_key: &[u8], max_key: Option<&[u8]>) -> Vec<TableStart> {
+    let Some(locate_min) = level.locate_nearest(min_key) else {
+        return vec![];
+    };
+
+    level.tables[locate_min.table_index..]
+        .iter()
+        .take_while(|t| max_key.is_none_or(|max| &t.min_key[..] < max))
+        .enumerate()
+        .filter(|(_, t)| {
+            t.range_filter
+                .as_ref()
+                .is_none_or(|f| f.may_intersect(min_key, max_key))
+        })
+        .map(|(idx, t)| {
+            let block_index = if idx == 0 { locate_min.block_index } else { 0 };
+            (t.file_path(), t.mmap.clone(), block_index)
+        })
+        .collect()
+}
+
+/// Opens each table only once the previous one is exhausted
+fn level_commands(tables: Vec<TableStart>) -> impl Iterator<Item = Command> + Send {
+    tables
+        .into_iter()
+        .flat_map(|(file_path, mmap, block_index)| {
+            let view = match mmap {
+                Some(mmap) => TableView::mapped(file_path, mmap, block_index),
+                None => TableView::new(file_path, block_index),
+            };
+            view.flat_map(|b| b.iter())
+        })
+}
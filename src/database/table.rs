@@ -1,28 +1,40 @@
-use crate::config::{BLOCK_SIZE_BYTES, BLOOM_CAPACITY, MAX_FILE_SIZE_BLOCKS};
+use crate::config::{BLOCK_SIZE_BYTES, MAX_FILE_SIZE_BLOCKS};
 
-use super::bloom::Bloom;
+use super::filter::{FilterKind, PointFilter};
+use super::learned_index::LearnedIndex;
+use super::merge_operator::MergeOperator;
 use super::once_done::OnceDoneTrait;
-use bytes::{Buf, BufMut, BytesMut};
+use super::range_filter::RangeFilter;
+use bytes::{BufMut, Bytes, BytesMut};
+use memmap2::Mmap;
 use std::cmp::Ordering;
 use std::fmt::Debug;
 use std::os::unix::fs::FileExt;
+use std::sync::atomic::{self, AtomicU64};
 use std::{
     fs::{self, File},
-    io::{Cursor, Write},
+    io::Write,
     path::{Path, PathBuf},
     time::SystemTime,
 };
 
+// tag, key length and value length, same for merges
+const PUT_HEADER_BYTES: usize = 5;
+// tag and key length
+const DELETE_HEADER_BYTES: usize = 3;
+// tag, key length, value length and expiry
+const EXPIRING_HEADER_BYTES: usize = 13;
+
 pub struct BlockMut {
     pub commands: BytesMut,
-    pub keys: Vec<i32>,
+    pub keys: Vec<Bytes>,
 }
 
 impl BlockMut {
     pub fn new() -> Self {
         Self {
             commands: BytesMut::with_capacity(BLOCK_SIZE_BYTES),
-            keys: Vec::with_capacity(BLOCK_SIZE_BYTES >> 2),
+            keys: Vec::with_capacity(BLOCK_SIZE_BYTES / (PUT_HEADER_BYTES + 8)),
         }
     }
 
@@ -35,11 +47,9 @@ impl BlockMut {
         self.keys.clear();
     }
 
-    pub fn push_command(&mut self, command: Command) -> bool {
-        let bytes_to_write = match command {
-            Command::Delete(..) => 5,
-            Command::Put(..) => 9,
-        };
+    /// Appends the record of `command`, or pads the block and returns false when it doesn't fit
+    pub fn push_command(&mut self, command: &Command) -> bool {
+        let bytes_to_write = command.encoded_len();
 
         if self.commands.len() + bytes_to_write > self.commands.capacity() {
             let remaining_space = self.commands.capacity() - self.commands.len();
@@ -52,91 +62,163 @@ impl BlockMut {
             return false;
         }
 
-        match command {
-            Command::Delete(key) => {
-                self.commands.put_u8(1);
-                self.commands.put_i32(key);
-                self.keys.push(key);
-            }
-            Command::Put(key, val) => {
-                self.commands.put_u8(0);
-                self.commands.put_i32(key);
-                self.commands.put_i32(val);
-                self.keys.push(key);
-            }
-        }
+        command.encode_to(&mut self.commands);
+        self.keys.push(command.key().clone());
         true
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+impl Default for BlockMut {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Command {
-    Delete(i32),
-    Put(i32, i32),
+    Delete(Bytes),
+    Put(Bytes, Bytes),
+    /// Operand for the [`MergeOperator`](super::merge_operator::MergeOperator) to fold into
+    /// the value below it
+    Merge(Bytes, Bytes),
+    /// Put that reads as a delete from its expiry on, in milliseconds since the unix epoch
+    Expiring(Bytes, Bytes, u64),
 }
 
 impl Command {
-    pub fn key(&self) -> i32 {
+    pub fn key(&self) -> &Bytes {
         match self {
-            &Self::Delete(key) => key,
-            &Self::Put(key, ..) => key,
+            Self::Delete(key) => key,
+            Self::Put(key, ..) | Self::Merge(key, ..) | Self::Expiring(key, ..) => key,
         }
     }
 
-    pub fn value(&self) -> Option<i32> {
+    pub fn value(&self) -> Option<&Bytes> {
         match self {
             Self::Delete(_) => None,
-            &Self::Put(_, val) => Some(val),
+            Self::Put(_, val) | Self::Merge(_, val) | Self::Expiring(_, val, _) => Some(val),
         }
     }
+
+    /// Deletes, and puts that expired by `now` (see [`unix_millis`])
+    pub fn is_deleted_at(&self, now: u64) -> bool {
+        match self {
+            Self::Delete(_) => true,
+            Self::Expiring(.., expires_at) => *expires_at <= now,
+            _ => false,
+        }
+    }
+
+    /// The command as it reads at `now`, puts that expired turn into deletes
+    pub fn at(self, now: u64) -> Self {
+        if self.is_deleted_at(now) {
+            Self::Delete(self.key().clone())
+        } else {
+            self
+        }
+    }
+
+    /// Records are a tag followed by the big endian u16 lengths of the key (and value for puts
+    /// and merges, then the u64 expiry for expiring puts) and then the bytes themselves
+    pub fn encode_to(&self, buf: &mut impl BufMut) {
+        match self {
+            Self::Delete(key) => {
+                buf.put_u8(1);
+                buf.put_u16(key.len() as u16);
+                buf.put_slice(key);
+            }
+            Self::Put(key, val) | Self::Merge(key, val) => {
+                let tag = match self {
+                    Self::Merge(..) => 2,
+                    _ => 0,
+                };
+                buf.put_u8(tag);
+                buf.put_u16(key.len() as u16);
+                buf.put_u16(val.len() as u16);
+                buf.put_slice(key);
+                buf.put_slice(val);
+            }
+            Self::Expiring(key, val, expires_at) => {
+                buf.put_u8(3);
+                buf.put_u16(key.len() as u16);
+                buf.put_u16(val.len() as u16);
+                buf.put_u64(*expires_at);
+                buf.put_slice(key);
+                buf.put_slice(val);
+            }
+        }
+    }
+
+    /// Size of the record in a block
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            Self::Delete(key) => DELETE_HEADER_BYTES + key.len(),
+            Self::Put(key, val) | Self::Merge(key, val) => PUT_HEADER_BYTES + key.len() + val.len(),
+            Self::Expiring(key, val, _) => EXPIRING_HEADER_BYTES + key.len() + val.len(),
+        }
+    }
+}
+
+/// Current time in milliseconds since the unix epoch, the clock record expiries are on
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// How tables of a level are indexed, set per level in `Config`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TableOptions {
+    pub filter: FilterKind,
+    pub learned_index: bool,
+    pub range_filter: bool,
+    /// Serve reads from a shared memory mapping of the table file instead of `pread`
+    pub mmap: bool,
+    /// Combines merge records, the same for every level of a database
+    pub merge_operator: MergeOperator,
+    /// Maintain a secondary index from values to keys, read from the first level like the
+    /// merge operator
+    pub value_index: bool,
+    /// Sync the write ahead log after every batch, read from the first level as well
+    pub sync_wal: bool,
 }
 
 pub struct TableBuilder {
     pub directory: PathBuf,
-    pub file_path: PathBuf,
+    pub id: u64,
     pub file: File,
-    pub min_key: Option<i32>,
-    pub max_key: Option<i32>,
-    pub bloom: Bloom,
-    pub index: Vec<(i32, i32)>, // min/max key for each block in file
+    pub options: TableOptions,
+    pub keys: Vec<Bytes>, // filters are built once all keys are known
+    pub block_lens: Vec<u32>,
+    pub index: Vec<(Bytes, Bytes)>, // min/max key for each block in file
 }
 
 impl TableBuilder {
-    pub fn new(directory: &Path) -> Self {
-        let tmp_file_name = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_nanos()
-            .to_string();
-
-        let file_path = directory.join(tmp_file_name);
-        let file = File::create_new(&file_path).unwrap();
+    pub fn new(directory: &Path, options: TableOptions) -> Self {
+        let id = next_table_id();
+        let file = File::create_new(directory.join(id.to_string())).unwrap();
         Self {
             directory: directory.to_path_buf(),
-            min_key: None,
-            max_key: None,
-            bloom: Bloom::new(BLOOM_CAPACITY),
+            id,
+            options,
+            keys: vec![],
+            block_lens: Vec::with_capacity(MAX_FILE_SIZE_BLOCKS),
             index: Vec::with_capacity(MAX_FILE_SIZE_BLOCKS),
             file,
-            file_path,
         }
     }
 
     pub fn insert_block(&mut self, block: &BlockMut) {
-        let min = *block.keys.first().unwrap();
-        let max = *block.keys.last().unwrap();
-
-        if self.min_key.is_none() {
-            self.min_key = Some(min);
-        }
-        self.max_key = Some(max);
+        // copied so the fences don't keep the blocks the keys were read from alive
+        let min = Bytes::copy_from_slice(block.keys.first().unwrap());
+        let max = Bytes::copy_from_slice(block.keys.last().unwrap());
 
         self.file.write_all(&block.commands).unwrap();
         self.index.push((min, max));
 
-        for &key in block.keys.iter() {
-            self.bloom.put(key);
-        }
+        self.keys.extend_from_slice(&block.keys);
+        self.block_lens.push(block.keys.len() as u32);
     }
 
     pub fn full(&self) -> bool {
@@ -148,44 +230,76 @@ impl TableBuilder {
     }
 
     pub fn build(self) -> Table {
-        let new_path = self.directory.join(format!(
-            "{}:{}",
-            self.min_key.unwrap(),
-            self.max_key.unwrap()
-        ));
-        fs::rename(&self.file_path, &new_path).unwrap();
-
-        let file_size = fs::metadata(&new_path).unwrap().len();
+        let file_path = self.directory.join(self.id.to_string());
+        let file_size = fs::metadata(&file_path).unwrap().len();
+        let mmap = self.options.mmap.then(|| map_file(&file_path));
 
         Table {
             directory: self.directory,
-            min_key: self.min_key.unwrap(),
-            max_key: self.max_key.unwrap(),
+            id: self.id,
+            min_key: self.index.first().unwrap().0.clone(),
+            max_key: self.index.last().unwrap().1.clone(),
             file_size,
-            bloom: self.bloom,
+            filter: self.options.filter.build(&self.keys),
+            learned: self
+                .options
+                .learned_index
+                .then(|| LearnedIndex::new(&self.keys, &self.block_lens))
+                .flatten(),
+            range_filter: self
+                .options
+                .range_filter
+                .then(|| RangeFilter::new(&self.keys)),
+            mmap,
             index: self.index,
         }
     }
 }
 
+/// Keys can't be put in file names, so tables (and write ahead logs) are named by increasing ids
+pub(super) fn next_table_id() -> u64 {
+    static LAST_ID: AtomicU64 = AtomicU64::new(0);
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64;
+    // strictly increasing even if two tables are created within the clock's resolution
+    let last = LAST_ID
+        .fetch_update(
+            atomic::Ordering::Relaxed,
+            atomic::Ordering::Relaxed,
+            |last| Some(now.max(last + 1)),
+        )
+        .unwrap();
+    now.max(last + 1)
+}
+
 #[derive(Debug)]
 pub struct Table {
     pub directory: PathBuf,
-    // file name = "{min_key}-{max_key}"
-    pub min_key: i32,
-    pub max_key: i32,
+    // file name = "{id}"
+    pub id: u64,
+    pub min_key: Bytes,
+    pub max_key: Bytes,
     pub file_size: u64,
-    pub bloom: Bloom,
-    pub index: Vec<(i32, i32)>, // min/max key for each block in file
+    pub filter: Box<dyn PointFilter>,
+    pub learned: Option<LearnedIndex>,
+    pub range_filter: Option<RangeFilter>,
+    pub mmap: Option<Bytes>,
+    pub index: Vec<(Bytes, Bytes)>, // min/max key for each block in file
 }
 
 impl Table {
     pub fn view(&self) -> TableView {
-        TableView::new(self.file_path(), 0)
+        self.view_from(0)
     }
 
     pub fn view_from(&self, block_index: usize) -> TableView {
-        TableView::new(self.file_path(), block_index)
+        match &self.mmap {
+            Some(mmap) => TableView::mapped(self.file_path(), mmap.clone(), block_index),
+            None => TableView::new(self.file_path(), block_index),
+        }
     }
 
     pub fn iter_commands_from(
@@ -199,7 +313,7 @@ impl Table {
                     v.delete_file()
                 }
             })
-            .flat_map(|b| unsafe { b.as_ref().unwrap().iter() })
+            .flat_map(|b| b.iter())
     }
 
     pub fn intersects(&self, other: &Table) -> Ordering {
@@ -217,7 +331,7 @@ impl Table {
     }
 
     pub fn file_name(&self) -> String {
-        format!("{}:{}", self.min_key, self.max_key)
+        self.id.to_string()
     }
 
     pub fn rename(&mut self, to_dir: &Path) {
@@ -228,95 +342,158 @@ impl Table {
         fs::rename(old_file_path, new_file_path).unwrap();
     }
 
-    pub fn create_from_existing(file_path: &Path) -> Self {
-        let file_name = file_path.file_name().unwrap().to_str().unwrap();
-        let (min_key_str, max_key_str) = file_name
-            .split_once(':')
-            .expect("File name was tampered with...");
+    /// Rebuilds the filter from the keys on disk, used when a table moves to a level
+    /// configured with a different filter
+    pub fn rebuild_filter(&mut self, filter: FilterKind) {
+        let keys: Vec<Bytes> = self
+            .iter_commands_from(0, false)
+            .map(|c| c.key().clone())
+            .collect();
+        self.filter = filter.build(&keys);
+    }
 
-        let min_key: i32 = min_key_str.parse().expect("File name was tampered with...");
-        let max_key: i32 = max_key_str.parse().expect("File name was tampered with...");
+    pub fn create_from_existing(file_path: &Path, options: TableOptions) -> Self {
+        let file_name = file_path.file_name().unwrap().to_str().unwrap();
+        let id: u64 = file_name.parse().expect("File name was tampered with...");
 
         let directory = file_path.parent().unwrap().to_owned();
 
-        let mut bloom = Bloom::new(BLOOM_CAPACITY);
+        let mut keys = vec![];
 
         let file_size = fs::metadata(file_path).unwrap().len();
         let block_count = file_size.div_ceil(BLOCK_SIZE_BYTES as u64);
 
         let mut index = Vec::with_capacity(block_count as usize);
+        let mut block_lens = Vec::with_capacity(block_count as usize);
 
-        let table_view = TableView::new(file_path.to_path_buf(), 0);
-
-        for block_ptr in table_view {
-            let mut block_iter = unsafe { &*block_ptr }.iter();
-
-            let first = block_iter.next().unwrap();
-            let mut last = first;
-            bloom.put(first.key());
+        let mmap = options.mmap.then(|| map_file(file_path));
+        let table_view = match &mmap {
+            Some(mmap) => TableView::mapped(file_path.to_path_buf(), mmap.clone(), 0),
+            None => TableView::new(file_path.to_path_buf(), 0),
+        };
 
-            while let Some(command) = block_iter.next() {
-                last = command;
-                bloom.put(command.key());
-            }
+        for block in table_view {
+            let block_start = keys.len();
+            keys.extend(block.iter().map(|c| c.key().clone()));
 
-            index.push((first.key(), last.key()));
+            let first = Bytes::copy_from_slice(&keys[block_start]);
+            let last = Bytes::copy_from_slice(keys.last().unwrap());
+            index.push((first, last));
+            block_lens.push((keys.len() - block_start) as u32);
         }
 
         Table {
             directory,
-            min_key,
-            max_key,
+            id,
+            min_key: index.first().unwrap().0.clone(),
+            max_key: index.last().unwrap().1.clone(),
             file_size,
-            bloom,
+            filter: options.filter.build(&keys),
+            learned: options
+                .learned_index
+                .then(|| LearnedIndex::new(&keys, &block_lens))
+                .flatten(),
+            range_filter: options.range_filter.then(|| RangeFilter::new(&keys)),
+            mmap,
             index,
         }
     }
 }
 
+/// Tables are written once and only ever renamed or unlinked afterwards, never truncated,
+/// so a mapping stays valid for as long as anyone holds on to it.
+fn map_file(path: &Path) -> Bytes {
+    let file = File::open(path).unwrap();
+    Bytes::from_owner(unsafe { Mmap::map(&file) }.unwrap())
+}
+
+/// One block of a table, sliced out of the table's mapping or read from its file. Records
+/// decoded from it share its buffer.
+#[derive(Clone)]
 pub struct BlockView {
-    buf: [u8; BLOCK_SIZE_BYTES],
+    buf: Bytes,
 }
 
 impl BlockView {
-    pub fn new() -> Self {
-        Self {
-            buf: [0xFF; BLOCK_SIZE_BYTES],
-        }
-    }
-
-    fn as_mut_slice(&mut self) -> &mut [u8] {
-        &mut self.buf
+    pub fn new(buf: Bytes) -> Self {
+        Self { buf }
     }
 
     pub fn iter(&self) -> BlockViewIter {
         BlockViewIter {
-            commands: Cursor::new(&self.buf[..]),
+            buf: self.buf.clone(),
+            pos: 0,
         }
     }
 }
 
-pub struct BlockViewIter<'a> {
-    commands: Cursor<&'a [u8]>,
+pub struct BlockViewIter {
+    buf: Bytes,
+    pos: usize,
+}
+
+impl BlockViewIter {
+    /// Skips `n` records by their headers alone, without decoding them
+    pub fn skip_records(&mut self, n: usize) {
+        for _ in 0..n {
+            match self.buf.get(self.pos) {
+                Some(0 | 2) => {
+                    self.pos += PUT_HEADER_BYTES + self.read_len(1) + self.read_len(3);
+                }
+                Some(1) => self.pos += DELETE_HEADER_BYTES + self.read_len(1),
+                Some(3) => {
+                    self.pos += EXPIRING_HEADER_BYTES + self.read_len(1) + self.read_len(3);
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn read_len(&self, offset: usize) -> usize {
+        let at = self.pos + offset;
+        u16::from_be_bytes([self.buf[at], self.buf[at + 1]]) as usize
+    }
 }
 
-impl<'a> Iterator for BlockViewIter<'a> {
+impl Iterator for BlockViewIter {
     type Item = Command;
 
     fn next(&mut self) -> Option<Command> {
-        if !self.commands.has_remaining() {
-            return None;
-        }
-
-        match self.commands.get_u8() {
-            0 => {
-                let key = self.commands.get_i32();
-                let val = self.commands.get_i32();
-                Some(Command::Put(key, val))
+        let &tag = self.buf.get(self.pos)?;
+
+        match tag {
+            0 | 2 => {
+                let key_len = self.read_len(1);
+                let val_len = self.read_len(3);
+                let key_start = self.pos + PUT_HEADER_BYTES;
+                let val_start = key_start + key_len;
+                self.pos = val_start + val_len;
+
+                let key = self.buf.slice(key_start..val_start);
+                let val = self.buf.slice(val_start..self.pos);
+                Some(if tag == 0 {
+                    Command::Put(key, val)
+                } else {
+                    Command::Merge(key, val)
+                })
             }
             1 => {
-                let key = self.commands.get_i32();
-                Some(Command::Delete(key))
+                let key_start = self.pos + DELETE_HEADER_BYTES;
+                self.pos = key_start + self.read_len(1);
+                Some(Command::Delete(self.buf.slice(key_start..self.pos)))
+            }
+            3 => {
+                let key_len = self.read_len(1);
+                let val_len = self.read_len(3);
+                let at = self.pos + PUT_HEADER_BYTES;
+                let expires_at = u64::from_be_bytes(self.buf[at..at + 8].try_into().unwrap());
+                let key_start = self.pos + EXPIRING_HEADER_BYTES;
+                let val_start = key_start + key_len;
+                self.pos = val_start + val_len;
+
+                let key = self.buf.slice(key_start..val_start);
+                let val = self.buf.slice(val_start..self.pos);
+                Some(Command::Expiring(key, val, expires_at))
             }
             0xFF => {
                 // Fin
@@ -327,45 +504,73 @@ impl<'a> Iterator for BlockViewIter<'a> {
     }
 }
 
+enum TableSource {
+    File(File),
+    Mmap(Bytes),
+}
+
 pub struct TableView {
     file_path: PathBuf,
-    file: File,
-    block_buf: BlockView,
+    source: TableSource,
+    buffered_block: Option<(usize, BlockView)>,
     cur_block: usize,
 }
 
 impl TableView {
     pub fn new(file_path: PathBuf, cur_block: usize) -> Self {
         let file = File::open(&file_path).unwrap();
+        Self::with_source(file_path, TableSource::File(file), cur_block)
+    }
+
+    /// Serves blocks straight out of `mmap` instead of reading them from the file
+    pub fn mapped(file_path: PathBuf, mmap: Bytes, cur_block: usize) -> Self {
+        Self::with_source(file_path, TableSource::Mmap(mmap), cur_block)
+    }
 
+    fn with_source(file_path: PathBuf, source: TableSource, cur_block: usize) -> Self {
         Self {
             file_path,
-            file,
-            block_buf: BlockView::new(),
+            source,
+            buffered_block: None,
             cur_block,
         }
     }
 
-    pub fn get_block_at(&mut self, index: usize) -> Option<&BlockView> {
-        let bytes_read = self
-            .file
-            .read_at(
-                self.block_buf.as_mut_slice(),
-                (index * BLOCK_SIZE_BYTES) as u64,
-            )
+    pub fn get_block_at(&mut self, index: usize) -> Option<BlockView> {
+        let file = match &self.source {
+            TableSource::Mmap(mmap) => {
+                let start = index * BLOCK_SIZE_BYTES;
+                if start >= mmap.len() {
+                    return None;
+                }
+                // the last block of a table isn't padded
+                let end = mmap.len().min(start + BLOCK_SIZE_BYTES);
+                return Some(BlockView::new(mmap.slice(start..end)));
+            }
+            TableSource::File(file) => file,
+        };
+
+        if let Some((buffered_index, block)) = &self.buffered_block {
+            if *buffered_index == index {
+                return Some(block.clone());
+            }
+        }
+
+        let mut buf = BytesMut::zeroed(BLOCK_SIZE_BYTES);
+        let bytes_read = file
+            .read_at(&mut buf, (index * BLOCK_SIZE_BYTES) as u64)
             .unwrap();
 
         if bytes_read == 0 {
+            self.buffered_block = None;
             return None;
         }
 
-        if bytes_read < BLOCK_SIZE_BYTES {
-            // this must be the last page
-            // sentinel of 0xFF
-            self.block_buf.as_mut_slice()[bytes_read] = 0xFF;
-        }
-
-        Some(&self.block_buf)
+        // a short read must be the last block of the table
+        buf.truncate(bytes_read);
+        let block = BlockView::new(buf.freeze());
+        self.buffered_block = Some((index, block.clone()));
+        Some(block)
     }
 
     pub fn delete_file(&self) {
@@ -374,11 +579,10 @@ impl TableView {
 }
 
 impl Iterator for TableView {
-    type Item = *const BlockView;
+    type Item = BlockView;
 
     fn next(&mut self) -> Option<Self::Item> {
         self.cur_block += 1;
         self.get_block_at(self.cur_block - 1)
-            .map(|b| b as *const BlockView)
     }
 }
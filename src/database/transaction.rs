@@ -0,0 +1,254 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+
+use super::{
+    codec::{decode_stored, Codec},
+    table::Command,
+    Database,
+};
+use crate::config::{MAX_KEY_BYTES, MAX_VALUE_BYTES};
+
+/// Sequence numbers of the writes made while transactions are running, so commits can tell
+/// whether the keys they read were overwritten since they began. Only updated with the
+/// memtable write lock held, which orders writes the same way as the memtable. Transactions
+/// begin with the read lock held, so every write before their start is applied already.
+#[derive(Default)]
+pub(super) struct WriteTracker {
+    seq: u64,
+    /// Last write to every key written since the oldest running transaction began
+    last_write: HashMap<Bytes, u64>,
+    /// Start sequence numbers of the running transactions, with how many started there
+    running: BTreeMap<u64, usize>,
+}
+
+impl WriteTracker {
+    pub(super) fn record(&mut self, batch: &[Command]) {
+        self.seq += 1;
+        if self.running.is_empty() {
+            return;
+        }
+        for command in batch {
+            self.last_write.insert(command.key().clone(), self.seq);
+        }
+    }
+
+    fn begin(&mut self) -> u64 {
+        *self.running.entry(self.seq).or_default() += 1;
+        self.seq
+    }
+
+    fn finish(&mut self, start_seq: u64) {
+        let count = self.running.get_mut(&start_seq).unwrap();
+        *count -= 1;
+        if *count > 0 {
+            return;
+        }
+        self.running.remove(&start_seq);
+
+        // writes no running transaction can conflict with anymore
+        match self.running.first_key_value() {
+            None => self.last_write.clear(),
+            Some((&oldest, _)) if oldest > start_seq => {
+                self.last_write.retain(|_, &mut seq| seq > oldest)
+            }
+            Some(_) => {}
+        }
+    }
+
+    fn written_since(&self, key: &[u8], start_seq: u64) -> bool {
+        self.last_write.get(key).is_some_and(|&seq| seq > start_seq)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct TransactionConflict;
+
+impl fmt::Display for TransactionConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("transaction conflict")
+    }
+}
+
+/// Optimistic transaction started by [`Database::begin`]. Reads see the latest committed
+/// values and the transaction's own writes, which are buffered until [`Database::commit`]
+/// applies them as one batch. The commit fails if any key read was written by someone else
+/// after the transaction began. Dropping the transaction aborts it.
+pub struct Transaction<K = Bytes, V = Bytes> {
+    tracker: Arc<Mutex<WriteTracker>>,
+    start_seq: u64,
+    reads: HashSet<Bytes>,
+    writes: BTreeMap<Bytes, Option<Bytes>>,
+    _codec: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K: Codec, V: Codec> Transaction<K, V> {
+    pub(super) fn new(tracker: Arc<Mutex<WriteTracker>>) -> Self {
+        let start_seq = tracker.lock().unwrap().begin();
+        Self {
+            tracker,
+            start_seq,
+            reads: HashSet::new(),
+            writes: BTreeMap::new(),
+            _codec: PhantomData,
+        }
+    }
+
+    /// Reads `key` from `db`, which has to be the database the transaction was started on
+    pub async fn get(&mut self, db: &Database<K, V>, key: &K) -> Option<V> {
+        let key = key.encode();
+        if let Some(val) = self.writes.get(&key) {
+            return val.clone().map(decode_stored);
+        }
+        self.reads.insert(key.clone());
+        db.get_encoded(key).await
+    }
+
+    /// Encoded keys can be up to `MAX_KEY_BYTES` and values up to `MAX_VALUE_BYTES` long
+    pub fn put(&mut self, key: K, value: V) {
+        let (key, value) = (key.encode(), value.encode());
+        assert!(
+            key.len() <= MAX_KEY_BYTES && value.len() <= MAX_VALUE_BYTES,
+            "record doesn't fit in a block"
+        );
+        self.writes.insert(key, Some(value));
+    }
+
+    pub fn delete(&mut self, key: K) {
+        let key = key.encode();
+        assert!(key.len() <= MAX_KEY_BYTES, "record doesn't fit in a block");
+        self.writes.insert(key, None);
+    }
+
+    /// Checks the read set against `tracker`. `locked` is the locked `tracker` of the
+    /// database committing, a transaction committed to another database than it began on
+    /// always conflicts.
+    pub(super) fn validate(
+        &self,
+        tracker: &Arc<Mutex<WriteTracker>>,
+        locked: &WriteTracker,
+    ) -> Result<(), TransactionConflict> {
+        if !Arc::ptr_eq(&self.tracker, tracker)
+            || self
+                .reads
+                .iter()
+                .any(|key| locked.written_since(key, self.start_seq))
+        {
+            return Err(TransactionConflict);
+        }
+        Ok(())
+    }
+
+    /// The buffered writes to apply
+    pub(super) fn commands(&self) -> Vec<Command> {
+        self.writes
+            .iter()
+            .map(|(key, val)| match val {
+                None => Command::Delete(key.clone()),
+                Some(val) => Command::Put(key.clone(), val.clone()),
+            })
+            .collect()
+    }
+}
+
+impl<K, V> Drop for Transaction<K, V> {
+    fn drop(&mut self) {
+        self.tracker.lock().unwrap().finish(self.start_seq);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{config::NUM_LEVELS, database::table::TableOptions};
+
+    fn open(dir: &tempfile::TempDir) -> Database<i32, i32> {
+        Database::new(dir.path().to_owned(), [TableOptions::default(); NUM_LEVELS])
+    }
+
+    #[tokio::test]
+    async fn write_between_read_and_commit_conflicts() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.insert(1, 10).await;
+
+        let mut tx = db.begin().await;
+        let val = tx.get(&db, &1).await.unwrap();
+        db.insert(1, 20).await;
+        tx.put(1, val + 1);
+        assert_eq!(db.commit(tx).await, Err(TransactionConflict));
+        assert_eq!(db.get(&1).await, Some(20));
+    }
+
+    #[tokio::test]
+    async fn unrelated_and_earlier_writes_dont_conflict() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.insert(1, 10).await;
+
+        let mut tx = db.begin().await;
+        let val = tx.get(&db, &1).await.unwrap();
+        // written after the transaction began, but never read by it
+        db.insert(2, 20).await;
+        db.insert(3, 30).await;
+        tx.put(1, val + 1);
+        tx.put(3, 31);
+        assert_eq!(db.commit(tx).await, Ok(()));
+        assert_eq!(db.get(&1).await, Some(11));
+        assert_eq!(db.get(&3).await, Some(31));
+    }
+
+    #[tokio::test]
+    async fn reads_see_own_writes_and_aborts_leave_no_trace() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+
+        let mut tx = db.begin().await;
+        tx.put(1, 10);
+        assert_eq!(tx.get(&db, &1).await, Some(10));
+        tx.delete(1);
+        assert_eq!(tx.get(&db, &1).await, None);
+        tx.put(2, 20);
+        drop(tx);
+        assert_eq!(db.get(&2).await, None);
+        assert!(db.tracker.lock().unwrap().running.is_empty());
+    }
+
+    #[tokio::test]
+    async fn begin_waits_for_recorded_writes_to_apply() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.insert(1, 10).await;
+
+        // a writer between recording its batch and applying it
+        let mem = db.memory.write().await;
+        let batch = vec![Command::Put(1.encode(), 20.encode())];
+        db.tracker.lock().unwrap().record(&batch);
+        assert!(tokio::time::timeout(Duration::from_millis(50), db.begin())
+            .await
+            .is_err());
+        db.apply(mem, batch, None).await;
+
+        let mut tx = db.begin().await;
+        assert_eq!(tx.get(&db, &1).await, Some(20));
+        tx.put(1, 21);
+        assert_eq!(db.commit(tx).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn commits_to_another_database_conflict() {
+        let (dir1, dir2) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (db1, db2) = (open(&dir1), open(&dir2));
+
+        let mut tx = db1.begin().await;
+        tx.put(1, 10);
+        assert_eq!(db2.commit(tx).await, Err(TransactionConflict));
+    }
+}
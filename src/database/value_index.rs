@@ -0,0 +1,282 @@
+use std::{collections::BTreeMap, path::Path};
+
+use bytes::Bytes;
+
+use super::{
+    merge_operator::MergeOperator,
+    run_blocking,
+    table::{Command, TableOptions},
+    Database,
+};
+use crate::config::{MAX_KEY_BYTES, NUM_LEVELS};
+
+const INDEX_DIRECTORY: &str = "index";
+/// Entries checked against the indexed database at a time by range scans
+pub(super) const CHUNK_ENTRIES: usize = 1 << 12;
+
+/// Old value, new value and the expiry of the new value of a key
+type Transition = (Option<Bytes>, Option<Bytes>, Option<u64>);
+
+/// Secondary index from values to the keys holding them, a database of its own in
+/// `<data_dir>/index` keyed by `(value, key)` entries with empty values.
+///
+/// Entries are written under the memtable lock of the indexed database just before the
+/// commands they mirror, so a crash can leave behind entries for writes that never made it
+/// but never lose one. Lookups check every entry against the indexed database anyway, which
+/// also drops entries outliving a merge into an expiring put. Pairs whose entry would be
+/// longer than `MAX_KEY_BYTES` are left out of the index.
+pub(super) struct ValueIndex {
+    db: Database,
+}
+
+impl ValueIndex {
+    pub(super) fn new(
+        data_directory: &Path,
+        mut table_options: [TableOptions; NUM_LEVELS],
+    ) -> Self {
+        for options in table_options.iter_mut() {
+            options.value_index = false;
+        }
+        Self {
+            db: Database::new(data_directory.join(INDEX_DIRECTORY), table_options),
+        }
+    }
+
+    /// Moves the keys written by `commands` from their `current` values (in the order of
+    /// `keys`) to the values the commands leave them with
+    pub(super) async fn update(
+        &self,
+        commands: &[Command],
+        keys: Vec<Bytes>,
+        current: Vec<Option<Bytes>>,
+        operator: MergeOperator,
+    ) {
+        let mut values: BTreeMap<Bytes, Transition> = keys
+            .into_iter()
+            .zip(current)
+            .map(|(key, val)| (key, (val.clone(), val, None)))
+            .collect();
+        for command in commands {
+            let (_, new, expires_at) = values.get_mut(command.key()).unwrap();
+            match command {
+                Command::Put(_, val) => (*new, *expires_at) = (Some(val.clone()), None),
+                Command::Expiring(_, val, at) => {
+                    (*new, *expires_at) = (Some(val.clone()), Some(*at))
+                }
+                Command::Delete(_) => (*new, *expires_at) = (None, None),
+                Command::Merge(_, operand) => {
+                    *new = Some(match new {
+                        Some(val) => operator.merge(val, operand),
+                        None => operand.clone(),
+                    });
+                }
+            }
+        }
+
+        let mut entries = vec![];
+        for (key, (old, new, expires_at)) in values {
+            if let Some(old) = old.filter(|old| new.as_ref() != Some(old)) {
+                entries.extend(entry_key(&old, &key).map(Command::Delete));
+            }
+            // written even when unchanged, the entry of an expiring value may have to outlive it
+            if let Some(entry) = new.and_then(|new| entry_key(&new, &key)) {
+                entries.push(match expires_at {
+                    Some(at) => Command::Expiring(entry, Bytes::new(), at),
+                    None => Command::Put(entry, Bytes::new()),
+                });
+            }
+        }
+        if entries.is_empty() {
+            return;
+        }
+
+        let mut mem_write = self.db.memory.write().await;
+        mem_write.write(entries);
+        if mem_write.is_full() {
+            self.db.flush(mem_write).await;
+        }
+    }
+
+    /// Up to `limit` `(key, value)` pairs from the entries at or after `from` and before
+    /// `end`, in entry order. The index levels are only locked while reading them.
+    pub(super) async fn entries(
+        &self,
+        from: Bytes,
+        end: Option<Bytes>,
+        limit: usize,
+    ) -> Vec<(Bytes, Bytes)> {
+        let entries = self.db.range(&from, end.as_ref()).await;
+        run_blocking(move || {
+            entries
+                .take(limit)
+                .map(|(entry, _)| split_entry(&entry))
+                .collect()
+        })
+        .await
+    }
+
+    pub(super) fn cleanup(self) {
+        self.db.cleanup();
+    }
+}
+
+/// `value` with its zero bytes escaped as `00 FF` and terminated by `00 00`, so entries sort by
+/// value and then key, and the entries of one value share this prefix
+pub(super) fn value_prefix(value: &[u8]) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(value.len() + 2);
+    for &byte in value {
+        prefix.push(byte);
+        if byte == 0 {
+            prefix.push(0xFF);
+        }
+    }
+    prefix.extend_from_slice(&[0, 0]);
+    prefix
+}
+
+/// First entry past every entry of `value` and of the values sorting before it
+pub(super) fn value_end(value: &[u8]) -> Vec<u8> {
+    let mut end = value_prefix(value);
+    *end.last_mut().unwrap() = 1;
+    end
+}
+
+/// First entry past the one of `value` and `key`
+pub(super) fn entry_after(value: &[u8], key: &[u8]) -> Bytes {
+    let mut entry = value_prefix(value);
+    entry.extend_from_slice(key);
+    entry.push(0);
+    entry.into()
+}
+
+fn entry_key(value: &[u8], key: &[u8]) -> Option<Bytes> {
+    let mut entry = value_prefix(value);
+    entry.extend_from_slice(key);
+    (entry.len() <= MAX_KEY_BYTES).then(|| entry.into())
+}
+
+/// The key and value an entry was made of
+fn split_entry(entry: &[u8]) -> (Bytes, Bytes) {
+    let mut value = Vec::with_capacity(entry.len());
+    let mut idx = 0;
+    loop {
+        let (byte, len) = match entry[idx..] {
+            [0, 0, ..] => break,
+            // an escaped zero
+            [0, ..] => (0, 2),
+            [byte, ..] => (byte, 1),
+            [] => unreachable!("index entry without a terminator"),
+        };
+        value.push(byte);
+        idx += len;
+    }
+    (Bytes::copy_from_slice(&entry[idx + 2..]), value.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::*;
+
+    const SAMPLES: [&[u8]; 6] = [b"", b"\0", b"\0\0", b"a\0b", b"\xFF", b"\xFF\0"];
+
+    #[test]
+    fn entries_split_into_their_key_and_value() {
+        for value in SAMPLES {
+            for key in SAMPLES {
+                let entry = entry_key(value, key).unwrap();
+                assert_eq!(split_entry(&entry), (Bytes::from(key), Bytes::from(value)));
+            }
+        }
+    }
+
+    #[test]
+    fn entries_sort_by_value_then_key() {
+        let mut pairs: Vec<(&[u8], &[u8])> = vec![];
+        for value in SAMPLES {
+            for key in SAMPLES {
+                pairs.push((value, key));
+            }
+        }
+        pairs.sort();
+        for pair in pairs.windows(2) {
+            let ((v1, k1), (v2, k2)) = (pair[0], pair[1]);
+            assert!(entry_key(v1, k1) < entry_key(v2, k2));
+            assert!(entry_key(v1, k1).unwrap() < entry_after(v1, k1));
+            assert!(entry_after(v1, k1) <= entry_key(v2, k2).unwrap());
+        }
+        for value in SAMPLES {
+            for key in SAMPLES {
+                let entry = entry_key(value, key).unwrap();
+                assert!(entry[..] < value_end(value)[..]);
+                assert!(entry.starts_with(&value_prefix(value)));
+            }
+        }
+    }
+
+    fn open(dir: &tempfile::TempDir) -> Database<i32, i32> {
+        let mut options = [TableOptions::default(); NUM_LEVELS];
+        options[0].value_index = true;
+        Database::new(dir.path().to_owned(), options)
+    }
+
+    async fn keys_with_value(db: &Database<i32, i32>, value: i32) -> Vec<i32> {
+        db.keys_with_value(&value).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn writes_move_entries_of_values_on_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.insert(1, 10).await;
+        db.insert(2, 10).await;
+        db.insert(3, 30).await;
+        db.flush(db.memory.write().await).await;
+
+        db.insert(1, 30).await;
+        db.delete(3).await;
+        assert_eq!(db.get_set(2, 20).await, Some(10));
+        assert_eq!(keys_with_value(&db, 10).await, Vec::<i32>::new());
+        assert_eq!(keys_with_value(&db, 20).await, vec![2]);
+        assert_eq!(keys_with_value(&db, 30).await, vec![1]);
+    }
+
+    #[tokio::test]
+    async fn indexed_writers_go_ahead_while_others_read_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Arc::new(open(&dir));
+        db.insert(1, 10).await;
+        db.flush(db.memory.write().await).await;
+        db.insert(2, 20).await;
+
+        // stalls every disk read until dropped
+        let level = db.disk[0].clone().write_owned().await;
+        let puts: Vec<_> = [11, 12]
+            .into_iter()
+            .map(|val| {
+                let db = db.clone();
+                tokio::spawn(async move { db.insert(1, val).await })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        // the memtable settles the value this one replaces
+        tokio::time::timeout(Duration::from_secs(1), db.insert(2, 21))
+            .await
+            .expect("writer stalled behind a disk read");
+        drop(level);
+        for put in puts {
+            put.await.unwrap();
+        }
+
+        // whichever put came second replaced the entry of the first one
+        let val = db.get(&1).await.unwrap();
+        for other in [10, 11, 12].into_iter().filter(|&other| other != val) {
+            assert_eq!(keys_with_value(&db, other).await, Vec::<i32>::new());
+        }
+        assert_eq!(keys_with_value(&db, val).await, vec![1]);
+        assert_eq!(keys_with_value(&db, 20).await, Vec::<i32>::new());
+        assert_eq!(keys_with_value(&db, 21).await, vec![2]);
+    }
+}
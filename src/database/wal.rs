@@ -0,0 +1,249 @@
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use bytes::{BufMut, Bytes};
+
+use super::{
+    filter::hash_key,
+    table::{next_table_id, BlockView, Command},
+};
+
+const WAL_DIRECTORY: &str = "wal";
+// payload length and checksum
+const RECORD_HEADER_BYTES: usize = 8;
+const CHECKSUM_SEED: u64 = 0x5741_4c00;
+
+/// Write ahead log of the memtable. Every write batch is appended as one record, a u32
+/// payload length and checksum followed by the batch's commands encoded like in blocks, so a
+/// batch is either replayed whole or not at all.
+///
+/// Unless `sync` is set, records go to the page cache without an fsync, they survive the
+/// process crashing but not the machine. With it every batch is synced before it's applied,
+/// so acknowledged writes survive both. A frozen memtable keeps its files until it has been
+/// flushed.
+pub struct Wal {
+    directory: PathBuf,
+    sync: bool,
+    /// Every file holding records of the memtable, oldest first
+    paths: Vec<PathBuf>,
+    /// Open while the memtable takes writes
+    file: Option<File>,
+}
+
+impl Wal {
+    /// Starts a new log in `data_directory` and returns the batches of the logs left behind,
+    /// oldest first. Those logs are kept until [`Wal::checkpoint`] replaces them.
+    pub fn open(data_directory: &Path, sync: bool) -> (Self, Vec<Vec<Command>>) {
+        let directory = data_directory.join(WAL_DIRECTORY);
+        fs::create_dir_all(&directory).unwrap();
+
+        let mut paths: Vec<PathBuf> = fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        paths.sort_by_key(|path| file_id(path));
+
+        let batches = paths.iter().flat_map(|path| read_batches(path)).collect();
+        let mut wal = Self {
+            directory,
+            sync,
+            paths,
+            file: None,
+        };
+        wal.start_file();
+        (wal, batches)
+    }
+
+    pub fn append(&mut self, batch: &[Command]) {
+        if batch.is_empty() {
+            return;
+        }
+        let payload_len: usize = batch.iter().map(|c| c.encoded_len()).sum();
+        let mut record = Vec::with_capacity(RECORD_HEADER_BYTES + payload_len);
+        record.put_u32(payload_len as u32);
+        record.put_u32(0); // checksum, filled in below
+        for command in batch {
+            command.encode_to(&mut record);
+        }
+        let checksum = checksum(&record[RECORD_HEADER_BYTES..]);
+        record[4..RECORD_HEADER_BYTES].copy_from_slice(&checksum.to_be_bytes());
+
+        let file = self.file.as_mut().expect("appending to a frozen log");
+        file.write_all(&record).unwrap();
+        if self.sync {
+            file.sync_data().unwrap();
+        }
+    }
+
+    /// Logs the whole memtable as one record and deletes the older files it supersedes
+    pub fn checkpoint(&mut self, snapshot: &[Command]) {
+        self.append(snapshot);
+        let current = self.paths.pop().unwrap();
+        for path in self.paths.drain(..) {
+            fs::remove_file(path).unwrap();
+        }
+        self.paths.push(current);
+    }
+
+    /// Moves the files written so far into the returned log, which only exists to be
+    /// discarded once its memtable is flushed, and continues in a new file
+    pub fn rotate(&mut self) -> Wal {
+        let frozen = Wal {
+            directory: self.directory.clone(),
+            sync: self.sync,
+            paths: std::mem::take(&mut self.paths),
+            file: None,
+        };
+        self.start_file();
+        frozen
+    }
+
+    /// Deletes the files of the log, its records must be in tables by now
+    pub fn discard(mut self) {
+        self.file = None;
+        for path in self.paths.drain(..) {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    fn start_file(&mut self) {
+        let path = self.directory.join(next_table_id().to_string());
+        self.file = Some(File::create_new(&path).unwrap());
+        if self.sync {
+            // the new file is only found again if its directory entry is on disk too
+            File::open(&self.directory).unwrap().sync_all().unwrap();
+        }
+        self.paths.push(path);
+    }
+}
+
+fn file_id(path: &Path) -> u64 {
+    path.file_name().unwrap().to_str().unwrap().parse().unwrap()
+}
+
+fn checksum(payload: &[u8]) -> u32 {
+    hash_key(payload, CHECKSUM_SEED) as u32
+}
+
+/// Batches of a log up to the first torn or corrupt record, which can only be the last
+/// one written before a crash
+fn read_batches(path: &Path) -> Vec<Vec<Command>> {
+    let buf = Bytes::from(fs::read(path).unwrap());
+    let mut batches = vec![];
+    let mut pos = 0;
+    while let Some(header) = buf.get(pos..pos + RECORD_HEADER_BYTES) {
+        let payload_len = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        let expected = u32::from_be_bytes(header[4..].try_into().unwrap());
+        let start = pos + RECORD_HEADER_BYTES;
+        let Some(payload) = buf.get(start..start + payload_len) else {
+            break;
+        };
+        if checksum(payload) != expected {
+            break;
+        }
+
+        batches.push(
+            BlockView::new(buf.slice(start..start + payload_len))
+                .iter()
+                .collect(),
+        );
+        pos = start + payload_len;
+    }
+    batches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch(keys: &[u8]) -> Vec<Command> {
+        keys.iter()
+            .map(|&k| match k % 2 {
+                0 => Command::Put(Bytes::from(vec![k]), Bytes::from(vec![k; 3])),
+                _ => Command::Delete(Bytes::from(vec![k])),
+            })
+            .collect()
+    }
+
+    /// Logs `batches` into a fresh log and returns the path of its file
+    fn write_log(data_directory: &Path, batches: &[Vec<Command>]) -> PathBuf {
+        let (mut wal, replayed) = Wal::open(data_directory, false);
+        assert!(replayed.is_empty());
+        for batch in batches {
+            wal.append(batch);
+        }
+        wal.paths.pop().unwrap()
+    }
+
+    #[test]
+    fn replays_every_batch_whole() {
+        let dir = tempfile::tempdir().unwrap();
+        let batches = vec![batch(&[1, 2, 3]), batch(&[4]), batch(&[2, 6])];
+        write_log(dir.path(), &batches);
+
+        let (_, replayed) = Wal::open(dir.path(), true);
+        assert_eq!(replayed, batches);
+    }
+
+    #[test]
+    fn stops_at_a_torn_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let batches = vec![batch(&[1, 2, 3]), batch(&[4, 5, 6])];
+        let path = write_log(dir.path(), &batches);
+
+        let last_len =
+            RECORD_HEADER_BYTES + batches[1].iter().map(|c| c.encoded_len()).sum::<usize>();
+        let last_start = fs::metadata(&path).unwrap().len() - last_len as u64;
+        // cut into the payload of the last record, then into its header
+        for torn in [
+            last_start + last_len as u64 - 1,
+            last_start + 9,
+            last_start + 4,
+        ] {
+            File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_len(torn)
+                .unwrap();
+            assert_eq!(read_batches(&path), batches[..1]);
+        }
+    }
+
+    #[test]
+    fn stops_at_a_corrupt_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let batches = vec![batch(&[1, 2]), batch(&[3, 4]), batch(&[5, 6])];
+        let path = write_log(dir.path(), &batches);
+
+        let mut file = fs::read(&path).unwrap();
+        let first_len =
+            RECORD_HEADER_BYTES + batches[0].iter().map(|c| c.encoded_len()).sum::<usize>();
+        file[first_len + RECORD_HEADER_BYTES] ^= 1;
+        fs::write(&path, file).unwrap();
+        assert_eq!(read_batches(&path), batches[..1]);
+    }
+
+    #[test]
+    fn checkpoints_replace_older_files() {
+        let dir = tempfile::tempdir().unwrap();
+        write_log(dir.path(), &[batch(&[1]), batch(&[2])]);
+
+        let (mut wal, replayed) = Wal::open(dir.path(), false);
+        let snapshot: Vec<Command> = replayed.into_iter().flatten().collect();
+        wal.checkpoint(&snapshot);
+        drop(wal);
+
+        assert_eq!(
+            fs::read_dir(dir.path().join(WAL_DIRECTORY))
+                .unwrap()
+                .count(),
+            1
+        );
+        let (_, replayed) = Wal::open(dir.path(), false);
+        assert_eq!(replayed, vec![snapshot]);
+    }
+}
@@ -0,0 +1,81 @@
+use std::{marker::PhantomData, time::Duration};
+
+use bytes::Bytes;
+
+use super::{
+    codec::Codec,
+    table::{unix_millis, Command},
+};
+use crate::config::{MAX_KEY_BYTES, MAX_VALUE_BYTES};
+
+/// Puts, deletes and merges applied together by [`Database::write`](super::Database::write). Readers
+/// see all of them or none and they are logged as one record, so a crash can't tear the
+/// batch either. Later commands on a key override earlier ones.
+#[derive(Clone, Debug)]
+pub struct WriteBatch<K = Bytes, V = Bytes> {
+    pub(super) commands: Vec<Command>,
+    _codec: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K: Codec, V: Codec> WriteBatch<K, V> {
+    pub fn new() -> Self {
+        Self {
+            commands: vec![],
+            _codec: PhantomData,
+        }
+    }
+
+    /// Encoded keys can be up to `MAX_KEY_BYTES` and values up to `MAX_VALUE_BYTES` long
+    pub fn put(&mut self, key: K, value: V) {
+        let (key, value) = (key.encode(), value.encode());
+        assert!(
+            key.len() <= MAX_KEY_BYTES && value.len() <= MAX_VALUE_BYTES,
+            "record doesn't fit in a block"
+        );
+        self.commands.push(Command::Put(key, value));
+    }
+
+    /// Puts `value` for `ttl`, after which `key` reads as deleted
+    pub fn put_with_ttl(&mut self, key: K, value: V, ttl: Duration) {
+        let (key, value) = (key.encode(), value.encode());
+        assert!(
+            key.len() <= MAX_KEY_BYTES && value.len() <= MAX_VALUE_BYTES,
+            "record doesn't fit in a block"
+        );
+        // ttls past the end of the clock never expire
+        let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        let expires_at = unix_millis().saturating_add(ttl);
+        self.commands
+            .push(Command::Expiring(key, value, expires_at));
+    }
+
+    pub fn delete(&mut self, key: K) {
+        let key = key.encode();
+        assert!(key.len() <= MAX_KEY_BYTES, "record doesn't fit in a block");
+        self.commands.push(Command::Delete(key));
+    }
+
+    /// Folds `operand` into the value of `key` with the database's merge operator
+    pub fn merge(&mut self, key: K, operand: V) {
+        let (key, operand) = (key.encode(), operand.encode());
+        assert!(
+            key.len() <= MAX_KEY_BYTES && operand.len() <= MAX_VALUE_BYTES,
+            "record doesn't fit in a block"
+        );
+        self.commands.push(Command::Merge(key, operand));
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+}
+
+impl<K: Codec, V: Codec> Default for WriteBatch<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,138 @@
+use bytes::Bytes;
+
+use super::filter::{hash_key, FilterKind, PointFilter};
+
+/// Static xor filter with 8 bit fingerprints (Graf & Lemire, 2020).
+/// Uses ~9.84 bits per key for a false positive rate of ~0.39%, but cannot be updated
+/// after construction, which is fine since tables are immutable.
+#[derive(Debug)]
+pub struct XorFilter {
+    seed: u64,
+    block_length: usize,
+    fingerprints: Vec<u8>,
+}
+
+impl XorFilter {
+    pub fn new(keys: &[Bytes]) -> Self {
+        let capacity = (32 + (keys.len() as f64 * 1.23).ceil() as usize) / 3 * 3;
+        let block_length = capacity / 3;
+
+        let mut seed = 0x9E3779B97F4A7C15_u64;
+        let mut counts = vec![0_u32; capacity];
+        let mut masks = vec![0_u64; capacity];
+        let mut queue = Vec::with_capacity(capacity);
+        let mut stack: Vec<(u64, usize)> = Vec::with_capacity(keys.len());
+
+        loop {
+            counts.fill(0);
+            masks.fill(0);
+            queue.clear();
+            stack.clear();
+
+            for key in keys {
+                let hash = hash_key(key, seed);
+                for idx in indices(hash, block_length) {
+                    counts[idx] += 1;
+                    masks[idx] ^= hash;
+                }
+            }
+
+            queue.extend((0..capacity).filter(|&idx| counts[idx] == 1));
+
+            // peel slots that only a single key maps to
+            while let Some(idx) = queue.pop() {
+                if counts[idx] != 1 {
+                    continue;
+                }
+                let hash = masks[idx];
+                stack.push((hash, idx));
+
+                for other in indices(hash, block_length) {
+                    counts[other] -= 1;
+                    masks[other] ^= hash;
+                    if counts[other] == 1 {
+                        queue.push(other);
+                    }
+                }
+            }
+
+            if stack.len() == keys.len() {
+                break;
+            }
+            // a cycle in the hypergraph, retry with another seed
+            seed = seed.wrapping_mul(0x5851F42D4C957F2D).wrapping_add(1);
+        }
+
+        let mut fingerprints = vec![0_u8; capacity];
+        for &(hash, idx) in stack.iter().rev() {
+            let [h0, h1, h2] = indices(hash, block_length);
+            fingerprints[idx] =
+                fingerprint(hash) ^ fingerprints[h0] ^ fingerprints[h1] ^ fingerprints[h2];
+        }
+
+        Self {
+            seed,
+            block_length,
+            fingerprints,
+        }
+    }
+}
+
+/// One slot in each third of the fingerprint array
+fn indices(hash: u64, block_length: usize) -> [usize; 3] {
+    std::array::from_fn(|i| {
+        let h = hash.rotate_left(21 * i as u32) as u32 as u64;
+        ((h * block_length as u64) >> 32) as usize + i * block_length
+    })
+}
+
+fn fingerprint(hash: u64) -> u8 {
+    (hash ^ (hash >> 32)) as u8
+}
+
+impl PointFilter for XorFilter {
+    fn kind(&self) -> FilterKind {
+        FilterKind::Xor
+    }
+
+    fn maybe_contains(&self, key: &[u8]) -> bool {
+        let hash = hash_key(key, self.seed);
+        let [h0, h1, h2] = indices(hash, self.block_length);
+        fingerprint(hash) == self.fingerprints[h0] ^ self.fingerprints[h1] ^ self.fingerprints[h2]
+    }
+
+    fn size_bytes(&self) -> usize {
+        self.fingerprints.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(count: u32) -> Vec<Bytes> {
+        (0..count)
+            .map(|i| Bytes::copy_from_slice(&(i * 7).to_be_bytes()))
+            .collect()
+    }
+
+    #[test]
+    fn no_false_negatives() {
+        for count in [0, 1, 2, 100, 10_000] {
+            let keys = keys(count);
+            let filter = XorFilter::new(&keys);
+            assert!(keys.iter().all(|key| filter.maybe_contains(key)));
+        }
+    }
+
+    #[test]
+    fn rules_out_most_missing_keys() {
+        let filter = XorFilter::new(&keys(10_000));
+        let false_positives = (0..10_000_u32)
+            .map(|i| (i * 7 + 3).to_be_bytes())
+            .filter(|key| filter.maybe_contains(key))
+            .count();
+        // ~0.39% expected
+        assert!(false_positives < 100, "{false_positives} false positives");
+    }
+}
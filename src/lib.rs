@@ -0,0 +1,6 @@
+pub mod admin;
+pub mod command;
+pub mod config;
+pub mod database;
+pub mod metrics;
+pub mod resp;
@@ -1,34 +1,66 @@
-mod database;
 use chrono::Local;
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, sync::Arc, time::Instant};
 
-use command::read_command;
-use config::Config;
-use database::Database;
+use lsm_tree::command::{read_command, Response, Session};
+use lsm_tree::config::Config;
+use lsm_tree::database::families::Families;
+use lsm_tree::metrics::Metrics;
+use lsm_tree::{admin, resp};
 use tokio::{
-    io::{AsyncWriteExt, BufReader},
+    io::{self, AsyncWriteExt, BufReader, BufWriter},
     net::{TcpListener, TcpStream},
     signal,
+    sync::mpsc,
 };
 use tokio_util::sync::CancellationToken;
 use tokio_util::task::TaskTracker;
 
-mod command;
-mod config;
+// Requests of a connection parsed ahead of the one executing
+const PIPELINE_DEPTH: usize = 1024;
+// Replies are coalesced into writes of up to this many bytes
+const WRITE_BUFFER_BYTES: usize = 1 << 16;
 
-/// TODO: explain how I lock levels to support threading
-/// Ex: When switch the lock to the next level during range/stats, I make sure a writer cant get the lock
+// TODO: explain how I lock levels to support threading
+// Ex: When switch the lock to the next level during range/stats, I make sure a writer cant get the lock
 
-#[tokio::main]
-async fn main() {
+fn main() {
     let config = Config::parse_from_args();
 
+    // disk IO and compactions run on the blocking pool, see `database::run_blocking`
+    tokio::runtime::Builder::new_multi_thread()
+        .max_blocking_threads(config.io_threads)
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(serve(config));
+}
+
+async fn serve(config: Config) {
     // TODO: could parallelize database creation since level initializations are independent
-    let db = Arc::new(Database::new(config.data_dir));
+    let families = Arc::new(Families::new(config.data_dir, config.table_options));
 
     let listener = TcpListener::bind(("127.0.0.1", config.port)).await.unwrap();
     println!("Starting server on 127.0.0.1:{}!", config.port);
 
+    let metrics = Arc::new(Metrics::default());
+
+    let resp_listener = match config.resp_port {
+        Some(port) => {
+            let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+            println!("Accepting RESP on 127.0.0.1:{port}!");
+            Some(listener)
+        }
+        None => None,
+    };
+    let http_listener = match config.http_port {
+        Some(port) => {
+            let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+            println!("Serving metrics on http://127.0.0.1:{port}/metrics!");
+            Some(listener)
+        }
+        None => None,
+    };
+
     let token = CancellationToken::new();
     let cloned_token = token.clone();
 
@@ -48,14 +80,34 @@ async fn main() {
         tokio::select! {
             accept_result = listener.accept() => {
                 let (stream, client) = accept_result.unwrap();
-                let db_clone = db.clone();
+                let families_clone = families.clone();
+                let metrics_clone = metrics.clone();
                 let cloned_token = token.clone();
                 tracker.spawn(async move {
                     println!("New connection with {:?}", client);
-                    handle_connection(stream, client, db_clone, cloned_token).await;
+                    handle_connection(stream, client, families_clone, metrics_clone, cloned_token)
+                        .await;
                     println!("Closed connection with {:?}", client);
                 });
             }
+            accept_result = accept_optional(resp_listener.as_ref()) => {
+                let (stream, client) = accept_result.unwrap();
+                let families_clone = families.clone();
+                let metrics_clone = metrics.clone();
+                let cloned_token = token.clone();
+                tracker.spawn(async move {
+                    println!("New RESP connection with {:?}", client);
+                    resp::handle_connection(stream, families_clone, metrics_clone, cloned_token)
+                        .await;
+                    println!("Closed RESP connection with {:?}", client);
+                });
+            }
+            accept_result = accept_optional(http_listener.as_ref()) => {
+                let (stream, _) = accept_result.unwrap();
+                let families_clone = families.clone();
+                let metrics_clone = metrics.clone();
+                tracker.spawn(admin::handle_connection(stream, families_clone, metrics_clone));
+            }
             _ = token.cancelled() => {
                 break;
             }
@@ -66,54 +118,99 @@ async fn main() {
     // Wait for everything to finish.
     tracker.wait().await;
 
-    let db: Database = unsafe { Arc::try_unwrap(db).unwrap_unchecked() };
-    db.cleanup();
+    let families: Families = unsafe { Arc::try_unwrap(families).unwrap_unchecked() };
+    families.cleanup();
+}
+
+/// Accepts on the listener, if there is one
+async fn accept_optional(listener: Option<&TcpListener>) -> io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
 }
 
 async fn handle_connection(
     stream: TcpStream,
     _: SocketAddr,
-    db: Arc<Database>,
+    families: Arc<Families>,
+    metrics: Arc<Metrics>,
     cancel_token: CancellationToken,
 ) {
     let now = Local::now();
     eprintln!("{}", now.format("%H:%M:%S%.6f"));
 
     let mut processed: usize = 0;
-    let (read, mut write) = stream.into_split();
-    let mut buf_read = BufReader::new(read);
+    let (read, write) = stream.into_split();
+    let mut write = BufWriter::with_capacity(WRITE_BUFFER_BYTES, write);
+
+    // requests are read ahead so pipelining clients aren't bound by round trips, the first
+    // error ends the stream
+    let (requests_tx, mut requests) = mpsc::channel(PIPELINE_DEPTH);
+    let reader = tokio::spawn(async move {
+        let mut buf_read = BufReader::new(read);
+        loop {
+            let read_res = read_command(&mut buf_read).await;
+            let failed = read_res.is_err();
+            if requests_tx.send(read_res).await.is_err() || failed {
+                break;
+            }
+        }
+    });
 
-    let mut out_buf = String::new();
+    let mut response = Response::default();
+    let mut out_buf = Vec::new();
+    let mut session = Session::default();
     loop {
-        tokio::select! {
-            read_res = read_command(&mut buf_read) => {
-                let command = if let Ok(command) = read_res {
-                    command
-                } else {
-                    break;
-                };
-
-                processed += 1;
-                // println!("Received command {:?} from {:?}, this is the {processed} command", command, addr);
-
-                if processed % 10_000 == 0 {
-                    let now = Local::now();
-                    eprintln!("{}", now.format("%H:%M:%S%.6f"));
-                }
-
-                command.execute(&db, &mut out_buf).await;
-
-                let mut bytes = out_buf.into_bytes();
-                // delimiter
-                bytes.push(0x00);
-                write.write_all(&bytes).await.unwrap();
+        // replies are only written out once no request is waiting behind them
+        if requests.is_empty() && write.flush().await.is_err() {
+            break;
+        }
 
-                bytes.clear();
-                out_buf = unsafe { String::from_utf8_unchecked(bytes) };
-            }
+        let read_res = tokio::select! {
+            read_res = requests.recv() => read_res,
             _ = cancel_token.cancelled() => {
                 break;
             }
+        };
+        let request = match read_res {
+            Some(Ok(read)) => read,
+            Some(Err(err)) => {
+                // the rest of the stream can't be parsed, say why before hanging up
+                if err.kind() == io::ErrorKind::InvalidData {
+                    response.error(err);
+                    response.write_frame(&mut out_buf);
+                    let _ = write.write_all(&out_buf).await;
+                    let _ = write.flush().await;
+                }
+                break;
+            }
+            None => break,
+        };
+
+        processed += 1;
+        // println!("Received command {:?} from {:?}, this is the {processed} command", command, addr);
+
+        if processed.is_multiple_of(10_000) {
+            let now = Local::now();
+            eprintln!("{}", now.format("%H:%M:%S%.6f"));
+        }
+
+        let (op, started) = (request.command.op(), Instant::now());
+        if request
+            .execute(&families, &mut session, &mut response, &mut write)
+            .await
+            .is_err()
+        {
+            break;
+        }
+        metrics.record(op, started.elapsed());
+
+        response.write_frame(&mut out_buf);
+        if write.write_all(&out_buf).await.is_err() {
+            break;
         }
+        out_buf.clear();
     }
+    reader.abort();
 }
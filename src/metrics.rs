@@ -0,0 +1,146 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+// Upper bounds of the latency histogram buckets in microseconds, the last bucket is unbounded
+const LATENCY_BUCKETS_MICROS: [u64; 12] = [
+    50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 1_000_000,
+];
+
+/// Kinds of requests, counted and timed separately whatever protocol they came in on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Get,
+    MultiGet,
+    Put,
+    Delete,
+    CompareAndSet,
+    GetSet,
+    GetDelete,
+    Merge,
+    Load,
+    Batch,
+    Range,
+    Aggregate,
+    Lookup,
+    ValueRange,
+    Stats,
+    Family,
+    Transaction,
+    Hello,
+}
+
+impl Op {
+    const ALL: [Op; 18] = [
+        Op::Get,
+        Op::MultiGet,
+        Op::Put,
+        Op::Delete,
+        Op::CompareAndSet,
+        Op::GetSet,
+        Op::GetDelete,
+        Op::Merge,
+        Op::Load,
+        Op::Batch,
+        Op::Range,
+        Op::Aggregate,
+        Op::Lookup,
+        Op::ValueRange,
+        Op::Stats,
+        Op::Family,
+        Op::Transaction,
+        Op::Hello,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Get => "get",
+            Self::MultiGet => "mget",
+            Self::Put => "put",
+            Self::Delete => "delete",
+            Self::CompareAndSet => "cas",
+            Self::GetSet => "getset",
+            Self::GetDelete => "getdel",
+            Self::Merge => "merge",
+            Self::Load => "load",
+            Self::Batch => "batch",
+            Self::Range => "range",
+            Self::Aggregate => "aggregate",
+            Self::Lookup => "lookup",
+            Self::ValueRange => "vrange",
+            Self::Stats => "stats",
+            Self::Family => "family",
+            Self::Transaction => "transaction",
+            Self::Hello => "hello",
+        }
+    }
+}
+
+/// Requests served by every listener, by kind, and how long they took to execute
+#[derive(Default)]
+pub struct Metrics {
+    ops: [OpMetrics; Op::ALL.len()],
+}
+
+#[derive(Default)]
+struct OpMetrics {
+    count: AtomicU64,
+    total_micros: AtomicU64,
+    /// Requests per latency bucket, not cumulative
+    buckets: [AtomicU64; LATENCY_BUCKETS_MICROS.len() + 1],
+}
+
+impl Metrics {
+    pub fn record(&self, op: Op, elapsed: Duration) {
+        let metrics = &self.ops[op as usize];
+        let micros = elapsed.as_micros() as u64;
+        let bucket = LATENCY_BUCKETS_MICROS.partition_point(|&bound| bound < micros);
+        metrics.count.fetch_add(1, Ordering::Relaxed);
+        metrics.total_micros.fetch_add(micros, Ordering::Relaxed);
+        metrics.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `lsm_tree_ops_total` and the `lsm_tree_op_latency_seconds` histogram in the Prometheus
+    /// text format
+    pub fn write_prometheus(&self, out: &mut String) {
+        out.push_str("# HELP lsm_tree_ops_total Requests served, by kind.\n");
+        out.push_str("# TYPE lsm_tree_ops_total counter\n");
+        for op in Op::ALL {
+            let count = self.ops[op as usize].count.load(Ordering::Relaxed);
+            writeln!(out, "lsm_tree_ops_total{{op=\"{}\"}} {count}", op.name()).unwrap();
+        }
+
+        out.push_str("# HELP lsm_tree_op_latency_seconds Time taken to execute requests.\n");
+        out.push_str("# TYPE lsm_tree_op_latency_seconds histogram\n");
+        for op in Op::ALL {
+            let metrics = &self.ops[op as usize];
+            let name = op.name();
+            let mut cumulative = 0;
+            for (idx, bucket) in metrics.buckets.iter().enumerate() {
+                cumulative += bucket.load(Ordering::Relaxed);
+                let le = match LATENCY_BUCKETS_MICROS.get(idx) {
+                    Some(&micros) => (micros as f64 / 1e6).to_string(),
+                    None => "+Inf".to_owned(),
+                };
+                writeln!(
+                    out,
+                    "lsm_tree_op_latency_seconds_bucket{{op=\"{name}\",le=\"{le}\"}} {cumulative}"
+                )
+                .unwrap();
+            }
+            let seconds = metrics.total_micros.load(Ordering::Relaxed) as f64 / 1e6;
+            writeln!(
+                out,
+                "lsm_tree_op_latency_seconds_sum{{op=\"{name}\"}} {seconds}"
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "lsm_tree_op_latency_seconds_count{{op=\"{name}\"}} {cumulative}"
+            )
+            .unwrap();
+        }
+    }
+}
@@ -0,0 +1,511 @@
+use std::{
+    fmt::Write as _,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+    net::TcpStream,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::command::PROTOCOL_VERSION;
+use crate::database::{codec::Codec, families::Families, run_blocking, Database};
+use crate::metrics::{Metrics, Op};
+
+// Arguments and argument counts past these are protocol errors, nothing needs that much
+const MAX_ARGUMENT_BYTES: usize = 1 << 16;
+const MAX_ARGUMENTS: usize = 1 << 16;
+const WRITE_BUFFER_BYTES: usize = 1 << 16;
+// Keys returned by a `SCAN` without a `COUNT`
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// Serves a connection speaking RESP2, the Redis protocol, so redis-cli and Redis client
+/// libraries can talk to the default family. Supports `PING`, `QUIT`, `GET`, `SET` (with `EX`
+/// or `PX`), `DEL`, `MGET`, `SCAN` and `INFO`.
+///
+/// Keys and values are decimal i32s, stored like the ones of the i32 commands. Values that
+/// aren't i32s, written by the byte commands, are returned as they are stored. `SCAN` cursors
+/// are the offset of the next key from `i32::MIN`, so scans walk the i32 keys in order.
+///
+/// Replies are buffered and written once no request is waiting behind them, so pipelined
+/// requests are answered together.
+pub async fn handle_connection(
+    stream: TcpStream,
+    families: Arc<Families>,
+    metrics: Arc<Metrics>,
+    cancel_token: CancellationToken,
+) {
+    let (read, write) = stream.into_split();
+    let mut read = BufReader::new(read);
+    let mut write = BufWriter::with_capacity(WRITE_BUFFER_BYTES, write);
+
+    let mut out = Vec::new();
+    loop {
+        let read_res = tokio::select! {
+            read_res = read_request(&mut read) => read_res,
+            _ = cancel_token.cancelled() => {
+                break;
+            }
+        };
+        let args = match read_res {
+            Ok(args) => args,
+            Err(err) => {
+                // the rest of the stream can't be parsed, say why before hanging up
+                if err.kind() == io::ErrorKind::InvalidData {
+                    write_error(&mut out, &format!("Protocol error: {err}"));
+                    let _ = write.write_all(&out).await;
+                    let _ = write.flush().await;
+                }
+                break;
+            }
+        };
+        // empty inline requests are skipped without a reply
+        if args.is_empty() {
+            continue;
+        }
+
+        let started = Instant::now();
+        let quit = execute(&args, &families, &mut out).await;
+        if let Some(op) = op(&args[0]) {
+            metrics.record(op, started.elapsed());
+        }
+        if write.write_all(&out).await.is_err() {
+            break;
+        }
+        out.clear();
+
+        if (quit || read.buffer().is_empty()) && write.flush().await.is_err() {
+            break;
+        }
+        if quit {
+            break;
+        }
+    }
+}
+
+/// What a command is counted as in the metrics, `None` for the ones about the connection
+fn op(name: &[u8]) -> Option<Op> {
+    Some(match name.to_ascii_uppercase().as_slice() {
+        b"GET" => Op::Get,
+        b"SET" => Op::Put,
+        b"DEL" => Op::Delete,
+        b"MGET" => Op::MultiGet,
+        b"SCAN" => Op::Range,
+        b"INFO" => Op::Stats,
+        _ => return None,
+    })
+}
+
+/// Arguments of an array of bulk strings, or of an inline request split on whitespace
+async fn read_request<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> io::Result<Vec<Bytes>> {
+    let line = read_line(reader).await?;
+    let Some(count) = line.strip_prefix(b"*") else {
+        return Ok(line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(Bytes::copy_from_slice)
+            .collect());
+    };
+
+    let count = parse_len(count, MAX_ARGUMENTS)?;
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let line = read_line(reader).await?;
+        let len = line
+            .strip_prefix(b"$")
+            .ok_or_else(|| invalid_data("expected '$'"))?;
+        let len = parse_len(len, MAX_ARGUMENT_BYTES)?;
+
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg).await?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(invalid_data("bulk string without CRLF"));
+        }
+        arg.truncate(len);
+        args.push(arg.into());
+    }
+    Ok(args)
+}
+
+/// A line without its `\r\n` (or lone `\n`)
+async fn read_line<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut line = vec![];
+    let read = (&mut *reader)
+        .take(MAX_ARGUMENT_BYTES as u64)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if read == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    if line.pop() != Some(b'\n') {
+        // a short read without a newline is the stream ending mid line
+        return Err(if read < MAX_ARGUMENT_BYTES {
+            io::ErrorKind::UnexpectedEof.into()
+        } else {
+            invalid_data("line too long")
+        });
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(line)
+}
+
+fn parse_len(bytes: &[u8], max: usize) -> io::Result<usize> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|len| len.parse().ok())
+        .filter(|len| *len <= max)
+        .ok_or_else(|| invalid_data("invalid length"))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Writes the reply to `args` into `out`, true if the connection should be closed after it
+async fn execute(args: &[Bytes], families: &Families, out: &mut Vec<u8>) -> bool {
+    let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+    let Some(db) = families.get(None).await else {
+        write_error(out, "ERR no such family");
+        return false;
+    };
+
+    let res = match (name.as_str(), &args[1..]) {
+        ("PING", []) => {
+            write_simple(out, "PONG");
+            Ok(())
+        }
+        ("PING", [message]) => {
+            write_bulk(out, message);
+            Ok(())
+        }
+        ("QUIT", []) => {
+            write_simple(out, "OK");
+            return true;
+        }
+        // redis-cli asks for the documentation of every command on start
+        ("COMMAND", _) => {
+            write_array_len(out, 0);
+            Ok(())
+        }
+        ("GET", [key]) => get(&db, key, out).await,
+        ("SET", [key, val, options @ ..]) => set(&db, key, val, options, out).await,
+        ("DEL", keys) if !keys.is_empty() => del(&db, keys, out).await,
+        ("MGET", keys) if !keys.is_empty() => mget(&db, keys, out).await,
+        ("SCAN", [cursor, options @ ..]) => scan(&db, cursor, options, out).await,
+        ("INFO", []) => info(&db, None, out).await,
+        ("INFO", [section]) => info(&db, Some(section), out).await,
+        ("PING" | "QUIT" | "GET" | "SET" | "DEL" | "MGET" | "SCAN" | "INFO", _) => Err(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name.to_ascii_lowercase()
+        )),
+        _ => Err(format!(
+            "ERR unknown command '{}'",
+            String::from_utf8_lossy(&args[0])
+        )),
+    };
+    if let Err(err) = res {
+        write_error(out, &err);
+    }
+    false
+}
+
+type ReplyResult = Result<(), String>;
+
+fn int_arg(arg: &[u8]) -> Result<i32, String> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|arg| arg.parse().ok())
+        .ok_or_else(|| "ERR value is not an integer or out of range".to_owned())
+}
+
+async fn get(db: &Database, key: &[u8], out: &mut Vec<u8>) -> ReplyResult {
+    let key = int_arg(key)?.encode();
+    match db.get(&key).await {
+        Some(val) => write_value(out, &val),
+        None => write_null(out),
+    }
+    Ok(())
+}
+
+async fn set(
+    db: &Database,
+    key: &[u8],
+    val: &[u8],
+    options: &[Bytes],
+    out: &mut Vec<u8>,
+) -> ReplyResult {
+    let (key, val) = (int_arg(key)?.encode(), int_arg(val)?.encode());
+    let ttl = match options {
+        [] => None,
+        [unit, amount] => {
+            let invalid = || "ERR invalid expire time in 'set' command".to_owned();
+            let amount: u64 = std::str::from_utf8(amount)
+                .ok()
+                .and_then(|amount| amount.parse().ok())
+                .filter(|amount| *amount > 0)
+                .ok_or_else(invalid)?;
+            // like Redis, expiries have to be expressible in milliseconds
+            let millis = match unit.to_ascii_uppercase().as_slice() {
+                b"EX" => amount.checked_mul(1000).ok_or_else(invalid)?,
+                b"PX" => amount,
+                _ => return Err("ERR syntax error".to_owned()),
+            };
+            Some(Duration::from_millis(millis))
+        }
+        _ => return Err("ERR syntax error".to_owned()),
+    };
+
+    match ttl {
+        Some(ttl) => db.insert_with_ttl(key, val, ttl).await,
+        None => db.insert(key, val).await,
+    }
+    write_simple(out, "OK");
+    Ok(())
+}
+
+/// Answered with the number of keys that held a value
+async fn del(db: &Database, keys: &[Bytes], out: &mut Vec<u8>) -> ReplyResult {
+    let keys = keys
+        .iter()
+        .map(|key| int_arg(key).map(|key| key.encode()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut deleted = 0;
+    for key in keys {
+        if db.get_delete(key).await.is_some() {
+            deleted += 1;
+        }
+    }
+    write_int(out, deleted);
+    Ok(())
+}
+
+async fn mget(db: &Database, keys: &[Bytes], out: &mut Vec<u8>) -> ReplyResult {
+    let keys = keys
+        .iter()
+        .map(|key| int_arg(key).map(|key| key.encode()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let vals = db.multi_get(&keys).await;
+    write_array_len(out, vals.len());
+    for val in vals {
+        match val {
+            Some(val) => write_value(out, &val),
+            None => write_null(out),
+        }
+    }
+    Ok(())
+}
+
+/// `SCAN cursor [COUNT count] [MATCH *]`, answered with the cursor of the next call (`0` once
+/// the scan is over) and the keys found
+async fn scan(db: &Database, cursor: &[u8], options: &[Bytes], out: &mut Vec<u8>) -> ReplyResult {
+    let cursor: u32 = std::str::from_utf8(cursor)
+        .ok()
+        .and_then(|cursor| cursor.parse().ok())
+        .ok_or_else(|| "ERR invalid cursor".to_owned())?;
+    let mut count = DEFAULT_SCAN_COUNT;
+    for option in options.chunks(2) {
+        match (option[0].to_ascii_uppercase().as_slice(), option.get(1)) {
+            (b"COUNT", Some(arg)) => {
+                count = std::str::from_utf8(arg)
+                    .ok()
+                    .and_then(|count| count.parse().ok())
+                    .filter(|count| *count > 0)
+                    .ok_or_else(|| "ERR value is not an integer or out of range".to_owned())?;
+            }
+            (b"MATCH", Some(pattern)) if pattern == "*" => {}
+            (b"MATCH", Some(_)) => return Err("ERR only the '*' pattern is supported".to_owned()),
+            _ => return Err("ERR syntax error".to_owned()),
+        }
+    }
+
+    let from = (i64::from(cursor) + i64::from(i32::MIN)) as i32;
+    let pairs = db.range(&from.encode(), None).await;
+    // one key past the page tells where the next one starts
+    let mut keys: Vec<i32> = run_blocking(move || {
+        pairs
+            .filter_map(|(key, _)| i32::decode_slice(&key))
+            .take(count.saturating_add(1))
+            .collect()
+    })
+    .await;
+    let next = match keys.len() > count {
+        true => keys
+            .pop()
+            .map_or(0, |key| i64::from(key) - i64::from(i32::MIN)),
+        false => 0,
+    };
+
+    write_array_len(out, 2);
+    write_bulk(out, next.to_string().as_bytes());
+    write_array_len(out, keys.len());
+    for key in keys {
+        write_bulk(out, key.to_string().as_bytes());
+    }
+    Ok(())
+}
+
+/// The `server` and `levels` sections, or just the one asked for
+async fn info(db: &Database, section: Option<&[u8]>, out: &mut Vec<u8>) -> ReplyResult {
+    let section = section.map(|s| s.to_ascii_lowercase());
+    let wants = |name: &str| match section.as_deref() {
+        None | Some(b"all" | b"default" | b"everything") => true,
+        Some(section) => section == name.as_bytes(),
+    };
+
+    let mut text = String::new();
+    if wants("server") {
+        text.push_str("# Server\r\n");
+        write!(text, "lsm_tree_version:{}\r\n", env!("CARGO_PKG_VERSION")).unwrap();
+        write!(text, "protocol_version:{PROTOCOL_VERSION}\r\n").unwrap();
+        text.push_str("\r\n");
+    }
+    if wants("levels") {
+        // the memtable is level 0, like in `STATS`
+        let sizes = db.level_sizes().await;
+        text.push_str("# Levels\r\n");
+        write!(text, "level0_records:{}\r\n", sizes.mem_records).unwrap();
+        write!(text, "level0_bytes:{}\r\n", sizes.mem_bytes).unwrap();
+        for level in sizes.levels {
+            let (num, tables) = (level.level, level.tables.len());
+            write!(text, "level{num}_tables:{tables}\r\n").unwrap();
+            write!(text, "level{num}_bytes:{}\r\n", level.file_bytes()).unwrap();
+        }
+    }
+    write_bulk(out, text.as_bytes());
+    Ok(())
+}
+
+/// i32s in decimal, anything else as stored
+fn write_value(out: &mut Vec<u8>, val: &[u8]) {
+    match i32::decode_slice(val) {
+        Some(val) => write_bulk(out, val.to_string().as_bytes()),
+        None => write_bulk(out, val),
+    }
+}
+
+fn write_simple(out: &mut Vec<u8>, text: &str) {
+    out.push(b'+');
+    out.extend_from_slice(text.as_bytes());
+    out.extend_from_slice(b"\r\n");
+}
+
+fn write_error(out: &mut Vec<u8>, message: &str) {
+    out.push(b'-');
+    out.extend_from_slice(message.as_bytes());
+    out.extend_from_slice(b"\r\n");
+}
+
+fn write_int(out: &mut Vec<u8>, val: i64) {
+    out.extend_from_slice(format!(":{val}\r\n").as_bytes());
+}
+
+fn write_bulk(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+    out.extend_from_slice(bytes);
+    out.extend_from_slice(b"\r\n");
+}
+
+fn write_null(out: &mut Vec<u8>) {
+    out.extend_from_slice(b"$-1\r\n");
+}
+
+fn write_array_len(out: &mut Vec<u8>, len: usize) {
+    out.extend_from_slice(format!("*{len}\r\n").as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::NUM_LEVELS;
+    use crate::database::table::TableOptions;
+
+    async fn requests(mut input: &[u8]) -> io::Result<Vec<Vec<Bytes>>> {
+        let mut requests = vec![];
+        while !input.is_empty() {
+            requests.push(read_request(&mut input).await?);
+        }
+        Ok(requests)
+    }
+
+    fn args(args: &[&'static [u8]]) -> Vec<Bytes> {
+        args.iter().map(|arg| Bytes::from_static(arg)).collect()
+    }
+
+    #[tokio::test]
+    async fn reads_arrays_and_inline_requests() {
+        let input = b"*3\r\n$3\r\nSET\r\n$1\r\n1\r\n$4\r\na\r\nb\r\n*0\r\nGET  1\r\nPING\n\r\n";
+        assert_eq!(
+            requests(input).await.unwrap(),
+            vec![
+                args(&[b"SET", b"1", b"a\r\nb"]),
+                vec![],
+                args(&[b"GET", b"1"]),
+                args(&[b"PING"]),
+                vec![],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_requests() {
+        for input in [
+            &b"*1\r\n:3\r\n"[..],
+            b"*1\r\n$3\r\nGETX\r\n",
+            b"*1\r\n$-1\r\n",
+            b"*x\r\n",
+            b"*65537\r\n",
+            b"*1\r\n$65537\r\n",
+        ] {
+            let err = requests(input).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{input:?}");
+        }
+
+        let long_line = vec![b'a'; MAX_ARGUMENT_BYTES + 1];
+        let err = requests(&long_line).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn truncated_requests_end_the_stream() {
+        for input in [&b"PING"[..], b"*2\r\n$3\r\nGET\r\n", b"*1\r\n$3\r\nGE"] {
+            let err = requests(input).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof, "{input:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn executes_commands_on_the_default_family() {
+        let dir = tempfile::tempdir().unwrap();
+        let families = Families::new(dir.path().to_owned(), [TableOptions::default(); NUM_LEVELS]);
+        let mut out = vec![];
+        for request in [
+            args(&[b"set", b"1", b"10"]),
+            args(&[b"GET", b"1"]),
+            args(&[b"MGET", b"1", b"2"]),
+            args(&[b"DEL", b"1", b"2"]),
+            args(&[b"GET", b"1"]),
+            args(&[b"SET", b"x", b"1"]),
+            args(&[b"SET", b"2", b"20", b"EX", b"18446744073709552"]),
+            args(&[b"SET", b"3", b"30", b"EX", b"18446744073709551"]),
+            args(&[b"SET", b"4", b"40", b"PX", b"18446744073709551615"]),
+            args(&[b"MGET", b"2", b"3", b"4"]),
+            args(&[b"GET"]),
+        ] {
+            assert!(!execute(&request, &families, &mut out).await);
+        }
+        assert!(execute(&args(&[b"QUIT"]), &families, &mut out).await);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "+OK\r\n$2\r\n10\r\n*2\r\n$2\r\n10\r\n$-1\r\n:1\r\n$-1\r\n\
+             -ERR value is not an integer or out of range\r\n\
+             -ERR invalid expire time in 'set' command\r\n+OK\r\n+OK\r\n\
+             *3\r\n$-1\r\n$2\r\n30\r\n$2\r\n40\r\n\
+             -ERR wrong number of arguments for 'get' command\r\n+OK\r\n"
+        );
+    }
+}